@@ -0,0 +1,93 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use mandelbrot::fractal::Fractal;
+use mandelbrot::palette::{Coloring, Colorizer, Palette};
+use mandelbrot::{renderer, simd};
+use num::Complex;
+
+const BOUNDS: (usize, usize) = (160, 120);
+const LIMIT: u32 = 1000;
+
+/// Views of the set with a different share of the interior points.
+fn views() -> Vec<(&'static str, Complex<f64>, Complex<f64>)> {
+    vec![
+        ("full", Complex { re: -2.2, im: 1.2 }, Complex { re: 1.0, im: -1.2 }),
+        ("seahorse", Complex { re: -0.76, im: 0.13 }, Complex { re: -0.72, im: 0.1 }),
+    ]
+}
+
+fn points(upper_left: Complex<f64>, lower_right: Complex<f64>) -> Vec<Complex<f64>> {
+    (0..BOUNDS.0 * BOUNDS.1)
+        .map(|i| Complex {
+            re: upper_left.re + (lower_right.re - upper_left.re) * (i % BOUNDS.0) as f64 / BOUNDS.0 as f64,
+            im: upper_left.im - (upper_left.im - lower_right.im) * (i / BOUNDS.0) as f64 / BOUNDS.1 as f64,
+        })
+        .collect()
+}
+
+fn escape_time_kernels(c: &mut Criterion) {
+    let mut group = c.benchmark_group("escape_time");
+    group.sample_size(10);
+
+    for (name, upper_left, lower_right) in views() {
+        let points = points(upper_left, lower_right);
+
+        group.bench_with_input(BenchmarkId::new("scalar", name), &points, |b, points| {
+            b.iter(|| {
+                points.iter()
+                    .map(|&c| Fractal::Mandelbrot.escape_time(black_box(c), LIMIT, 2.0).unwrap_or(LIMIT))
+                    .collect::<Vec<u32>>()
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("scalar_early_out", name), &points, |b, points| {
+            b.iter(|| simd::escape_times_scalar(black_box(points), LIMIT, 2.0))
+        });
+        group.bench_with_input(BenchmarkId::new("simd", name), &points, |b, points| {
+            b.iter(|| simd::escape_times(black_box(points), LIMIT, 2.0))
+        });
+        group.bench_with_input(BenchmarkId::new("smooth_scalar", name), &points, |b, points| {
+            b.iter(|| {
+                points.iter()
+                    .map(|&c| Fractal::Mandelbrot.smooth_escape_time(black_box(c), LIMIT, 2.0))
+                    .collect::<Vec<Option<f64>>>()
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("smooth_scalar_early_out", name), &points, |b, points| {
+            b.iter(|| simd::smooth_escape_times_scalar(black_box(points), LIMIT, 2.0))
+        });
+        group.bench_with_input(BenchmarkId::new("smooth_simd", name), &points, |b, points| {
+            b.iter(|| simd::smooth_escape_times(black_box(points), LIMIT, 2.0))
+        });
+    }
+
+    group.finish();
+}
+
+fn renderers(c: &mut Criterion) {
+    let mut group = c.benchmark_group("render");
+    group.sample_size(10);
+    let options = renderer::Options { limit: LIMIT, ..Default::default() };
+
+    for (name, upper_left, lower_right) in views() {
+        let mut counts = vec![0; BOUNDS.0 * BOUNDS.1];
+
+        group.bench_function(BenchmarkId::new("render_counts", name), |b| {
+            b.iter(|| renderer::render_counts(&mut counts, BOUNDS, upper_left, lower_right, &options))
+        });
+        group.bench_function(BenchmarkId::new("parallel_render_counts", name), |b| {
+            b.iter(|| renderer::parallel_render_counts(&mut counts, BOUNDS, upper_left, lower_right, &options, 4))
+        });
+        group.bench_function(BenchmarkId::new("render_escape", name), |b| {
+            let mut values = vec![None; BOUNDS.0 * BOUNDS.1];
+            b.iter(|| renderer::render_escape(&mut values, BOUNDS, upper_left, lower_right, &options))
+        });
+        group.bench_function(BenchmarkId::new("render_image_palette", name), |b| {
+            let colorizer = Colorizer::new(Palette::by_name("fire").unwrap(), Coloring::Smooth);
+            b.iter(|| renderer::render_image(BOUNDS, upper_left, lower_right, &options, Some(&colorizer), 4))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, escape_time_kernels, renderers);
+criterion_main!(benches);
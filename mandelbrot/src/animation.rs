@@ -0,0 +1,177 @@
+//! Zoom animations from an initial view into a target point.
+//!
+//! The width of the view shrinks by the same factor between every pair of
+//! consecutive frames, so the zoom looks steady however deep it goes. The
+//! center moves towards the target in proportion to the change of width,
+//! which keeps the target at the same place of the picture once it's in view.
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::{Delay, Frame, RgbaImage};
+use num::Complex;
+use super::Error;
+use std::fs::File;
+use std::path::Path;
+
+/// A zoom from the view with corners `upper_left` and `lower_right` to the
+/// view centered on `target` and `zoom` times smaller, over `frames` frames.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Zoom {
+    pub upper_left: Complex<f64>,
+    pub lower_right: Complex<f64>,
+    pub target: Complex<f64>,
+    pub zoom: f64,
+    pub frames: usize,
+}
+
+impl Zoom {
+    /// Return the corners of the view of frame `index`, the first frame
+    /// being the initial view and the last one the target view.
+    pub fn frame(&self, index: usize) -> (Complex<f64>, Complex<f64>) {
+        let t = if self.frames > 1 {
+            index.min(self.frames - 1) as f64 / (self.frames - 1) as f64
+        } else {
+            1.0
+        };
+
+        let start_center = (self.upper_left + self.lower_right) / 2.0;
+        let start_size = self.lower_right - self.upper_left;
+
+        // Relative size of the view, going from 1 down to 1 / zoom.
+        let scale = self.zoom.powf(-t);
+        // How far the center has come, going from 0 up to 1.
+        let progress = if self.zoom == 1.0 {
+            t
+        } else {
+            (1.0 - scale) / (1.0 - 1.0 / self.zoom)
+        };
+
+        let center = start_center + (self.target - start_center) * progress;
+        let half_size = start_size * scale / 2.0;
+        (center - half_size, center + half_size)
+    }
+
+    /// Iterate over the corners of the views of all frames.
+    pub fn frames(&self) -> impl Iterator<Item = (Complex<f64>, Complex<f64>)> + '_ {
+        (0..self.frames).map(move |index| self.frame(index))
+    }
+}
+
+/// Name of the file of frame `index` of a sequence written to `filename`:
+/// the frame number goes before the extension, so `zoom.png` becomes
+/// `zoom-0007.png`.
+pub fn frame_filename(filename: &str, index: usize) -> String {
+    let path = Path::new(filename);
+    match (path.file_stem(), path.extension()) {
+        (Some(stem), Some(extension)) => path
+            .with_file_name(format!("{}-{:04}.{}",
+                                    stem.to_string_lossy(),
+                                    index,
+                                    extension.to_string_lossy()))
+            .to_string_lossy()
+            .into_owned(),
+        _ => format!("{}-{:04}", filename, index),
+    }
+}
+
+/// Writes frames one after the other into an animated, looping GIF.
+pub struct GifWriter {
+    encoder: GifEncoder<File>,
+    bounds: (usize, usize),
+    delay: Delay,
+}
+
+impl GifWriter {
+    /// Create the file `filename` for frames of size `bounds`, each shown for
+    /// `delay_ms` milliseconds.
+    pub fn create(filename: &str, bounds: (usize, usize), delay_ms: u32) -> Result<GifWriter, Error> {
+        let mut encoder = GifEncoder::new_with_speed(File::create(filename)?, 10);
+        encoder.set_repeat(Repeat::Infinite)?;
+        Ok(GifWriter { encoder, bounds, delay: Delay::from_numer_denom_ms(delay_ms, 1) })
+    }
+
+    /// Append a frame of grayscale, RGB or RGBA `pixels`.
+    pub fn write_frame(&mut self, pixels: &[u8]) -> Result<(), Error> {
+        let (width, height) = self.bounds;
+        let rgba = to_rgba(pixels, width * height)?;
+        let buffer = RgbaImage::from_raw(width as u32, height as u32, rgba)
+            .expect("RGBA buffer matches the frame size");
+        self.encoder
+            .encode_frame(Frame::from_parts(buffer, 0, 0, self.delay))
+            .map_err(Error::from)
+    }
+}
+
+/// Expand grayscale or RGB pixels to RGBA.
+fn to_rgba(pixels: &[u8], n_pixels: usize) -> Result<Vec<u8>, Error> {
+    let rgba = match pixels.len() / n_pixels.max(1) {
+        1 => pixels.iter().flat_map(|&l| vec![l, l, l, 255]).collect(),
+        3 => pixels.chunks(3).flat_map(|rgb| vec![rgb[0], rgb[1], rgb[2], 255]).collect(),
+        4 => pixels.to_vec(),
+        _ => return Err(Error::Validation(
+            format!("buffer of {} bytes doesn't match {} pixels", pixels.len(), n_pixels)
+        ))
+    };
+    Ok(rgba)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zoom(frames: usize) -> Zoom {
+        Zoom {
+            upper_left: Complex { re: -2.0, im: 1.0 },
+            lower_right: Complex { re: 2.0, im: -1.0 },
+            target: Complex { re: -1.0, im: 0.5 },
+            zoom: 100.0,
+            frames,
+        }
+    }
+
+    fn assert_close(a: Complex<f64>, b: Complex<f64>) {
+        assert!((a - b).norm() < 1e-12, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn test_zoom_ends() {
+        let zoom = zoom(11);
+
+        let (upper_left, lower_right) = zoom.frame(0);
+        assert_close(upper_left, zoom.upper_left);
+        assert_close(lower_right, zoom.lower_right);
+
+        let (upper_left, lower_right) = zoom.frame(10);
+        assert_close(upper_left, Complex { re: -1.02, im: 0.51 });
+        assert_close(lower_right, Complex { re: -0.98, im: 0.49 });
+    }
+
+    #[test]
+    fn test_zoom_is_exponential() {
+        let widths: Vec<f64> = zoom(5).frames().map(|(ul, lr)| lr.re - ul.re).collect();
+
+        assert_eq!(widths.len(), 5);
+        for pair in widths.windows(2) {
+            assert!((pair[0] / pair[1] - 100f64.powf(0.25)).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_zoom_single_frame() {
+        let (upper_left, _) = zoom(1).frame(0);
+
+        assert_close(upper_left, Complex { re: -1.02, im: 0.51 });
+    }
+
+    #[test]
+    fn test_frame_filename() {
+        assert_eq!(frame_filename("zoom.png", 7), "zoom-0007.png");
+        assert_eq!(frame_filename("out/zoom.png", 123), "out/zoom-0123.png");
+        assert_eq!(frame_filename("zoom", 1), "zoom-0001");
+    }
+
+    #[test]
+    fn test_to_rgba() {
+        assert_eq!(to_rgba(&[7, 8], 2).unwrap(), vec![7, 7, 7, 255, 8, 8, 8, 255]);
+        assert_eq!(to_rgba(&[1, 2, 3], 1).unwrap(), vec![1, 2, 3, 255]);
+        assert!(to_rgba(&[1, 2], 1).is_err());
+    }
+}
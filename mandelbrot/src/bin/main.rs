@@ -1,23 +1,219 @@
-use env_logger;
 use structopt::{self, StructOpt};
-use mandelbrot::{parser, renderer};
+use structopt::clap;
+use log::info;
+use std::convert::TryFrom;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::thread;
 use num::Complex;
+use mandelbrot::{error, parser, renderer, Error};
+use mandelbrot::animation::{self, GifWriter, Zoom};
+use mandelbrot::deep::{self, BigComplex};
+use mandelbrot::cache::CachedRender;
+use mandelbrot::density;
+use mandelbrot::distributed;
+use mandelbrot::fractal::Fractal;
+use mandelbrot::output::{self, Format};
+use mandelbrot::palette::{self, Colorizer, Coloring, Palette};
+use mandelbrot::preview::{self, Viewport};
+use mandelbrot::scene::{self, Scene};
+use mandelbrot::scheduler::{Control, Progress};
+use mandelbrot::server::TileServer;
+use mandelbrot::shading::{self, Shading};
+use mandelbrot::view::View;
 
 #[derive(StructOpt, Debug)]
-#[structopt(name = "mandelbrot")]
+#[structopt(name = "mandelbrot", setting = clap::AppSettings::SubcommandsNegateReqs)]
 struct Args {
 
+    #[structopt(subcommand)]
+    command: Option<Command>,
+
+    /// Render the TOML or JSON scene file, or every scene file of the
+    /// directory, instead of the view given by the other options.
+    #[structopt(long, parse(from_os_str),
+                conflicts_with_all = &["filename", "pixels", "upper-left", "center", "frames"])]
+    scene: Option<PathBuf>,
+
+    /// Render again the PNG image written by an earlier render, with the
+    /// parameters saved in its metadata, to --filename. Options changing
+    /// the image are ignored; --threads, --workers and --cache-dir apply.
+    #[structopt(long, parse(from_os_str),
+                conflicts_with_all = &["scene", "pixels", "upper-left", "center", "frames", "density"])]
+    from_image: Option<PathBuf>,
+
+    /// Explore the view in the terminal with the keyboard instead of writing
+    /// an image, then print the options rendering the last view shown.
+    /// Needs a terminal with 24-bit colors.
+    #[structopt(long, conflicts_with_all = &["scene", "from-image", "filename", "frames", "density",
+                                             "workers", "cache-dir", "deep", "shading"])]
+    preview: bool,
+
+    /// Output file, required unless rendering scenes or running a
+    /// subcommand.
     #[structopt(short, long)]
-    filename: String,
+    filename: Option<String>,
 
+    /// Image size, required unless rendering scenes or running a
+    /// subcommand.
     #[structopt(short, long, parse(try_from_str = parser::bounds_from_str))]
-    pixels: (usize, usize),
+    pixels: Option<(usize, usize)>,
+
+    /// Upper-left corner of the view. The view is widened or heightened to
+    /// match the aspect ratio of the image.
+    #[structopt(short, long, parse(try_from_str = parser::big_complex_from_str),
+                required_unless_one = &["center", "scene", "from-image"], requires = "lower-right")]
+    upper_left: Option<BigComplex>,
+
+    /// Lower-right corner of the view.
+    #[structopt(short, long, parse(try_from_str = parser::big_complex_from_str),
+                requires = "upper-left")]
+    lower_right: Option<BigComplex>,
+
+    /// Center of the view, an alternative to the corners used with --zoom
+    /// or --width.
+    #[structopt(long, parse(try_from_str = parser::big_complex_from_str),
+                conflicts_with_all = &["upper-left", "lower-right"])]
+    center: Option<BigComplex>,
+
+    /// Magnification around --center, zoom 1 showing a view 4 units wide.
+    #[structopt(long, requires = "center", conflicts_with = "width")]
+    zoom: Option<f64>,
+
+    /// Width of the view around --center.
+    #[structopt(long, requires = "center")]
+    width: Option<f64>,
+
+    /// Render with arbitrary precision for zooms beyond 1e-14. Only the
+    /// Mandelbrot set is supported.
+    #[structopt(long)]
+    deep: bool,
+
+    /// Fractal to render: mandelbrot, burning-ship, tricorn,
+    /// julia:<re>,<im> or multibrot:<exponent>.
+    #[structopt(long, default_value = "mandelbrot", parse(try_from_str = parser::fractal_from_str))]
+    fractal: Fractal,
+
+    /// Maximum number of iterations per point.
+    #[structopt(long, default_value = "255")]
+    limit: u32,
+
+    /// Radius of the circle an orbit has to leave to escape.
+    #[structopt(long, default_value = "2", parse(try_from_str = parser::escape_radius_from_str))]
+    escape_radius: f64,
+
+    /// Color palette: grayscale, fire, ocean or rainbow. Without a palette
+    /// the image is rendered in plain grayscale.
+    #[structopt(long)]
+    palette: Option<Palette>,
+
+    /// Shade the image by distance estimation, interior period or lighting
+    /// instead of escape counts: distance, interior or lighting. Works best
+    /// with a large --escape-radius.
+    #[structopt(long)]
+    shading: Option<Shading>,
+
+    /// Render the density of orbits instead of escape counts: buddhabrot or
+    /// anti-buddhabrot.
+    #[structopt(long, conflicts_with = "shading")]
+    density: Option<density::Mode>,
+
+    /// Iteration limits of the red, green and blue channels of a density
+    /// image, like 5000,500,50 for a Nebulabrot. A single channel with
+    /// --limit by default.
+    #[structopt(long, requires = "density", use_delimiter = true)]
+    channel_limits: Vec<u32>,
+
+    /// Number of orbits followed for a density image, 100 per pixel by
+    /// default.
+    #[structopt(long, requires = "density")]
+    orbits: Option<usize>,
+
+    /// Render on the `mandelbrot worker` processes listening on these
+    /// comma-separated host:port addresses instead of on this machine.
+    #[structopt(long, use_delimiter = true, conflicts_with_all = &["frames", "density"])]
+    workers: Vec<String>,
+
+    /// Keep the rendered tiles in a subdirectory of this directory, named
+    /// after the render parameters, so that an interrupted render started
+    /// again resumes where it stopped. The image is written as PNG or PPM
+    /// without ever being whole in memory.
+    #[structopt(long, parse(from_os_str), conflicts_with_all = &["frames", "density", "workers"])]
+    cache_dir: Option<PathBuf>,
+
+    /// Palette coloring: banded, smooth or histogram.
+    #[structopt(long, default_value = "smooth")]
+    coloring: Coloring,
+
+    /// Number of rendering threads, all available cores by default.
+    #[structopt(long)]
+    threads: Option<usize>,
+
+    /// Write RGBA pixels with a transparent interior of the set.
+    #[structopt(long)]
+    alpha: bool,
+
+    /// Supersample every pixel with N by N samples to smooth out edges.
+    #[structopt(long, default_value = "1")]
+    samples: usize,
+
+    /// Take samples at random positions within a pixel instead of on a grid.
+    #[structopt(long)]
+    jitter: bool,
 
-    #[structopt(short, long, parse(try_from_str = parser::complex_from_str))]
-    upper_left: Complex<f64>,
+    /// Render a zoom animation of this many frames into --zoom-target. A
+    /// filename ending in .gif gets an animated GIF, any other filename a
+    /// sequence of numbered images.
+    #[structopt(long, requires_all = &["zoom-target", "zoom-factor"])]
+    frames: Option<usize>,
 
-    #[structopt(short, long, parse(try_from_str = parser::complex_from_str))]
-    lower_right: Complex<f64>
+    /// Point the animation zooms into.
+    #[structopt(long, parse(try_from_str = parser::complex_from_str))]
+    zoom_target: Option<Complex<f64>>,
+
+    /// How many times smaller the last frame's view is than the first one.
+    #[structopt(long)]
+    zoom_factor: Option<f64>,
+
+    /// Time each frame of an animated GIF is shown, in milliseconds.
+    #[structopt(long, default_value = "40")]
+    frame_delay: u32,
+
+    /// Bits per color component, 16 for high dynamic range PNG or TIFF
+    /// files. The output format is chosen by the file extension: .png,
+    /// .tif, .ppm/.pgm, or .raw/.npy for the iteration counts themselves.
+    #[structopt(long, default_value = "8", possible_values = &["8", "16"])]
+    depth: u32
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Serve /{z}/{x}/{y}.png map tiles and a viewer page on localhost,
+    /// rendered with the fractal, limit, palette and sampling options.
+    /// --deep and --shading aren't supported.
+    Serve {
+        /// Port to listen on.
+        #[structopt(long, default_value = "8000")]
+        port: u16,
+
+        /// Number of rendered tiles kept in memory.
+        #[structopt(long, default_value = "1024")]
+        cache_size: usize,
+    },
+
+    /// Render tiles for a coordinator started with --workers.
+    Worker {
+        /// Port to listen on.
+        #[structopt(long, default_value = "7878")]
+        port: u16,
+
+        /// Address to listen on. Workers have no authentication: only listen
+        /// on other addresses than localhost in trusted networks.
+        #[structopt(long, default_value = "127.0.0.1")]
+        bind: String,
+    },
 }
 
 fn main() {
@@ -25,13 +221,451 @@ fn main() {
 
     let args = Args::from_args();
 
-    let mut pixels = vec![0; args.pixels.0 * args.pixels.1];
+    let n_threads = args.threads.unwrap_or_else(
+        || thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+    );
+
+    let result = match (&args.command, &args.scene, &args.from_image) {
+        (Some(Command::Serve { port, cache_size }), _, _) => serve(&args, *port, *cache_size, n_threads),
+        (Some(Command::Worker { port, bind }), _, _) => worker(bind, *port, n_threads),
+        (None, Some(path), _) => render_scenes(path, &args.workers, args.cache_dir.as_deref(), n_threads),
+        (None, None, Some(image)) => render_from_image(&args, image, n_threads),
+        (None, None, None) if args.preview => preview(&args, n_threads),
+        (None, None, None) => render(&args, n_threads),
+    };
+
+    if let Err(err) = result {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    }
+}
+
+/// Build the rendering options given on the command line.
+fn options(args: &Args) -> renderer::Options {
+    renderer::Options {
+        limit: args.limit,
+        escape_radius: args.escape_radius,
+        fractal: args.fractal,
+        samples: args.samples.max(1),
+        jitter: args.jitter,
+    }
+}
+
+/// Build the colorizer of the palette given on the command line, if any.
+fn colorizer(args: &Args) -> Option<Colorizer> {
+    args.palette.clone().map(|palette| {
+        let mut colorizer = Colorizer::new(palette, args.coloring);
+        colorizer.alpha = args.alpha;
+        colorizer
+    })
+}
+
+fn serve(args: &Args, port: u16, cache_size: usize, n_threads: usize) -> Result<(), Error> {
+    // Tiles are rendered in plain `f64` precision without shading.
+    let conflict = if args.deep {
+        Some("--deep can't be used with serve")
+    } else if args.shading.is_some() {
+        Some("--shading can't be used with serve")
+    } else {
+        None
+    };
+    if let Some(conflict) = conflict {
+        clap::Error::with_description(conflict, clap::ErrorKind::ArgumentConflict).exit();
+    }
+
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .map_err(|err| Error::from(err).context(format!("listening on port {}", port)))?;
+    println!("Serving on http://{}/", listener.local_addr()?);
+
+    let server = TileServer::new(options(args), colorizer(args), cache_size, n_threads);
+    Arc::new(server).serve(listener)?;
+    Ok(())
+}
+
+fn worker(bind: &str, port: u16, n_threads: usize) -> Result<(), Error> {
+    let listener = TcpListener::bind((bind, port))
+        .map_err(|err| Error::from(err).context(format!("listening on {}:{}", bind, port)))?;
+    println!("Worker listening on {}", listener.local_addr()?);
+
+    distributed::serve_worker(listener, n_threads)
+}
+
+/// Render the scene file `path`, or every scene file of the directory
+/// `path`, reporting the scenes that fail and carrying on with the others.
+fn render_scenes(path: &Path, workers: &[String], cache: Option<&Path>, n_threads: usize) -> Result<(), Error> {
+    let files = if path.is_dir() {
+        scene::scene_files(path).map_err(|err| err.context(path.display()))?
+    } else {
+        vec![path.to_path_buf()]
+    };
+
+    let mut n_failed = 0;
+    for file in &files {
+        info!("rendering scene {}", file.display());
+        let result = Scene::load(file).and_then(|scene| {
+            render_scene(&scene, workers, cache, n_threads).map_err(|err| err.context(file.display()))
+        });
+        if let Err(err) = result {
+            eprintln!("error: {}", err);
+            n_failed += 1;
+        }
+    }
+
+    if n_failed > 0 {
+        return Err(Error::Render(format!("{} of {} scenes failed", n_failed, files.len())));
+    }
+    Ok(())
+}
+
+/// Render the scene saved in the metadata of the PNG file `image` to the
+/// file given on the command line.
+fn render_from_image(args: &Args, image: &Path, n_threads: usize) -> Result<(), Error> {
+    let filename = match &args.filename {
+        Some(filename) => filename,
+        None => clap::Error::with_description(
+            "--filename is required to render an image",
+            clap::ErrorKind::MissingRequiredArgument
+        ).exit(),
+    };
+
+    let scene = output::read_png_text(&image.to_string_lossy())
+        .and_then(|text| Scene::from_metadata(&text, filename))
+        .map_err(|err| err.context(image.display()))?;
+    let (upper_left, lower_right) = scene.view.complex_corners();
+    println!("{}: {}x{} image of {}, upper-left {},{}, lower-right {},{}, limit {}",
+             image.display(), scene.bounds.0, scene.bounds.1, scene.options.fractal,
+             upper_left.re, upper_left.im, lower_right.re, lower_right.im, scene.options.limit);
+
+    render_scene(&scene, &args.workers, args.cache_dir.as_deref(), n_threads)
+        .map_err(|err| err.context(&scene.output))
+}
+
+/// Render the image described on the command line.
+fn render(args: &Args, n_threads: usize) -> Result<(), Error> {
+    let (filename, bounds) = match (&args.filename, args.pixels) {
+        (Some(filename), Some(bounds)) => (filename, bounds),
+        _ => clap::Error::with_description(
+            "--filename and --pixels are required to render an image",
+            clap::ErrorKind::MissingRequiredArgument
+        ).exit(),
+    };
+
+    let format = Format::from_filename(filename)?;
+
+    if args.density.is_some() {
+        let conflict = if args.frames.is_some() {
+            Some("--density can't be used with --frames")
+        } else if args.deep {
+            Some("--density can't be used with --deep")
+        } else if args.depth == 16 || format.is_counts() || format == Format::Gif {
+            Some("--density only writes 8-bit images")
+        } else if ![0, 1, 3].contains(&args.channel_limits.len()) {
+            Some("--channel-limits takes one or three limits")
+        } else {
+            None
+        };
+        if let Some(conflict) = conflict {
+            clap::Error::with_description(conflict, clap::ErrorKind::ArgumentConflict).exit();
+        }
+    }
+
+    if let Some(frames) = args.frames {
+        let zoom_factor = args.zoom_factor.unwrap();
+        let conflict = if frames == 0 {
+            Some("--frames must be at least 1")
+        } else if zoom_factor <= 0.0 || !zoom_factor.is_finite() {
+            Some("--zoom-factor must be a positive number")
+        } else if args.depth == 16 {
+            Some("--depth 16 can't be used with --frames")
+        } else if args.deep {
+            Some("--frames can't be used with --deep")
+        } else if args.shading.is_some() {
+            Some("--shading can't be used with --frames")
+        } else {
+            None
+        };
+        if let Some(conflict) = conflict {
+            clap::Error::with_description(conflict, clap::ErrorKind::ArgumentConflict).exit();
+        }
+    }
+
+    let view = view(args, bounds);
+    // Check the view before fitting it, which would widen an empty one.
+    error::validate_size(view.width, view.height)?;
+    let fitted = view.fit(bounds);
+    if fitted != view {
+        info!("corrected view size from {}x{} to {}x{} to match the image",
+              view.width, view.height, fitted.width, fitted.height);
+    }
+
+    let scene = Scene {
+        bounds,
+        view: fitted,
+        options: options(args),
+        palette: args.palette.clone(),
+        coloring: args.coloring,
+        alpha: args.alpha,
+        shading: args.shading,
+        deep: args.deep,
+        depth: args.depth,
+        output: filename.clone(),
+    };
+    match args.cache_dir {
+        Some(_) => scene.validate_cached()?,
+        None => scene.validate()?,
+    }
+
+    match (args.frames, args.density) {
+        (Some(frames), _) => animate(args, &scene, frames, n_threads),
+        (None, Some(mode)) => render_density(args, &scene, mode, n_threads)
+            .map_err(|err| err.context(&scene.output)),
+        (None, None) => render_scene(&scene, &args.workers, args.cache_dir.as_deref(), n_threads)
+            .map_err(|err| err.context(&scene.output)),
+    }
+}
+
+/// Build the view given on the command line for an image of size `bounds`,
+/// before fitting it to the image.
+fn view(args: &Args, bounds: (usize, usize)) -> View {
+    match (&args.center, args.zoom, args.width) {
+        (Some(center), Some(zoom), _) => View::from_zoom(center.clone(), zoom, bounds),
+        (Some(center), None, Some(width)) => View::from_width(center.clone(), width, bounds),
+        (Some(_), None, None) => clap::Error::with_description(
+            "--center requires either --zoom or --width",
+            clap::ErrorKind::MissingRequiredArgument
+        ).exit(),
+        (None, _, _) => View::from_corners(args.upper_left.as_ref().unwrap(),
+                                           args.lower_right.as_ref().unwrap()),
+    }
+}
+
+/// Explore the view given on the command line in the terminal, then print
+/// the options rendering the last view shown.
+fn preview(args: &Args, n_threads: usize) -> Result<(), Error> {
+    // Only the center and the width are kept, the height following from
+    // the size of the terminal.
+    let view = view(args, (1, 1));
+    error::validate_size(view.width, view.height)?;
+
+    let viewport = Viewport { center: view.center.to_complex(), width: view.width, limit: args.limit };
+    let viewport = preview::run(viewport, &options(args), colorizer(args).as_ref(), n_threads)?;
+    println!("{}", viewport.to_args());
+    Ok(())
+}
+
+/// Render the orbit density image given on the command line, with the view
+/// of `scene`.
+fn render_density(args: &Args, scene: &Scene, mode: density::Mode, n_threads: usize) -> Result<(), Error> {
+    let bounds = scene.bounds;
+    let options = density::Options {
+        mode,
+        limits: if args.channel_limits.is_empty() { vec![args.limit] } else { args.channel_limits.clone() },
+        orbits: args.orbits.unwrap_or(bounds.0 * bounds.1 * 100),
+        escape_radius: args.escape_radius,
+        fractal: args.fractal,
+        seed: 0,
+    };
+
+    let (upper_left, lower_right) = scene.view.complex_corners();
+    let pixels = density::render(bounds, upper_left, lower_right, &options, scene.palette.as_ref(), n_threads);
+    renderer::write_image(&scene.output, &pixels, bounds)
+}
+
+/// Render the zoom animation given on the command line, starting from the
+/// view of `scene`.
+fn animate(args: &Args, scene: &Scene, frames: usize, n_threads: usize) -> Result<(), Error> {
+    let (filename, bounds) = (&scene.output, scene.bounds);
+    let colorizer = scene.colorizer();
+
+    let (upper_left, lower_right) = scene.view.complex_corners();
+    let zoom = Zoom {
+        upper_left,
+        lower_right,
+        target: args.zoom_target.unwrap(),
+        zoom: args.zoom_factor.unwrap(),
+        frames,
+    };
+
+    let mut gif = if Format::from_filename(filename)? == Format::Gif {
+        Some(GifWriter::create(filename, bounds, args.frame_delay)?)
+    } else {
+        None
+    };
+
+    for (index, (upper_left, lower_right)) in zoom.frames().enumerate() {
+        info!("rendering frame {}/{}", index + 1, frames);
+
+        let pixels = renderer::render_image(bounds,
+                                            upper_left,
+                                            lower_right,
+                                            &scene.options,
+                                            colorizer.as_ref(),
+                                            n_threads);
+
+        match &mut gif {
+            Some(gif) => gif.write_frame(&pixels)?,
+            None => renderer::write_image(&animation::frame_filename(filename, index),
+                                          &pixels,
+                                          bounds)?,
+        }
+    }
+    Ok(())
+}
+
+/// Render `scene` on this machine, or on the `workers` if there are any,
+/// keeping the tiles in `cache` if given, and write it to its output file
+/// with the scene in its metadata.
+fn render_scene(scene: &Scene, workers: &[String], cache: Option<&Path>, n_threads: usize) -> Result<(), Error> {
+    let (filename, bounds, view, options) = (&scene.output, scene.bounds, &scene.view, &scene.options);
+    let colorizer = scene.colorizer();
+    let metadata = scene.metadata();
+    let (upper_left, lower_right) = view.complex_corners();
+
+    // A cached render streams its tiles to the image, which can thus be far
+    // larger than what fits in memory.
+    match cache {
+        Some(_) => scene.validate_cached()?,
+        None => scene.validate()?,
+    }
+
+    let format = Format::from_filename(filename)?;
+    if format == Format::Gif {
+        return Err(Error::Validation("only zoom animations are written as GIF files".to_string()));
+    }
+
+    let plain = !scene.deep && scene.shading.is_none() && scene.depth == 8 && !format.is_counts();
+    if (!workers.is_empty() || cache.is_some()) && !plain {
+        return Err(Error::Validation(
+            "distributed and cached renders only write 8-bit images, without deep zooms or shading".to_string()
+        ));
+    }
+
+    if !workers.is_empty() {
+        let pixels = distributed::render_image(workers, bounds, upper_left, lower_right, options, colorizer.as_ref())?;
+        return output::write_image(filename, &pixels, bounds, &metadata);
+    }
+
+    if let Some(cache) = cache {
+        let render = CachedRender::new(cache, bounds, upper_left, lower_right, options, colorizer.as_ref())?;
+        let mut control = Control::default().on_progress(|progress: Progress| {
+            info!("{}/{} tiles rendered", progress.done, progress.total);
+        });
+        render.render(n_threads, &mut control)?;
+        return render.write_image(filename, &metadata);
+    }
+
+    if let Some(shading) = scene.shading {
+        let palette = scene.palette.clone().unwrap_or_else(Palette::grayscale);
+        let pixels = shading::render(bounds, upper_left, lower_right, options, shading, &palette, n_threads);
+        return output::write_image(filename, &pixels, bounds, &metadata);
+    }
+
+    if format.is_counts() {
+        let mut counts = vec![0; bounds.0 * bounds.1];
+
+        if scene.deep {
+            let (upper_left, lower_right) = view.corners();
+            deep::render_counts(&mut counts, bounds, &upper_left, &lower_right, options, n_threads)?;
+        } else {
+            renderer::parallel_render_counts(&mut counts,
+                                             bounds,
+                                             upper_left,
+                                             lower_right,
+                                             options,
+                                             n_threads);
+        }
+
+        return output::write_counts(filename, &counts, bounds);
+    }
+
+    if scene.depth == 16 {
+        let pixels = if scene.deep {
+            render_deep(view, bounds, options, colorizer.as_ref(), n_threads,
+                        palette::grayscale16, Colorizer::colorize16)?
+        } else {
+            renderer::render_image16(bounds,
+                                     upper_left,
+                                     lower_right,
+                                     options,
+                                     colorizer.as_ref(),
+                                     n_threads)
+        };
+
+        output::write_image16(filename, &pixels, bounds, &metadata)
+    } else {
+        let pixels = if scene.deep {
+            render_deep(view, bounds, options, colorizer.as_ref(), n_threads,
+                        palette::grayscale, Colorizer::colorize)?
+        } else {
+            renderer::render_image(bounds,
+                                   upper_left,
+                                   lower_right,
+                                   options,
+                                   colorizer.as_ref(),
+                                   n_threads)
+        };
+
+        output::write_image(filename, &pixels, bounds, &metadata)
+    }
+}
+
+/// Render `view` with arbitrary precision, supersampled on a regular grid,
+/// turning the samples into pixels with `grayscale` or `colorize`.
+fn render_deep<T, G, C>(
+    view: &View,
+    bounds: (usize, usize),
+    options: &renderer::Options,
+    colorizer: Option<&Colorizer>,
+    n_threads: usize,
+    grayscale: G,
+    colorize: C,
+) -> Result<Vec<T>, Error>
+where
+    T: Copy + Default + Into<u64> + TryFrom<u64>,
+    G: Fn(&[u32], u32) -> Vec<T>,
+    C: Fn(&Colorizer, &[Option<f64>], u32) -> Vec<T>,
+{
+    let (upper_left, lower_right) = view.corners();
+    let samples = options.samples;
+    let sample_bounds = (bounds.0 * samples, bounds.1 * samples);
+
+    let pixels = match colorizer {
+        None => {
+            let mut counts = vec![0; sample_bounds.0 * sample_bounds.1];
+            deep::render_counts(&mut counts,
+                                sample_bounds,
+                                &upper_left,
+                                &lower_right,
+                                options,
+                                n_threads)?;
+            grayscale(&counts, options.limit)
+        }
+        Some(colorizer) => {
+            let mut values = vec![None; sample_bounds.0 * sample_bounds.1];
+            deep::render_escape(&mut values,
+                                sample_bounds,
+                                &upper_left,
+                                &lower_right,
+                                options,
+                                n_threads)?;
+            colorize(colorizer, &values, options.limit)
+        }
+    };
+
+    Ok(renderer::downsample(&pixels, bounds, samples))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_empty_view() {
+        let args = Args::from_iter(&["main", "-f", "empty.png", "-p", "40x30", "-u=-0.5,0.5", "-l=-0.5,0.5"]);
 
-    renderer::parallel_render(&mut pixels,
-                              args.pixels,
-                              args.upper_left,
-                              args.lower_right,
-                              8);
+        let error = render(&args, 1).unwrap_err();
 
-    renderer::write_image(&args.filename, &pixels, args.pixels).expect("error writing PNG file");
+        assert!(matches!(error, Error::Validation(_)), "{}", error);
+        assert!(!Path::new("empty.png").exists());
+    }
 }
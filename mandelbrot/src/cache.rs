@@ -0,0 +1,292 @@
+//! Resumable renders of images too large to render in one go.
+//!
+//! The image is cut into tiles of `TILE_PIXELS` pixels, and each tile is
+//! saved to a cache directory as soon as it's rendered. The directory is
+//! named after a hash of the render parameters, so a render started again
+//! with the same parameters, after a crash or a cancellation, only renders
+//! the missing tiles. The final image is then written band by band from the
+//! tiles, holding a single row of tiles in memory.
+//!
+//! Tiles are colored on their own, which rules out histogram coloring as it
+//! depends on the whole image.
+use super::output;
+use super::palette::{self, Colorizer, Coloring};
+use super::renderer::{self, Options};
+use super::scheduler::{self, Control, Progress, Tile};
+use super::Error;
+use log::{debug, info};
+use num::Complex;
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Width and height of a cached tile in pixels.
+pub const TILE_PIXELS: usize = 512;
+
+/// The parameters a cached render depends on, saved next to its tiles.
+#[derive(Debug, Serialize)]
+struct Key<'a> {
+    bounds: (usize, usize),
+    upper_left: Complex<f64>,
+    lower_right: Complex<f64>,
+    options: &'a Options,
+    /// The colorizer, written with `Debug` as it isn't serializable.
+    colorizer: Option<String>,
+}
+
+/// A render whose tiles are kept in a cache directory.
+#[derive(Debug)]
+pub struct CachedRender<'a> {
+    directory: PathBuf,
+    bounds: (usize, usize),
+    upper_left: Complex<f64>,
+    lower_right: Complex<f64>,
+    options: &'a Options,
+    colorizer: Option<&'a Colorizer>,
+    tiles: Vec<Tile>,
+}
+
+impl<'a> CachedRender<'a> {
+    /// Prepare the render of the image of size `bounds` showing the
+    /// rectangle from `upper_left` to `lower_right`, in grayscale without a
+    /// `colorizer`, keeping its tiles in a subdirectory of `cache_directory`.
+    pub fn new(
+        cache_directory: &Path,
+        bounds: (usize, usize),
+        upper_left: Complex<f64>,
+        lower_right: Complex<f64>,
+        options: &'a Options,
+        colorizer: Option<&'a Colorizer>,
+    ) -> Result<CachedRender<'a>, Error> {
+        if colorizer.is_some_and(|colorizer| colorizer.coloring == Coloring::Histogram) {
+            return Err(Error::Validation("cached renders can't use histogram coloring".to_string()));
+        }
+
+        let key = Key {
+            bounds,
+            upper_left,
+            lower_right,
+            options,
+            colorizer: colorizer.map(|colorizer| format!("{:?}", colorizer)),
+        };
+        let description = serde_json::to_string_pretty(&key).map_err(|err| Error::Io(err.into()))?;
+        let directory = cache_directory.join(format!("{:016x}", fnv1a(description.as_bytes())));
+
+        // Hashes of different parameters could collide: check the saved ones.
+        let saved_key = directory.join("render.json");
+        match fs::read_to_string(&saved_key) {
+            Ok(saved) if saved != description => return Err(Error::Validation(format!(
+                "{} holds the tiles of another render", directory.display()
+            ))),
+            Ok(_) => {}
+            Err(_) => {
+                fs::create_dir_all(&directory)?;
+                fs::write(&saved_key, description)?;
+            }
+        }
+
+        Ok(CachedRender {
+            directory,
+            bounds,
+            upper_left,
+            lower_right,
+            options,
+            colorizer,
+            tiles: scheduler::tiles(bounds, TILE_PIXELS),
+        })
+    }
+
+    /// Directory holding the tiles of the render.
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Number of bytes per pixel of the image.
+    pub fn channels(&self) -> usize {
+        self.colorizer.map_or(1, Colorizer::channels)
+    }
+
+    /// Tiles of the image that aren't in the cache yet.
+    pub fn missing_tiles(&self) -> Vec<Tile> {
+        self.tiles.iter()
+            .filter(|tile| {
+                let size = fs::metadata(self.tile_path(tile)).map(|metadata| metadata.len());
+                size.ok() != Some((tile.len() * self.channels()) as u64)
+            })
+            .copied()
+            .collect()
+    }
+
+    /// Render the missing tiles with `n_threads` threads each, saving them
+    /// one by one, and return how many were rendered.
+    ///
+    /// `control` gets the progress over all the tiles of the image,
+    /// counting the ones found in the cache as done. Once it's cancelled,
+    /// the render stops with the tiles done so far saved.
+    pub fn render(&self, n_threads: usize, control: &mut Control) -> Result<usize, Error> {
+        let missing = self.missing_tiles();
+        let mut progress = Progress { done: self.tiles.len() - missing.len(), total: self.tiles.len() };
+        if progress.done > 0 {
+            info!("resuming render: {} of {} tiles cached in {}",
+                  progress.done, progress.total, self.directory.display());
+        }
+        control.report(progress);
+
+        for tile in &missing {
+            let pixels = self.render_tile(tile, n_threads, &mut Control::new(control.cancel.clone()))?;
+
+            // Write to a temporary file first, so that a crash never leaves
+            // a partial tile behind.
+            let path = self.tile_path(tile);
+            let partial = path.with_extension("partial");
+            fs::write(&partial, &pixels)?;
+            fs::rename(&partial, &path)?;
+
+            progress.done += 1;
+            debug!("cached tile {:?} ({}/{})", tile, progress.done, progress.total);
+            control.report(progress);
+        }
+        Ok(missing.len())
+    }
+
+    /// Write the image to `filename`, as PNG or PPM/PGM, from the cached
+    /// tiles, which must all be rendered. PNG files get the text chunks of
+    /// `text`.
+    pub fn write_image(&self, filename: &str, text: &[(String, String)]) -> Result<(), Error> {
+        let channels = self.channels();
+        let bands = self.tiles
+            .chunks(self.bounds.0.div_ceil(TILE_PIXELS).max(1))
+            .map(|row| {
+                let tiles = row.iter()
+                    .map(|tile| fs::read(self.tile_path(tile)).map_err(|err| {
+                        Error::from(err).context(format!("tile {:?} of {}", tile, self.directory.display()))
+                    }))
+                    .collect::<Result<Vec<_>, Error>>()?;
+
+                let mut band = Vec::with_capacity(self.bounds.0 * row[0].height * channels);
+                for line in 0..row[0].height {
+                    for (tile, pixels) in row.iter().zip(&tiles) {
+                        let length = tile.width * channels;
+                        band.extend_from_slice(&pixels[line * length..(line + 1) * length]);
+                    }
+                }
+                Ok(band)
+            });
+
+        output::write_image_bands(filename, self.bounds, channels, bands, text)
+    }
+
+    fn tile_path(&self, tile: &Tile) -> PathBuf {
+        self.directory.join(format!("{}-{}.tile", tile.top, tile.left))
+    }
+
+    /// Render the pixels of `tile` the way `renderer::render_image` renders
+    /// them as part of the whole image.
+    fn render_tile(&self, tile: &Tile, n_threads: usize, control: &mut Control) -> Result<Vec<u8>, Error> {
+        let samples = self.options.samples.max(1);
+        let sample_bounds = (self.bounds.0 * samples, self.bounds.1 * samples);
+        let tile_bounds = (tile.width * samples, tile.height * samples);
+        let point = |(column, row)| renderer::sample_point(sample_bounds,
+                                                           (tile.left * samples + column, tile.top * samples + row),
+                                                           self.upper_left,
+                                                           self.lower_right,
+                                                           self.options);
+
+        let pixels = match self.colorizer {
+            None => {
+                let mut counts = vec![0; tile_bounds.0 * tile_bounds.1];
+                renderer::parallel_counts(&mut counts, tile_bounds, self.options, n_threads, control, point)?;
+                palette::grayscale(&counts, self.options.limit)
+            }
+            Some(colorizer) => {
+                let mut values = vec![None; tile_bounds.0 * tile_bounds.1];
+                renderer::parallel_escape(&mut values, tile_bounds, self.options, n_threads, control, point)?;
+                colorizer.colorize(&values, self.options.limit)
+            }
+        };
+        Ok(renderer::downsample(&pixels, (tile.width, tile.height), samples))
+    }
+}
+
+/// 64-bit FNV-1a hash, stable across builds unlike the standard hasher.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::palette::Palette;
+    use crate::scheduler::CancelToken;
+
+    const UPPER_LEFT: Complex<f64> = Complex { re: -2.0, im: 1.2 };
+    const LOWER_RIGHT: Complex<f64> = Complex { re: 1.0, im: -1.2 };
+
+    fn temp_directory(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("mandelbrot-cache-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_matches_render_image() {
+        let directory = temp_directory("match");
+        let options = Options { limit: 50, samples: 2, jitter: true, ..Options::default() };
+        let colorizer = Colorizer::new(Palette::by_name("ocean").unwrap(), Coloring::Smooth);
+        let bounds = (530, 520);
+        let filename = directory.join("image.png").to_string_lossy().into_owned();
+
+        for colorizer in &[None, Some(&colorizer)] {
+            let render = CachedRender::new(&directory, bounds, UPPER_LEFT, LOWER_RIGHT, &options, *colorizer).unwrap();
+            assert_eq!(render.render(2, &mut Control::default()).unwrap(), 4);
+            render.write_image(&filename, &[]).unwrap();
+
+            let expected = renderer::render_image(bounds, UPPER_LEFT, LOWER_RIGHT, &options, *colorizer, 2);
+            assert!(image::open(&filename).unwrap().into_bytes() == expected);
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_resume() {
+        let directory = temp_directory("resume");
+        let options = Options { limit: 50, ..Options::default() };
+        let bounds = (1030, 520);
+        let render = CachedRender::new(&directory, bounds, UPPER_LEFT, LOWER_RIGHT, &options, None).unwrap();
+
+        // Cancel after the first two tiles.
+        let cancel = CancelToken::new();
+        let mut reports = Vec::new();
+        let result = render.render(1, &mut Control::new(cancel.clone()).on_progress(|progress: Progress| {
+            reports.push(progress.done);
+            if progress.done == 2 {
+                cancel.cancel();
+            }
+        }));
+        assert!(matches!(result, Err(Error::Render(_))));
+        assert_eq!(reports, vec![0, 1, 2]);
+        assert_eq!(render.missing_tiles().len(), 4);
+        assert!(render.write_image(&directory.join("image.png").to_string_lossy(), &[]).is_err());
+
+        let resumed = CachedRender::new(&directory, bounds, UPPER_LEFT, LOWER_RIGHT, &options, None).unwrap();
+        assert_eq!(resumed.directory(), render.directory());
+        assert_eq!(resumed.render(1, &mut Control::default()).unwrap(), 4);
+        assert!(resumed.missing_tiles().is_empty());
+
+        let other = Options { limit: 60, ..options };
+        let other = CachedRender::new(&directory, bounds, UPPER_LEFT, LOWER_RIGHT, &other, None).unwrap();
+        assert_ne!(other.directory(), render.directory());
+        assert_eq!(other.missing_tiles().len(), 6);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_rejects_histogram_coloring() {
+        let (options, colorizer) = (Options::default(), Colorizer::new(Palette::grayscale(), Coloring::Histogram));
+
+        let result = CachedRender::new(&temp_directory("histogram"), (10, 10), UPPER_LEFT, LOWER_RIGHT,
+                                       &options, Some(&colorizer));
+
+        assert!(matches!(result, Err(Error::Validation(_))));
+    }
+}
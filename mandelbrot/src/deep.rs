@@ -0,0 +1,522 @@
+//! Deep zoom renderer based on perturbation theory.
+//!
+//! Beyond a zoom of about `1e-14` neighbouring pixels map to the same `f64`
+//! point, so the regular renderer draws flat blocks. Here only one orbit, the
+//! reference orbit of the image center, is computed with arbitrary precision.
+//! Every pixel then iterates in `f64` the (small) difference between its own
+//! orbit and the reference one, switching back to the start of the reference
+//! orbit whenever the difference stops being small (so-called rebasing).
+//!
+//! Pixel offsets are kept as `f64`, which limits the zoom to about `1e-300`.
+//!
+//! Series approximation, which skips the first iterations of all pixels at
+//! once by following a polynomial of the offset, is deliberately left out:
+//! it needs error bounds to know how far it can skip, and a wrong bound
+//! silently distorts the image, while perturbation alone is exact enough and
+//! already fast compared to arbitrary-precision pixels.
+use super::fractal::Fractal;
+use super::renderer::Options;
+use super::scheduler;
+use super::Error;
+use log::debug;
+use num::{BigInt, Complex, Float, ToPrimitive, Zero};
+use std::fmt;
+use std::ops::{Add, Mul, Sub};
+use std::str::FromStr;
+
+/// Number of bits kept on top of the ones needed to tell pixels apart.
+const GUARD_BITS: u32 = 64;
+
+/// Largest scale numbers are parsed with: far beyond what zooms limited to
+/// `1e-300` use, and small enough to keep parsing them cheap.
+pub const MAX_SCALE: u32 = 10_000;
+
+/// Largest number of digits before the point numbers are parsed with.
+const MAX_WHOLE_DIGITS: i64 = 10_000;
+
+/// Arbitrary-precision fixed-point number with `scale` fractional bits.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fixed {
+    mantissa: BigInt,
+    scale: u32,
+}
+
+impl Fixed {
+    /// Return the same number with `scale` fractional bits.
+    pub fn with_scale(&self, scale: u32) -> Fixed {
+        let mantissa = if scale >= self.scale {
+            &self.mantissa << (scale - self.scale)
+        } else {
+            &self.mantissa >> (self.scale - scale)
+        };
+        Fixed { mantissa, scale }
+    }
+
+    /// Convert `x` to a number with `scale` fractional bits, exactly unless
+    /// `x` has more fractional bits than that.
+    pub fn from_f64(x: f64, scale: u32) -> Fixed {
+        let (mantissa, exponent, sign) = x.integer_decode();
+        let mantissa = BigInt::from(mantissa) * sign;
+        let shift = exponent as i64 + scale as i64;
+        let mantissa = if shift >= 0 {
+            mantissa << shift as usize
+        } else {
+            mantissa >> (-shift) as usize
+        };
+        Fixed { mantissa, scale }
+    }
+
+    /// Number of fractional bits.
+    pub fn scale(&self) -> u32 {
+        self.scale
+    }
+
+    /// Divide the number by two.
+    pub fn half(&self) -> Fixed {
+        Fixed { mantissa: &self.mantissa >> 1, scale: self.scale }
+    }
+
+    /// Convert to the nearest `f64`, keeping the precision of tiny numbers
+    /// as long as they are within the `f64` range.
+    pub fn to_f64(&self) -> f64 {
+        let bits = self.mantissa.bits();
+        let shift = bits.saturating_sub(64);
+        let top = (&self.mantissa >> shift as usize).to_f64().unwrap_or(0.0);
+        let exponent = shift as i64 - self.scale as i64;
+        // Scale in two steps so that intermediate powers of two don't
+        // underflow before the top bits are applied.
+        let half = (exponent / 2) as i32;
+        top * 2f64.powi(half) * 2f64.powi(exponent as i32 - half)
+    }
+}
+
+impl FromStr for Fixed {
+    type Err = String;
+
+    /// Parse a decimal number like `-0.75`, `.5` or `1.5e-300`, choosing the
+    /// scale from the number of significant decimal places, or the smallest
+    /// one representing the number exactly when there is one, as for the
+    /// numbers `Display` writes.
+    ///
+    /// Numbers needing a scale above `MAX_SCALE` are rejected rather than
+    /// rounded.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || format!("wrong decimal format: {}", s);
+
+        let (number, exponent) = match s.find(['e', 'E']) {
+            None => (s, 0),
+            Some(index) => (&s[..index], s[index + 1..].parse::<i64>().map_err(|_| error())?)
+        };
+        let (negative, number) = match number.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, number.strip_prefix('+').unwrap_or(number))
+        };
+        let (whole, fraction) = match number.find('.') {
+            None => (number, ""),
+            Some(index) => (&number[..index], &number[index + 1..])
+        };
+        let all_digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+        if whole.len() + fraction.len() == 0 || !all_digits(whole) || !all_digits(fraction) {
+            return Err(error());
+        }
+
+        let too_precise = || format!("{} needs more than {} bits of precision", s, MAX_SCALE);
+        // Every decimal place takes at least one bit.
+        let decimals = (fraction.len() as i64).checked_sub(exponent)
+            .filter(|&decimals| decimals <= MAX_SCALE as i64)
+            .ok_or_else(too_precise)?;
+        if exponent.checked_add(whole.len() as i64).is_none_or(|n| n > MAX_WHOLE_DIGITS) {
+            return Err(format!("too many digits: {}", s));
+        }
+        let digits = BigInt::from_str(&format!("0{}{}", whole, fraction)).map_err(|_| error())?;
+        // d decimal places are d binary places when the digits are a multiple
+        // of 5^d, and else about d log2(10) of them plus guard bits.
+        let exact = decimals <= 0 || (&digits % BigInt::from(5).pow(decimals as u32)).is_zero();
+        let scale = if exact {
+            (decimals.max(0) as u32).max(2 * GUARD_BITS)
+        } else {
+            ((decimals as f64 * std::f64::consts::LOG2_10).ceil() as u32).max(GUARD_BITS) + GUARD_BITS
+        };
+        if scale > MAX_SCALE {
+            return Err(too_precise());
+        }
+
+        let scaled = digits << scale as usize;
+        let mut mantissa = if decimals >= 0 {
+            scaled / BigInt::from(10).pow(decimals as u32)
+        } else {
+            scaled * BigInt::from(10).pow((-decimals) as u32)
+        };
+        if negative {
+            mantissa = -mantissa;
+        }
+
+        Ok(Fixed { mantissa, scale })
+    }
+}
+
+impl fmt::Display for Fixed {
+    /// Format the number as the exact decimal it stands for, which
+    /// `Fixed::from_str` reads back to the same value.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // m / 2^scale is m * 5^scale / 10^scale, with `scale` decimals.
+        let scale = self.scale as usize;
+        let mut digits = (self.mantissa.magnitude() * num::BigUint::from(5u32).pow(self.scale)).to_string();
+        if digits.len() <= scale {
+            digits.insert_str(0, &"0".repeat(scale + 1 - digits.len()));
+        }
+        let (whole, fraction) = digits.split_at(digits.len() - scale);
+        let sign = if self.mantissa < BigInt::zero() { "-" } else { "" };
+        match fraction.trim_end_matches('0') {
+            "" => write!(f, "{}{}", sign, whole),
+            fraction => write!(f, "{}{}.{}", sign, whole, fraction)
+        }
+    }
+}
+
+impl<'a> Add for &'a Fixed {
+    type Output = Fixed;
+
+    fn add(self, other: &'a Fixed) -> Fixed {
+        assert_eq!(self.scale, other.scale);
+        Fixed { mantissa: &self.mantissa + &other.mantissa, scale: self.scale }
+    }
+}
+
+impl<'a> Sub for &'a Fixed {
+    type Output = Fixed;
+
+    fn sub(self, other: &'a Fixed) -> Fixed {
+        assert_eq!(self.scale, other.scale);
+        Fixed { mantissa: &self.mantissa - &other.mantissa, scale: self.scale }
+    }
+}
+
+impl<'a> Mul for &'a Fixed {
+    type Output = Fixed;
+
+    fn mul(self, other: &'a Fixed) -> Fixed {
+        assert_eq!(self.scale, other.scale);
+        Fixed { mantissa: (&self.mantissa * &other.mantissa) >> self.scale as usize, scale: self.scale }
+    }
+}
+
+/// Complex number with arbitrary-precision components.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BigComplex {
+    pub re: Fixed,
+    pub im: Fixed,
+}
+
+impl BigComplex {
+    /// Return the same number with `scale` fractional bits in both components.
+    pub fn with_scale(&self, scale: u32) -> BigComplex {
+        BigComplex { re: self.re.with_scale(scale), im: self.im.with_scale(scale) }
+    }
+
+    /// Largest scale of the two components.
+    pub fn scale(&self) -> u32 {
+        self.re.scale().max(self.im.scale())
+    }
+
+    /// Convert to the nearest `Complex<f64>`.
+    pub fn to_complex(&self) -> Complex<f64> {
+        Complex { re: self.re.to_f64(), im: self.im.to_f64() }
+    }
+}
+
+impl fmt::Display for BigComplex {
+    /// Format the number the way `parser::big_complex_from_str` reads it.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{}", self.re, self.im)
+    }
+}
+
+/// Orbit of the reference point, rounded to `f64` once computed.
+pub struct ReferenceOrbit {
+    orbit: Vec<Complex<f64>>,
+}
+
+impl ReferenceOrbit {
+    /// Iterate `z = z^2 + c` for the reference point `c` with the precision
+    /// of `c`, stopping when the orbit escapes or after `limit` iterations.
+    pub fn new(c: &BigComplex, limit: u32, escape_radius: f64) -> ReferenceOrbit {
+        let c = c.with_scale(c.scale());
+        let bailout = escape_radius * escape_radius;
+        let zero = Fixed { mantissa: BigInt::zero(), scale: c.re.scale() };
+        let (mut re, mut im) = (zero.clone(), zero);
+        let mut orbit = Vec::with_capacity(limit as usize + 1);
+
+        for _ in 0..=limit {
+            let z = Complex { re: re.to_f64(), im: im.to_f64() };
+            orbit.push(z);
+            if z.norm_sqr() > bailout {
+                break;
+            }
+            let (re_sqr, im_sqr, re_im) = (&re * &re, &im * &im, &re * &im);
+            re = &(&re_sqr - &im_sqr) + &c.re;
+            im = &(&re_im + &re_im) + &c.im;
+        }
+
+        debug!("reference orbit length: {}", orbit.len());
+
+        ReferenceOrbit { orbit }
+    }
+
+    /// Iterate the point at offset `dc` from the reference point and return
+    /// the number of iterations it took to escape along with the squared norm
+    /// of the first orbit value outside of the escape circle.
+    fn escape(&self, dc: Complex<f64>, limit: u32, escape_radius: f64) -> Option<(u32, f64)> {
+        let bailout = escape_radius * escape_radius;
+        let last = self.orbit.len() - 1;
+        let mut dz = Complex { re: 0.0, im: 0.0 };
+        let mut m = 0;
+
+        for i in 0..limit {
+            let z = self.orbit[m] + dz;
+            let norm_sqr = z.norm_sqr();
+            if norm_sqr > bailout {
+                return Some((i, norm_sqr));
+            }
+            // Rebase onto the start of the reference orbit when the pixel
+            // orbit gets closer to zero than to the reference, or when the
+            // reference orbit has run out.
+            if norm_sqr < dz.norm_sqr() || m == last {
+                dz = z;
+                m = 0;
+            }
+            dz = self.orbit[m] * dz * 2.0 + dz * dz + dc;
+            m += 1;
+        }
+        None
+    }
+
+    /// The same as `Fractal::escape_time` for the point at offset `dc` from
+    /// the reference point.
+    pub fn escape_time(&self, dc: Complex<f64>, limit: u32, escape_radius: f64) -> Option<u32> {
+        self.escape(dc, limit, escape_radius).map(|(i, _)| i)
+    }
+
+    /// The same as `Fractal::smooth_escape_time` for the point at offset `dc`
+    /// from the reference point.
+    pub fn smooth_escape_time(&self, dc: Complex<f64>, limit: u32, escape_radius: f64) -> Option<f64> {
+        self.escape(dc, limit, escape_radius).map(|(i, norm_sqr)| {
+            let nu = (norm_sqr.ln() / 2.0 / escape_radius.ln()).ln() / std::f64::consts::LN_2;
+            (i as f64 + 1.0 - nu).max(0.0)
+        })
+    }
+}
+
+/// The same as `renderer::parallel_render_counts` for corners given with
+/// arbitrary precision, or an error for fractals other than the Mandelbrot
+/// set, the only one supported.
+pub fn render_counts(
+    counts: &mut [u32],
+    bounds: (usize, usize),
+    upper_left: &BigComplex,
+    lower_right: &BigComplex,
+    options: &Options,
+    n_threads: usize,
+) -> Result<(), Error> {
+    render_offsets(counts, bounds, upper_left, lower_right, options, n_threads, |orbit, dc| {
+        orbit.escape_time(dc, options.limit, options.escape_radius).unwrap_or(options.limit)
+    })
+}
+
+/// The same as `renderer::parallel_render_escape` for corners given with
+/// arbitrary precision, or an error for fractals other than the Mandelbrot
+/// set, the only one supported.
+pub fn render_escape(
+    values: &mut [Option<f64>],
+    bounds: (usize, usize),
+    upper_left: &BigComplex,
+    lower_right: &BigComplex,
+    options: &Options,
+    n_threads: usize,
+) -> Result<(), Error> {
+    render_offsets(values, bounds, upper_left, lower_right, options, n_threads, |orbit, dc| {
+        orbit.smooth_escape_time(dc, options.limit, options.escape_radius)
+    })
+}
+
+/// Compute the reference orbit of the center of the rectangle and fill
+/// `buffer` with `value` of every pixel's offset from the center, using
+/// `n_threads` threads.
+fn render_offsets<T, F>(
+    buffer: &mut [T],
+    bounds: (usize, usize),
+    upper_left: &BigComplex,
+    lower_right: &BigComplex,
+    options: &Options,
+    n_threads: usize,
+    value: F,
+) -> Result<(), Error>
+where
+    T: Send,
+    F: Fn(&ReferenceOrbit, Complex<f64>) -> T + Sync,
+{
+    assert_eq!(buffer.len(), bounds.0 * bounds.1);
+    if options.fractal != Fractal::Mandelbrot {
+        return Err(Error::Validation("deep zoom supports the Mandelbrot set only".to_string()));
+    }
+
+    let scale = upper_left.scale().max(lower_right.scale());
+    let (upper_left, lower_right) = (upper_left.with_scale(scale), lower_right.with_scale(scale));
+    let width = (&lower_right.re - &upper_left.re).to_f64();
+    let height = (&upper_left.im - &lower_right.im).to_f64();
+
+    // Keep enough bits to tell the pixels apart, plus some guard bits.
+    let pixel_size = (width / bounds.0 as f64).abs().min((height / bounds.1 as f64).abs());
+    let scale = scale.max((-pixel_size.log2()).ceil().max(0.0) as u32 + GUARD_BITS);
+    let (upper_left, lower_right) = (upper_left.with_scale(scale), lower_right.with_scale(scale));
+    let center = BigComplex {
+        re: (&upper_left.re + &lower_right.re).half(),
+        im: (&upper_left.im + &lower_right.im).half(),
+    };
+
+    debug!("deep rendering: scale={} bits, pixel_size={:e}", scale, pixel_size);
+
+    let orbit = ReferenceOrbit::new(&center, options.limit, options.escape_radius);
+
+    scheduler::render_tiles(buffer, bounds, n_threads, |column, row| {
+        let dc = Complex {
+            re: width * (column as f64 / bounds.0 as f64 - 0.5),
+            im: height * (0.5 - row as f64 / bounds.1 as f64),
+        };
+        value(&orbit, dc)
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer;
+    use rstest::rstest;
+
+    fn big(re: &str, im: &str) -> BigComplex {
+        BigComplex { re: re.parse().unwrap(), im: im.parse().unwrap() }
+    }
+
+    #[rstest]
+    #[case("0", 0.0)]
+    #[case("-0.75", -0.75)]
+    #[case(".5", 0.5)]
+    #[case("+12.", 12.0)]
+    #[case("1.5e-300", 1.5e-300)]
+    #[case("-2e3", -2000.0)]
+    fn test_parse_fixed(#[case] s: &str, #[case] expected: f64) {
+        let actual = s.parse::<Fixed>().unwrap().to_f64();
+
+        assert!((actual - expected).abs() <= expected.abs() * 1e-15, "{} vs {}", actual, expected);
+    }
+
+    #[rstest]
+    #[case("")]
+    #[case("-")]
+    #[case(".")]
+    #[case("1.2.3")]
+    #[case("1e")]
+    #[case("0x10")]
+    #[case("1.5e-3000")]
+    #[case("1e-99999")]
+    #[case("1e99999")]
+    #[case("1e-9223372036854775808")]
+    fn test_parse_fixed_error(#[case] s: &str) {
+        assert!(s.parse::<Fixed>().is_err());
+    }
+
+    #[test]
+    fn test_fixed_arithmetic() {
+        let a: Fixed = "1.25".parse().unwrap();
+        let b = "-0.5".parse::<Fixed>().unwrap().with_scale(a.scale());
+
+        assert_eq!((&a + &b).to_f64(), 0.75);
+        assert_eq!((&a - &b).to_f64(), 1.75);
+        assert_eq!((&a * &b).to_f64(), -0.625);
+        assert_eq!(a.half().to_f64(), 0.625);
+    }
+
+    #[rstest]
+    #[case(0.0, 64)]
+    #[case(-0.75, 64)]
+    #[case(1.5e-30, 200)]
+    #[case(-1234.5, 80)]
+    fn test_fixed_from_f64(#[case] x: f64, #[case] scale: u32) {
+        let fixed = Fixed::from_f64(x, scale);
+
+        assert_eq!(fixed.scale(), scale);
+        assert_eq!(fixed.to_f64(), x);
+    }
+
+    #[rstest]
+    #[case("0", "0")]
+    #[case("-0.75", "-0.75")]
+    #[case("12", "12")]
+    #[case("-2e3", "-2000")]
+    #[case("0.1", "0.09999999999999999999999999999999999999")]
+    #[case("-1.5e-2000", "-0.000")]
+    fn test_display_fixed(#[case] s: &str, #[case] prefix: &str) {
+        let fixed: Fixed = s.parse().unwrap();
+
+        let displayed = fixed.to_string();
+
+        assert!(displayed.starts_with(prefix), "{}", displayed);
+        assert_eq!(displayed.parse::<Fixed>().unwrap().with_scale(fixed.scale()), fixed);
+        assert_eq!(big(s, "-0.5").to_string(), format!("{},-0.5", displayed));
+    }
+
+    #[test]
+    fn test_parse_fixed_precision() {
+        let error = "1.5e-3000".parse::<Fixed>().unwrap_err();
+        assert!(error.contains("more than 10000 bits of precision"), "{}", error);
+
+        // Exact decimals need no more bits than decimal places.
+        let tiny = Fixed { mantissa: BigInt::from(-3), scale: MAX_SCALE };
+        let parsed: Fixed = tiny.to_string().parse().unwrap();
+        assert_eq!(parsed, tiny);
+        assert_eq!("0.0625".parse::<Fixed>().unwrap().scale(), 2 * GUARD_BITS);
+    }
+
+    #[test]
+    fn test_deep_render_matches_regular_render() {
+        let bounds = (40, 30);
+        let options = renderer::Options { limit: 500, ..Default::default() };
+        let (upper_left, lower_right) = (big("-0.75", "0.125"), big("-0.73", "0.11"));
+        let mut expected = vec![0; bounds.0 * bounds.1];
+        let mut actual = vec![0; bounds.0 * bounds.1];
+
+        renderer::render_counts(&mut expected, bounds,
+                                upper_left.to_complex(), lower_right.to_complex(),
+                                &options);
+        render_counts(&mut actual, bounds, &upper_left, &lower_right, &options, 4).unwrap();
+
+        let mismatches = actual.iter().zip(expected.iter()).filter(|(a, e)| a != e).count();
+        assert!(mismatches < bounds.0 * bounds.1 / 100, "{} mismatches", mismatches);
+    }
+
+    #[test]
+    fn test_deep_render_resolves_tiny_views() {
+        let bounds = (16, 16);
+        let options = renderer::Options { limit: 2000, ..Default::default() };
+        let upper_left = big("-1.7490000000000000000000000000001", "0.0000000000000000000000000000001");
+        let lower_right = big("-1.7489999999999999999999999999999", "-0.0000000000000000000000000000001");
+        let mut counts = vec![0; bounds.0 * bounds.1];
+
+        render_counts(&mut counts, bounds, &upper_left, &lower_right, &options, 2).unwrap();
+
+        counts.sort_unstable();
+        counts.dedup();
+        assert!(counts.len() > 4, "{:?}", counts);
+    }
+
+    #[test]
+    fn test_deep_render_other_fractals() {
+        let options = renderer::Options { fractal: Fractal::Tricorn, ..Default::default() };
+        let (upper_left, lower_right) = (big("-2", "1"), big("1", "-1"));
+
+        let error = render_escape(&mut [None; 4], (2, 2), &upper_left, &lower_right, &options, 1).unwrap_err();
+
+        assert!(matches!(error, Error::Validation(_)), "{}", error);
+    }
+}
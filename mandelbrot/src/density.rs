@@ -0,0 +1,277 @@
+//! Orbit density renderers: the Buddhabrot and its relatives.
+//!
+//! Instead of coloring each point by how fast its orbit escapes, these
+//! renderers pick points `c` at random, follow their orbits and count how
+//! often the orbits pass through each pixel:
+//!
+//! - `Mode::Buddhabrot` counts the orbits of the points outside the set, up
+//!   to where they escape.
+//! - `Mode::AntiBuddhabrot` counts the orbits of the points that don't
+//!   escape, which trace the cycles of the interior.
+//!
+//! Each color channel can have its own iteration limit. With three limits
+//! the image is RGB, which gives the Nebulabrot when the limits differ, for
+//! example 5000, 500 and 50.
+use super::fractal::Fractal;
+use super::palette::Palette;
+use super::renderer;
+use num::Complex;
+use std::str::FromStr;
+
+/// Half the side of the square centered on the origin in which the points
+/// `c` are picked: all orbits that don't start outside it escape.
+const SAMPLE_RADIUS: f64 = 2.0;
+
+/// Which orbits are counted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// The orbits of points escaping within the limit.
+    Buddhabrot,
+    /// The orbits of points not escaping within the limit.
+    AntiBuddhabrot,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "buddhabrot" => Ok(Mode::Buddhabrot),
+            "anti-buddhabrot" => Ok(Mode::AntiBuddhabrot),
+            _ => Err(format!("unknown density mode: {} (expected buddhabrot or anti-buddhabrot)", s))
+        }
+    }
+}
+
+/// Parameters of a density render.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub mode: Mode,
+    /// Iteration limit of each channel: one limit for a grayscale image,
+    /// three for an RGB one.
+    pub limits: Vec<u32>,
+    /// Number of points `c` whose orbits are followed.
+    pub orbits: usize,
+    pub escape_radius: f64,
+    pub fractal: Fractal,
+    /// Seed of the points picked, the same seed giving the same image.
+    pub seed: usize,
+}
+
+/// Count the orbits passing through each pixel of the image of size
+/// `bounds` showing the rectangle from `upper_left` to `lower_right`, with
+/// one histogram per channel.
+///
+/// The orbits are shared among `n_threads` threads, each filling its own
+/// histograms, which are added up at the end. The `i`-th point only depends
+/// on the seed and `i`, so the result doesn't depend on `n_threads`.
+pub fn histogram(
+    bounds: (usize, usize),
+    upper_left: Complex<f64>,
+    lower_right: Complex<f64>,
+    options: &Options,
+    n_threads: usize,
+) -> Vec<Vec<u32>> {
+    let n_pixels = bounds.0 * bounds.1;
+    let per_thread = options.orbits.div_ceil(n_threads.max(1)).max(1);
+
+    crossbeam::scope(|spawner| {
+        let handles: Vec<_> = (0..options.orbits).step_by(per_thread)
+            .map(|first| spawner.spawn(move |_| {
+                let mut histograms = vec![vec![0u32; n_pixels]; options.limits.len()];
+                let mut tracer = Tracer::new(options);
+                for index in first..(first + per_thread).min(options.orbits) {
+                    tracer.trace(random_point(options.seed, index), |channel, z| {
+                        if let Some((column, row)) = super::point_to_pixel(bounds, z, upper_left, lower_right) {
+                            let count = &mut histograms[channel][row * bounds.0 + column];
+                            *count = count.saturating_add(1);
+                        }
+                    });
+                }
+                histograms
+            }))
+            .collect();
+
+        let mut total = vec![vec![0u32; n_pixels]; options.limits.len()];
+        for handle in handles {
+            for (total, histogram) in total.iter_mut().zip(handle.join().unwrap()) {
+                for (total, count) in total.iter_mut().zip(histogram) {
+                    *total = total.saturating_add(count);
+                }
+            }
+        }
+        total
+    }).unwrap()
+}
+
+/// Turn the `histograms` into pixels, one channel per histogram, brighter
+/// where more orbits pass. A single histogram is mapped through `palette`
+/// if there's one, into RGB pixels.
+pub fn to_pixels(histograms: &[Vec<u32>], palette: Option<&Palette>) -> Vec<u8> {
+    // The square root brings out the faint orbits next to the dense ones.
+    let levels: Vec<Vec<f64>> = histograms.iter()
+        .map(|histogram| {
+            let max = histogram.iter().copied().max().unwrap_or(0).max(1) as f64;
+            histogram.iter().map(|&count| (count as f64 / max).sqrt()).collect()
+        })
+        .collect();
+    let n_pixels = levels.first().map_or(0, |level| level.len());
+
+    match (&levels[..], palette) {
+        ([level], Some(palette)) => level.iter().flat_map(|&t| palette.at(t)).collect(),
+        _ => (0..n_pixels)
+            .flat_map(|pixel| levels.iter().map(move |level| (level[pixel] * 255.0).round() as u8))
+            .collect()
+    }
+}
+
+/// Render the density image, grayscale or colored by `palette` for a single
+/// limit, RGB for three limits.
+pub fn render(
+    bounds: (usize, usize),
+    upper_left: Complex<f64>,
+    lower_right: Complex<f64>,
+    options: &Options,
+    palette: Option<&Palette>,
+    n_threads: usize,
+) -> Vec<u8> {
+    to_pixels(&histogram(bounds, upper_left, lower_right, options, n_threads), palette)
+}
+
+/// The `index`-th point picked for the `seed`, uniformly in the square of
+/// side `2 * SAMPLE_RADIUS` centered on the origin.
+fn random_point(seed: usize, index: usize) -> Complex<f64> {
+    let (u, v) = renderer::jitter((seed, index));
+    Complex {
+        re: (2.0 * u - 1.0) * SAMPLE_RADIUS,
+        im: (2.0 * v - 1.0) * SAMPLE_RADIUS,
+    }
+}
+
+/// Follows orbits, keeping the buffer of the current one.
+struct Tracer<'a> {
+    options: &'a Options,
+    limit: u32,
+    orbit: Vec<Complex<f64>>,
+}
+
+impl<'a> Tracer<'a> {
+    fn new(options: &'a Options) -> Tracer<'a> {
+        let limit = options.limits.iter().copied().max().unwrap_or(0);
+        Tracer { options, limit, orbit: Vec::with_capacity(limit as usize) }
+    }
+
+    /// Follow the orbit of `point` and pass each of its points to `record`
+    /// with the channels counting it.
+    fn trace(&mut self, point: Complex<f64>, mut record: impl FnMut(usize, Complex<f64>)) {
+        let Options { mode, fractal, escape_radius, .. } = *self.options;
+        if mode == Mode::Buddhabrot && fractal == Fractal::Mandelbrot && in_main_components(point) {
+            return;
+        }
+
+        let bailout = escape_radius * escape_radius;
+        let (mut z, c) = fractal.start(point);
+        let mut escape = None;
+        self.orbit.clear();
+        for i in 0..self.limit {
+            z = fractal.step(z, c);
+            if z.norm_sqr() > bailout {
+                escape = Some(i + 1);
+                break;
+            }
+            self.orbit.push(z);
+        }
+
+        for (channel, &limit) in self.options.limits.iter().enumerate() {
+            let counted = match (mode, escape) {
+                (Mode::Buddhabrot, Some(escape)) if escape <= limit => self.orbit.len(),
+                (Mode::AntiBuddhabrot, Some(escape)) if escape > limit => limit as usize,
+                (Mode::AntiBuddhabrot, None) => limit as usize,
+                _ => 0
+            };
+            for &z in &self.orbit[..counted] {
+                record(channel, z);
+            }
+        }
+    }
+}
+
+/// Whether `c` is in the main cardioid or the period-2 bulb of the
+/// Mandelbrot set, whose orbits never escape.
+fn in_main_components(c: Complex<f64>) -> bool {
+    let q = (c.re - 0.25) * (c.re - 0.25) + c.im * c.im;
+    q * (q + (c.re - 0.25)) <= 0.25 * c.im * c.im || (c.re + 1.0) * (c.re + 1.0) + c.im * c.im <= 0.0625
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    const UPPER_LEFT: Complex<f64> = Complex { re: -2.0, im: 1.5 };
+    const LOWER_RIGHT: Complex<f64> = Complex { re: 1.0, im: -1.5 };
+
+    fn options(mode: Mode, limits: Vec<u32>) -> Options {
+        Options { mode, limits, orbits: 20_000, escape_radius: 2.0, fractal: Fractal::Mandelbrot, seed: 1 }
+    }
+
+    #[rstest]
+    #[case(Mode::Buddhabrot)]
+    #[case(Mode::AntiBuddhabrot)]
+    fn test_histogram_is_independent_of_threads(#[case] mode: Mode) {
+        let options = options(mode, vec![100, 20]);
+
+        let single = histogram((30, 30), UPPER_LEFT, LOWER_RIGHT, &options, 1);
+
+        assert_eq!(single.len(), 2);
+        assert!(single[0].iter().any(|&count| count > 0));
+        assert_eq!(histogram((30, 30), UPPER_LEFT, LOWER_RIGHT, &options, 3), single);
+        assert_eq!(histogram((30, 30), UPPER_LEFT, LOWER_RIGHT, &options, 64), single);
+    }
+
+    #[test]
+    fn test_histogram_is_symmetric() {
+        // The set is symmetric about the real axis, and so are the orbits
+        // of conjugate points, but not the random points themselves: the
+        // two halves only match roughly.
+        let options = Options { orbits: 200_000, ..options(Mode::Buddhabrot, vec![50]) };
+
+        let histogram = &histogram((20, 20), UPPER_LEFT, LOWER_RIGHT, &options, 4)[0];
+
+        let (top, bottom): (u64, u64) = (histogram[..200].iter().map(|&c| c as u64).sum(),
+                                         histogram[200..].iter().map(|&c| c as u64).sum());
+        assert!((top as f64 / bottom as f64 - 1.0).abs() < 0.05, "{} vs {}", top, bottom);
+    }
+
+    #[test]
+    fn test_channel_limits() {
+        let options = options(Mode::Buddhabrot, vec![10, 1000]);
+        let mut tracer = Tracer::new(&options);
+        let mut counts = [0; 2];
+
+        // Escapes after a few dozen iterations.
+        tracer.trace(Complex { re: 0.26, im: 0.0 }, |channel, _| counts[channel] += 1);
+
+        assert_eq!(counts[0], 0);
+        assert!(counts[1] > 10);
+    }
+
+    #[test]
+    fn test_to_pixels() {
+        let histograms = vec![vec![0, 1, 4], vec![2, 2, 0], vec![0, 0, 0]];
+
+        assert_eq!(to_pixels(&histograms, None), vec![0, 255, 0, 128, 255, 0, 255, 0, 0]);
+        assert_eq!(to_pixels(&histograms[..1], None), vec![0, 128, 255]);
+        let palette = Palette::gradient(vec![(0.0, [0, 0, 0]), (1.0, [0, 0, 255])]);
+        assert_eq!(to_pixels(&histograms[..1], Some(&palette)), vec![0, 0, 0, 0, 0, 128, 0, 0, 255]);
+    }
+
+    #[rstest]
+    #[case(Complex { re: 0.0, im: 0.0 }, true)]
+    #[case(Complex { re: -1.0, im: 0.1 }, true)]
+    #[case(Complex { re: 0.3, im: 0.0 }, false)]
+    #[case(Complex { re: -0.75, im: 0.2 }, false)]
+    fn test_in_main_components(#[case] c: Complex<f64>, #[case] expected: bool) {
+        assert_eq!(in_main_components(c), expected);
+    }
+}
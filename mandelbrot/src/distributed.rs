@@ -0,0 +1,547 @@
+//! Rendering across several processes or machines.
+//!
+//! Workers (`mandelbrot worker`) listen for a coordinator, which connects to
+//! all of them, hands out tiles of the image one at a time and stitches the
+//! samples sent back into the final image. Messages are JSON objects, one per
+//! line: the coordinator sends a `Job`, the worker answers with a `Reply`.
+//!
+//! Tiles are cut in sample space, before supersampling is averaged out, and
+//! workers compute the same samples as a local render, so the stitched image
+//! is the one `renderer::render_image` would give. The tiles of a worker that
+//! fails, or doesn't answer within `REPLY_TIMEOUT`, are handed to the others.
+//! There's no authentication: workers should only listen on trusted networks,
+//! and they bound the length of job lines and the number of coordinators
+//! served at once, and check jobs before rendering them, so that a faulty
+//! coordinator can't make them run out of memory.
+use super::error;
+use super::palette::{self, Colorizer};
+use super::renderer::{self, Options};
+use super::scheduler::{self, Control, Tile};
+use super::Error;
+use crossbeam::channel::{self, Sender};
+use log::{debug, info, warn};
+use num::Complex;
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Width and height in samples of the tiles handed to workers, large enough
+/// to make up for the round trip.
+pub const TILE_SIZE: usize = 256;
+
+/// Largest iteration limit workers render with, which bounds the time a
+/// tile takes.
+pub const MAX_LIMIT: u32 = 1_000_000;
+
+/// How long the coordinator waits to connect to a worker, to send it a job
+/// or to get the reply before giving the tile to another worker. A tile of
+/// `MAX_LIMIT` iterations per sample takes well below that.
+pub const REPLY_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How long a worker waits for the next job of a coordinator, or to send a
+/// reply, before hanging up.
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Longest job line workers read, well beyond the size of any valid job.
+pub const MAX_JOB_BYTES: u64 = 64 * 1024;
+
+/// Number of coordinators a worker answers at once; further ones wait to be
+/// accepted.
+pub const MAX_COORDINATORS: usize = 4;
+
+/// A tile to render, with everything a worker needs to know about the image.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Job {
+    /// Size of the whole image in samples.
+    pub sample_bounds: (usize, usize),
+    pub upper_left: Complex<f64>,
+    pub lower_right: Complex<f64>,
+    pub options: Options,
+    /// Whether to send escape values for a palette rather than iteration
+    /// counts.
+    pub escape: bool,
+    pub tile: Tile,
+}
+
+impl Job {
+    /// Check that the image isn't empty and fits in memory, that the tile
+    /// lies within it and that the limit doesn't exceed `MAX_LIMIT`.
+    pub fn validate(&self) -> Result<(), Error> {
+        let tile = self.tile;
+        error::validate_bounds(self.sample_bounds, 1)?;
+        error::validate_bounds((tile.width, tile.height), 1)?;
+        let inside = |start: usize, size: usize, end: usize| {
+            start.checked_add(size).is_some_and(|stop| stop <= end)
+        };
+        if !inside(tile.left, tile.width, self.sample_bounds.0)
+            || !inside(tile.top, tile.height, self.sample_bounds.1) {
+            return Err(Error::Validation(format!("tile {:?} lies outside the image of {}x{} samples",
+                                                 tile, self.sample_bounds.0, self.sample_bounds.1)));
+        }
+        if self.options.limit > MAX_LIMIT {
+            return Err(Error::Validation(format!("limit {} exceeds the limit of workers, {}",
+                                                 self.options.limit, MAX_LIMIT)));
+        }
+        Ok(())
+    }
+}
+
+/// A worker's answer to a `Job`: the samples of the tile, row by row.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reply {
+    Counts(Vec<u32>),
+    Escape(Vec<Option<f64>>),
+    Error(String),
+}
+
+/// Render the samples of `job` using `n_threads` threads, or answer with an
+/// error if the job isn't valid.
+pub fn render_job(job: &Job, n_threads: usize) -> Reply {
+    if let Err(err) = job.validate() {
+        return Reply::Error(format!("bad job: {}", err));
+    }
+    let tile = job.tile;
+    let bounds = (tile.width, tile.height);
+    let point = |(column, row)| renderer::sample_point(job.sample_bounds,
+                                                       (tile.left + column, tile.top + row),
+                                                       job.upper_left,
+                                                       job.lower_right,
+                                                       &job.options);
+    let mut control = Control::default();
+
+    if job.escape {
+        let mut values = vec![None; tile.len()];
+        renderer::parallel_escape(&mut values, bounds, &job.options, n_threads, &mut control, point)
+            .expect("render without cancel token cancelled");
+        Reply::Escape(values)
+    } else {
+        let mut counts = vec![0; tile.len()];
+        renderer::parallel_counts(&mut counts, bounds, &job.options, n_threads, &mut control, point)
+            .expect("render without cancel token cancelled");
+        Reply::Counts(counts)
+    }
+}
+
+/// Answer the jobs of the coordinators connecting to `listener`, on
+/// `MAX_COORDINATORS` threads, until accepting fails.
+pub fn serve_worker(listener: TcpListener, n_threads: usize) -> Result<(), Error> {
+    info!("worker listening on {}", listener.local_addr()?);
+    // Accepted connections wait in a channel of one slot per thread, beyond
+    // which they queue in the listener's backlog.
+    let (sender, receiver) = channel::bounded::<TcpStream>(MAX_COORDINATORS);
+    for _ in 0..MAX_COORDINATORS {
+        let receiver = receiver.clone();
+        thread::spawn(move || {
+            for stream in receiver {
+                if let Err(err) = handle_jobs(stream, n_threads) {
+                    warn!("error answering coordinator: {}", err);
+                }
+            }
+        });
+    }
+    for stream in listener.incoming() {
+        sender.send(stream?).expect("worker threads exited");
+    }
+    Ok(())
+}
+
+/// Answer jobs read from `stream` until the coordinator hangs up or stays
+/// idle for `IDLE_TIMEOUT`.
+fn handle_jobs(stream: TcpStream, n_threads: usize) -> io::Result<()> {
+    let peer = stream.peer_addr()?;
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    stream.set_write_timeout(Some(IDLE_TIMEOUT))?;
+    let mut writer = io::BufWriter::new(stream.try_clone()?);
+    debug!("coordinator {} connected", peer);
+
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let read = (&mut reader).take(MAX_JOB_BYTES).read_line(&mut line)?;
+        if read == 0 {
+            break;
+        }
+        // The rest of a line too long to read can't be told from the next
+        // job, so the connection ends after the error.
+        let too_long = read as u64 == MAX_JOB_BYTES && !line.ends_with('\n');
+        let reply = if too_long {
+            Reply::Error(format!("bad job: longer than {} bytes", MAX_JOB_BYTES))
+        } else {
+            match serde_json::from_str::<Job>(&line) {
+                Ok(job) => {
+                    debug!("rendering tile {:?} for {}", job.tile, peer);
+                    render_job(&job, n_threads)
+                }
+                Err(err) => Reply::Error(format!("bad job: {}", err)),
+            }
+        };
+        serde_json::to_writer(&mut writer, &reply)?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+        if too_long {
+            break;
+        }
+    }
+    debug!("coordinator {} disconnected", peer);
+    Ok(())
+}
+
+/// The samples of the whole image, filled in as replies come.
+enum Samples {
+    Counts(Vec<u32>),
+    Escape(Vec<Option<f64>>),
+}
+
+/// Render the image of size `bounds` showing the rectangle from
+/// `upper_left` to `lower_right` on the `workers`, given as `host:port`
+/// addresses, returning the same pixels as `renderer::render_image`.
+///
+/// Fails if the workers all fail before the image is complete.
+pub fn render_image(
+    workers: &[String],
+    bounds: (usize, usize),
+    upper_left: Complex<f64>,
+    lower_right: Complex<f64>,
+    options: &Options,
+    colorizer: Option<&Colorizer>,
+) -> Result<Vec<u8>, Error> {
+    render_image_with_timeout(workers, bounds, upper_left, lower_right, options, colorizer, REPLY_TIMEOUT)
+}
+
+/// The same as `render_image`, giving up on a worker after `timeout`.
+#[allow(clippy::too_many_arguments)]
+fn render_image_with_timeout(
+    workers: &[String],
+    bounds: (usize, usize),
+    upper_left: Complex<f64>,
+    lower_right: Complex<f64>,
+    options: &Options,
+    colorizer: Option<&Colorizer>,
+    timeout: Duration,
+) -> Result<Vec<u8>, Error> {
+    let samples = options.samples.max(1);
+    let sample_bounds = (bounds.0 * samples, bounds.1 * samples);
+    let job = Job {
+        sample_bounds,
+        upper_left,
+        lower_right,
+        options: *options,
+        escape: colorizer.is_some(),
+        tile: Tile { left: 0, top: 0, width: sample_bounds.0.min(1), height: sample_bounds.1.min(1) },
+    };
+    // Fail early rather than have every worker reject the jobs.
+    job.validate()?;
+
+    // Workers pop tiles from the end: reverse them to go row by row.
+    let mut tiles = scheduler::tiles(sample_bounds, TILE_SIZE);
+    tiles.reverse();
+    let n_tiles = tiles.len();
+    let queue = Queue { state: Mutex::new((tiles, 0)), changed: Condvar::new() };
+
+    let mut buffer = if job.escape {
+        Samples::Escape(vec![None; sample_bounds.0 * sample_bounds.1])
+    } else {
+        Samples::Counts(vec![0; sample_bounds.0 * sample_bounds.1])
+    };
+    let mut n_done = 0;
+
+    crossbeam::scope(|spawner| {
+        let (sender, receiver) = channel::unbounded();
+        for address in workers {
+            let (sender, job, queue) = (sender.clone(), &job, &queue);
+            spawner.spawn(move |_| {
+                if let Err(err) = run_worker(address, job, queue, &sender, timeout) {
+                    warn!("worker {} failed: {}", address, err);
+                }
+            });
+        }
+        drop(sender);
+
+        for (tile, reply) in receiver {
+            match (&mut buffer, reply) {
+                (Samples::Counts(counts), Reply::Counts(tile_counts)) => {
+                    scheduler::copy_tile(counts, sample_bounds.0, &tile, tile_counts)
+                }
+                (Samples::Escape(values), Reply::Escape(tile_values)) => {
+                    scheduler::copy_tile(values, sample_bounds.0, &tile, tile_values)
+                }
+                _ => unreachable!("replies are checked by run_worker"),
+            }
+            n_done += 1;
+            debug!("{}/{} tiles done", n_done, n_tiles);
+        }
+    }).unwrap();
+
+    if n_done < n_tiles {
+        return Err(Error::Render(format!(
+            "all workers failed with {} of {} tiles left", n_tiles - n_done, n_tiles
+        )));
+    }
+
+    let pixels = match (buffer, colorizer) {
+        (Samples::Escape(values), Some(colorizer)) => colorizer.colorize(&values, options.limit),
+        (Samples::Counts(counts), _) => palette::grayscale(&counts, options.limit),
+        _ => unreachable!("the buffer matches the colorizer"),
+    };
+    Ok(renderer::downsample(&pixels, bounds, samples))
+}
+
+/// The tiles left to render, shared by the threads talking to workers.
+struct Queue {
+    /// The tiles nobody renders yet and the number of tiles being rendered.
+    state: Mutex<(Vec<Tile>, usize)>,
+    changed: Condvar,
+}
+
+impl Queue {
+    /// Take a tile to render, waiting while the queue is empty but tiles
+    /// being rendered may still come back to it. Returns `None` once all
+    /// tiles are rendered.
+    fn take(&self) -> Option<Tile> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let (tiles, n_rendering) = &mut *state;
+            if let Some(tile) = tiles.pop() {
+                *n_rendering += 1;
+                return Some(tile);
+            }
+            if *n_rendering == 0 {
+                return None;
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    /// Record the end of the render of `tile`, putting it back in the queue
+    /// unless it was `rendered`.
+    fn finish(&self, tile: Tile, rendered: bool) {
+        let mut state = self.state.lock().unwrap();
+        let (tiles, n_rendering) = &mut *state;
+        *n_rendering -= 1;
+        if !rendered {
+            tiles.push(tile);
+        }
+        self.changed.notify_all();
+    }
+}
+
+/// Connect to the worker at `address` and have it render tiles from
+/// `queue` until all tiles are rendered, sending the replies to `results`.
+///
+/// A tile being rendered when the worker fails, or doesn't answer within
+/// `timeout`, goes back to the queue.
+fn run_worker(
+    address: &str,
+    job: &Job,
+    queue: &Queue,
+    results: &Sender<(Tile, Reply)>,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let stream = connect(address, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let mut writer = io::BufWriter::new(stream.try_clone()?);
+    let mut reader = BufReader::new(stream);
+    info!("connected to worker {}", address);
+
+    while let Some(tile) = queue.take() {
+        let reply = request(&mut reader, &mut writer, &Job { tile, ..job.clone() });
+        let reply = match reply {
+            Ok(Reply::Counts(counts)) if !job.escape && counts.len() == tile.len() => Reply::Counts(counts),
+            Ok(Reply::Escape(values)) if job.escape && values.len() == tile.len() => Reply::Escape(values),
+            reply => {
+                queue.finish(tile, false);
+                return Err(match reply {
+                    Ok(Reply::Error(message)) => Error::Render(message),
+                    Ok(_) => Error::Render(format!("unexpected reply for tile {:?}", tile)),
+                    Err(err) => err,
+                });
+            }
+        };
+        results.send((tile, reply)).unwrap();
+        queue.finish(tile, true);
+    }
+    Ok(())
+}
+
+/// Connect to `address`, trying each of its socket addresses for at most
+/// `timeout`.
+fn connect(address: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "address resolves to nothing");
+    for address in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_error = err,
+        }
+    }
+    Err(last_error)
+}
+
+/// Send `job` and read the reply.
+fn request(reader: &mut impl BufRead, writer: &mut impl Write, job: &Job) -> Result<Reply, Error> {
+    serde_json::to_writer(&mut *writer, job).map_err(|err| Error::Io(err.into()))?;
+    writer.write_all(b"\n")?;
+    writer.flush()?;
+
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(Error::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "worker hung up")));
+    }
+    serde_json::from_str(&line).map_err(|err| Error::Parse(format!("bad reply: {}", err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fractal::Fractal;
+    use crate::palette::{Coloring, Palette};
+    use std::net::SocketAddr;
+
+    const UPPER_LEFT: Complex<f64> = Complex { re: -2.0, im: 1.2 };
+    const LOWER_RIGHT: Complex<f64> = Complex { re: 1.0, im: -1.2 };
+
+    /// Start a worker on a free local port.
+    fn start_worker() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || serve_worker(listener, 2));
+        address
+    }
+
+    /// An address nobody listens on.
+    fn dead_address() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address: SocketAddr = listener.local_addr().unwrap();
+        address.to_string()
+    }
+
+    #[test]
+    fn test_job_round_trip() {
+        let job = Job {
+            sample_bounds: (100, 80),
+            upper_left: UPPER_LEFT,
+            lower_right: LOWER_RIGHT,
+            options: Options { fractal: Fractal::Julia(Complex { re: -0.8, im: 0.156 }), ..Options::default() },
+            escape: true,
+            tile: Tile { left: 10, top: 20, width: 5, height: 6 },
+        };
+
+        let line = serde_json::to_string(&job).unwrap();
+
+        assert!(!line.contains('\n'));
+        assert_eq!(serde_json::from_str::<Job>(&line).unwrap(), job);
+        assert_eq!(serde_json::to_string(&Reply::Escape(vec![Some(1.5), None])).unwrap(),
+                   r#"{"escape":[1.5,null]}"#);
+    }
+
+    #[test]
+    fn test_matches_local_render() {
+        let workers: Vec<String> = (0..3).map(|_| start_worker()).collect();
+        let options = Options { samples: 2, jitter: true, ..Options::default() };
+        let colorizer = Colorizer::new(Palette::by_name("fire").unwrap(), Coloring::Smooth);
+        let bounds = (300, 250);
+
+        for colorizer in &[None, Some(&colorizer)] {
+            let distributed = render_image(&workers, bounds, UPPER_LEFT, LOWER_RIGHT, &options, *colorizer).unwrap();
+            let local = renderer::render_image(bounds, UPPER_LEFT, LOWER_RIGHT, &options, *colorizer, 2);
+            assert!(distributed == local);
+        }
+    }
+
+    #[test]
+    fn test_failing_workers() {
+        let options = Options::default();
+        let bounds = (300, 300);
+
+        let workers = vec![dead_address(), start_worker()];
+        let pixels = render_image(&workers, bounds, UPPER_LEFT, LOWER_RIGHT, &options, None).unwrap();
+        assert!(pixels == renderer::render_image(bounds, UPPER_LEFT, LOWER_RIGHT, &options, None, 1));
+
+        let error = render_image(&[dead_address()], bounds, UPPER_LEFT, LOWER_RIGHT, &options, None).unwrap_err();
+        assert_eq!(error.to_string(), "all workers failed with 4 of 4 tiles left");
+    }
+
+    #[test]
+    fn test_bad_job() {
+        let stream = TcpStream::connect(start_worker()).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+
+        writer.write_all(b"{\"tile\": 1}\n").unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+
+        assert!(matches!(serde_json::from_str(&line).unwrap(), Reply::Error(message) if message.starts_with("bad job")));
+    }
+
+    #[test]
+    fn test_long_job() {
+        let stream = TcpStream::connect(start_worker()).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+
+        // The worker answers once it has read `MAX_JOB_BYTES`, and hangs up
+        // rather than reading the rest.
+        let _ = writer.write_all(&vec![b' '; 2 * MAX_JOB_BYTES as usize]);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+
+        assert!(matches!(serde_json::from_str(&line).unwrap(),
+                         Reply::Error(message) if message.ends_with(&format!("longer than {} bytes", MAX_JOB_BYTES))));
+        line.clear();
+        assert!(!matches!(reader.read_line(&mut line), Ok(read) if read > 0));
+    }
+
+    #[test]
+    fn test_invalid_jobs() {
+        let job = Job {
+            sample_bounds: (100, 80),
+            upper_left: UPPER_LEFT,
+            lower_right: LOWER_RIGHT,
+            options: Options::default(),
+            escape: false,
+            tile: Tile { left: 90, top: 70, width: 10, height: 10 },
+        };
+        assert!(job.validate().is_ok());
+
+        let invalid = [
+            Job { tile: Tile { left: 91, ..job.tile }, ..job.clone() },
+            Job { tile: Tile { left: usize::MAX, ..job.tile }, ..job.clone() },
+            Job { sample_bounds: (1 << 20, 1 << 20), tile: Tile { width: 1 << 20, ..job.tile }, ..job.clone() },
+            Job { options: Options { limit: MAX_LIMIT + 1, ..job.options }, ..job.clone() },
+        ];
+        for job in &invalid {
+            assert!(matches!(job.validate(), Err(Error::Validation(_))), "{:?}", job);
+            assert!(matches!(render_job(job, 1), Reply::Error(message) if message.starts_with("bad job")));
+        }
+
+        let options = Options { limit: MAX_LIMIT + 1, ..Options::default() };
+        let error = render_image(&[start_worker()], (10, 10), UPPER_LEFT, LOWER_RIGHT, &options, None).unwrap_err();
+        assert!(matches!(error, Error::Validation(_)));
+    }
+
+    #[test]
+    fn test_stalled_worker() {
+        // A worker that accepts connections and reads jobs but never answers.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stalled = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                thread::spawn(move || io::copy(&mut stream.unwrap(), &mut io::sink()));
+            }
+        });
+        let options = Options::default();
+        let bounds = (300, 300);
+
+        let workers = vec![stalled, start_worker()];
+        let pixels = render_image_with_timeout(&workers, bounds, UPPER_LEFT, LOWER_RIGHT, &options, None,
+                                               Duration::from_millis(200)).unwrap();
+
+        assert!(pixels == renderer::render_image(bounds, UPPER_LEFT, LOWER_RIGHT, &options, None, 1));
+    }
+}
@@ -0,0 +1,157 @@
+//! The error type of the crate.
+use super::scheduler::Cancelled;
+use std::fmt;
+use std::io;
+
+/// Largest number of samples of a render, beyond which the buffers would
+/// take gigabytes: an image of 16384 by 16384 pixels with one sample each.
+pub const MAX_SAMPLES: usize = 1 << 28;
+
+/// What went wrong while parsing, rendering or writing an image.
+#[derive(Debug)]
+pub enum Error {
+    /// Malformed text, like an image size or a complex number.
+    Parse(String),
+    /// Well-formed parameters that can't be rendered, like an empty image or
+    /// a view whose corners are swapped.
+    Validation(String),
+    /// A render that didn't complete.
+    Render(String),
+    /// A failure of an image encoder.
+    Encode(String),
+    /// A failure reading or writing a file or a stream.
+    Io(io::Error),
+}
+
+impl Error {
+    /// Return the same error with `context`, like a file name, in front of
+    /// its message.
+    pub fn context(self, context: impl fmt::Display) -> Error {
+        match self {
+            Error::Parse(message) => Error::Parse(format!("{}: {}", context, message)),
+            Error::Validation(message) => Error::Validation(format!("{}: {}", context, message)),
+            Error::Render(message) => Error::Render(format!("{}: {}", context, message)),
+            Error::Encode(message) => Error::Encode(format!("{}: {}", context, message)),
+            Error::Io(err) => Error::Io(io::Error::new(err.kind(), format!("{}: {}", context, err))),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Parse(message)
+            | Error::Validation(message)
+            | Error::Render(message)
+            | Error::Encode(message) => write!(f, "{}", message),
+            Error::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<image::ImageError> for Error {
+    fn from(err: image::ImageError) -> Error {
+        match err {
+            image::ImageError::IoError(err) => Error::Io(err),
+            err => Error::Encode(err.to_string()),
+        }
+    }
+}
+
+impl From<Cancelled> for Error {
+    fn from(err: Cancelled) -> Error {
+        Error::Render(err.to_string())
+    }
+}
+
+/// Check that an image of size `bounds` with `samples` by `samples` samples
+/// per pixel isn't empty and fits in memory.
+pub fn validate_bounds(bounds: (usize, usize), samples: usize) -> Result<(), Error> {
+    if bounds.0 == 0 || bounds.1 == 0 {
+        return Err(Error::Validation(format!("image size {}x{} is empty", bounds.0, bounds.1)));
+    }
+    let n_samples = bounds.0
+        .checked_mul(bounds.1)
+        .and_then(|n| n.checked_mul(samples.max(1)))
+        .and_then(|n| n.checked_mul(samples.max(1)));
+    match n_samples {
+        Some(n) if n <= MAX_SAMPLES => Ok(()),
+        _ => Err(Error::Validation(format!(
+            "image size {}x{} with {} samples per pixel is too large, the limit is {} samples",
+            bounds.0, bounds.1, samples.max(1) * samples.max(1), MAX_SAMPLES
+        )))
+    }
+}
+
+/// Check that an image of size `bounds`, rendered in square tiles of `tile`
+/// pixels and written out a band of tiles at a time, isn't empty and that a
+/// tile and a band of tiles fit in memory, however large the whole image.
+pub fn validate_tiled_bounds(bounds: (usize, usize), samples: usize, tile: usize) -> Result<(), Error> {
+    validate_bounds((bounds.0.min(tile), bounds.1.min(tile)), samples)?;
+    validate_bounds((bounds.0, bounds.1.min(tile)), 1)
+}
+
+/// Check that a view of size `width` by `height`, as the difference between
+/// its lower-right and upper-left corners, isn't empty or inverted.
+pub fn validate_size(width: f64, height: f64) -> Result<(), Error> {
+    if width > 0.0 && height > 0.0 && width.is_finite() && height.is_finite() {
+        Ok(())
+    } else {
+        Err(Error::Validation(format!(
+            "view of size {}x{} is empty or its corners are swapped: the upper-left corner \
+             must be to the left of and above the lower-right one",
+            width, height
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case((0, 10), 1)]
+    #[case((10, 0), 1)]
+    #[case((1 << 15, 1 << 14), 1)]
+    #[case((1 << 12, 1 << 12), 5)]
+    #[case((usize::MAX, 2), 1)]
+    fn test_validate_bounds_rejects(#[case] bounds: (usize, usize), #[case] samples: usize) {
+        assert!(matches!(validate_bounds(bounds, samples), Err(Error::Validation(_))));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(validate_bounds((1 << 14, 1 << 14), 1).is_ok());
+        assert!(validate_bounds((800, 600), 4).is_ok());
+        assert!(validate_size(3.0, 2.0).is_ok());
+        assert!(validate_size(-3.0, 2.0).is_err());
+        assert!(validate_size(3.0, 0.0).is_err());
+        assert!(validate_size(f64::NAN, 1.0).is_err());
+    }
+
+    #[test]
+    fn test_context() {
+        let err = Error::Parse("wrong image size format: 1x".to_string()).context("size");
+
+        assert_eq!(err.to_string(), "size: wrong image size format: 1x");
+        let err = Error::from(io::Error::new(io::ErrorKind::NotFound, "no such file")).context("a.toml");
+        assert!(matches!(&err, Error::Io(err) if err.kind() == io::ErrorKind::NotFound));
+        assert_eq!(err.to_string(), "a.toml: no such file");
+    }
+}
@@ -0,0 +1,188 @@
+//! Escape-time fractals the renderer knows how to draw.
+use num::Complex;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// An escape-time fractal, defined by the starting point of an orbit and by
+/// the function iterated on it.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum Fractal {
+    /// `z = z^2 + c`, starting at zero with `c` taken from the pixel.
+    #[default]
+    Mandelbrot,
+    /// `z = z^2 + c` for a fixed `c`, starting at the pixel.
+    Julia(Complex<f64>),
+    /// `z = (|re z| + i|im z|)^2 + c`, starting at zero.
+    BurningShip,
+    /// `z = conj(z)^2 + c`, starting at zero.
+    Tricorn,
+    /// `z = z^d + c` for an arbitrary real exponent `d > 1`, starting at zero.
+    Multibrot(f64),
+}
+
+impl Fractal {
+    /// Degree of the iterated polynomial, which defines how fast escaping
+    /// orbits grow.
+    pub fn degree(&self) -> f64 {
+        match self {
+            Fractal::Multibrot(d) => *d,
+            _ => 2.0
+        }
+    }
+
+    /// Whether the iterated function is holomorphic, so that the derivative
+    /// of the orbit (used for distance estimation) exists.
+    pub fn is_analytic(&self) -> bool {
+        !matches!(self, Fractal::BurningShip | Fractal::Tricorn)
+    }
+
+    /// Return the initial value of the orbit and the constant added on
+    /// every iteration for the `point` of the complex plane.
+    pub(crate) fn start(&self, point: Complex<f64>) -> (Complex<f64>, Complex<f64>) {
+        match self {
+            Fractal::Julia(c) => (point, *c),
+            _ => (Complex { re: 0.0, im: 0.0 }, point)
+        }
+    }
+
+    /// Compute the next value of the orbit.
+    pub(crate) fn step(&self, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        match self {
+            Fractal::Mandelbrot | Fractal::Julia(_) => z * z + c,
+            Fractal::BurningShip => {
+                let z = Complex { re: z.re.abs(), im: z.im.abs() };
+                z * z + c
+            }
+            Fractal::Tricorn => {
+                let z = z.conj();
+                z * z + c
+            }
+            Fractal::Multibrot(d) if d.fract() == 0.0 => z.powi(*d as i32) + c,
+            Fractal::Multibrot(d) => z.powf(*d) + c
+        }
+    }
+
+    /// Return the initial derivative of the orbit with respect to the
+    /// `point` of the complex plane.
+    pub(crate) fn start_derivative(&self) -> Complex<f64> {
+        match self {
+            Fractal::Julia(_) => Complex { re: 1.0, im: 0.0 },
+            _ => Complex { re: 0.0, im: 0.0 }
+        }
+    }
+
+    /// Compute the next derivative of the orbit given the current value `z`
+    /// and derivative `dz`, or `None` for fractals that aren't analytic.
+    pub(crate) fn step_derivative(&self, z: Complex<f64>, dz: Complex<f64>) -> Option<Complex<f64>> {
+        let one = Complex { re: 1.0, im: 0.0 };
+        match self {
+            Fractal::Mandelbrot => Some(z * dz * 2.0 + one),
+            Fractal::Julia(_) => Some(z * dz * 2.0),
+            // `powf` of zero isn't finite, but the term vanishes anyway.
+            Fractal::Multibrot(_) if z.norm_sqr() == 0.0 => Some(one),
+            Fractal::Multibrot(d) => Some(z.powf(d - 1.0) * dz * *d + one),
+            Fractal::BurningShip | Fractal::Tricorn => None
+        }
+    }
+
+    /// Normalized iteration count of an orbit escaping at iteration `i`
+    /// with `|z|^2 = norm_sqr`, see `smooth_escape_time`.
+    pub(crate) fn smooth_count(&self, i: u32, norm_sqr: f64, escape_radius: f64) -> f64 {
+        let log_z = norm_sqr.ln() / 2.0;
+        let nu = (log_z / escape_radius.ln()).ln() / self.degree().ln();
+        (i as f64 + 1.0 - nu).max(0.0)
+    }
+
+    /// Return the number of iterations it took the orbit of `point` to leave
+    /// the circle of radius `escape_radius`, or `None` if it didn't leave it
+    /// within `limit` iterations.
+    pub fn escape_time(&self, point: Complex<f64>, limit: u32, escape_radius: f64) -> Option<u32> {
+        if let Fractal::Mandelbrot = self {
+            return super::escape_time(point, limit, escape_radius);
+        }
+
+        let bailout = escape_radius * escape_radius;
+        let (mut z, c) = self.start(point);
+        for i in 0..limit {
+            if z.norm_sqr() > bailout {
+                return Some(i);
+            }
+            z = self.step(z, c);
+        }
+        None
+    }
+
+    /// The same as `escape_time` but return the normalized iteration count, a
+    /// continuous value that doesn't jump by one between neighbouring points.
+    ///
+    /// The count is computed as `i + 1 - log_d(ln |z| / ln r)`, where `d` is
+    /// the degree of the fractal, `r` is the escape radius and `z` is the first
+    /// value of the orbit outside of the escape circle.
+    pub fn smooth_escape_time(&self, point: Complex<f64>, limit: u32, escape_radius: f64) -> Option<f64> {
+        let bailout = escape_radius * escape_radius;
+        let (mut z, c) = self.start(point);
+        for i in 0..limit {
+            let norm_sqr = z.norm_sqr();
+            if norm_sqr > bailout {
+                return Some(self.smooth_count(i, norm_sqr, escape_radius));
+            }
+            z = self.step(z, c);
+        }
+        None
+    }
+}
+
+impl fmt::Display for Fractal {
+    /// Format the fractal the way `parser::fractal_from_str` reads it.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fractal::Mandelbrot => write!(f, "mandelbrot"),
+            Fractal::Julia(c) => write!(f, "julia:{},{}", c.re, c.im),
+            Fractal::BurningShip => write!(f, "burning-ship"),
+            Fractal::Tricorn => write!(f, "tricorn"),
+            Fractal::Multibrot(d) => write!(f, "multibrot:{}", d)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(Fractal::Mandelbrot, 0.0, 0.0, None)]
+    #[case(Fractal::Mandelbrot, 0.9, 0.8, Some(2))]
+    #[case(Fractal::Julia(Complex { re: 0.0, im: 0.0 }), 0.5, 0.5, None)]
+    #[case(Fractal::Julia(Complex { re: 0.0, im: 0.0 }), 1.5, 0.0, Some(1))]
+    #[case(Fractal::BurningShip, -0.5, -0.2, None)]
+    #[case(Fractal::BurningShip, 0.3, 0.6, Some(5))]
+    #[case(Fractal::Tricorn, 0.2, 0.0, None)]
+    #[case(Fractal::Tricorn, -0.2, 0.2, Some(20))]
+    #[case(Fractal::Multibrot(3.0), 0.3, 0.0, None)]
+    #[case(Fractal::Multibrot(3.0), 0.5, 0.0, Some(6))]
+    #[case(Fractal::Multibrot(2.0), 0.9, 0.8, Some(2))]
+    fn test_escape_time(
+        #[case] fractal: Fractal,
+        #[case] re: f64,
+        #[case] im: f64,
+        #[case] result: Option<u32>
+    ) {
+        assert_eq!(fractal.escape_time(Complex { re, im }, 100, 2.0), result);
+    }
+
+    #[test]
+    fn test_smooth_escape_time() {
+        let origin = Complex { re: 0.0, im: 0.0 };
+        assert_eq!(Fractal::Mandelbrot.smooth_escape_time(origin, 100, 2.0), None);
+
+        for fractal in &[Fractal::Mandelbrot, Fractal::Tricorn, Fractal::Multibrot(3.5)] {
+            for &(re, im) in &[(-1.0, 2.5), (0.9, 0.8), (-0.2, 0.9), (-0.75, 0.2)] {
+                let c = Complex { re, im };
+                let count = fractal.escape_time(c, 1000, 2.0).unwrap() as f64;
+                let smooth = fractal.smooth_escape_time(c, 1000, 2.0).unwrap();
+                assert!((smooth - count).abs() <= 1.0, "{}: {} vs {}", fractal, smooth, count);
+            }
+        }
+    }
+}
@@ -3,42 +3,228 @@ use log::debug;
 use num::Complex;
 use std::str::FromStr;
 
+pub mod animation;
+pub mod cache;
+pub mod deep;
+pub mod density;
+pub mod distributed;
+pub mod error;
+pub mod fractal;
+pub mod output;
+pub mod palette;
+pub mod preview;
+pub mod progressive;
+pub mod scene;
+pub mod scheduler;
+pub mod server;
+pub mod shading;
+pub mod simd;
+pub mod view;
+
+pub use error::Error;
+
 pub mod parser {
     use super::*;
-    use std::io::{Error, ErrorKind};
+    use super::deep::BigComplex;
+    use super::fractal::Fractal;
+    use super::Error;
+
+    const ESCAPE_RADIUS_RANGE: &str = "escape radius should be a number from 2 to 1.34e154";
 
     /// Parse complex number from string or return error if format is wrong.
     pub fn complex_from_str(s: &str) -> Result<Complex<f64>, Error> {
         parse_complex(s.trim_matches(|c| c == '"')).ok_or_else(
-            || Error::new(
-                ErrorKind::InvalidData,
-                format!("wrong input format: {}", s)
-            )
+            || Error::Parse(format!("wrong input format: {}", s))
         )
     }
 
+    /// Parse complex number with arbitrary-precision components from string
+    /// or return error if format is wrong.
+    pub fn big_complex_from_str(s: &str) -> Result<BigComplex, Error> {
+        let error = |reason: String| Error::Parse(format!("wrong input format: {}: {}", s, reason));
+        match s.trim_matches(|c| c == '"').split_once(',') {
+            Some((re, im)) => Ok(BigComplex { re: re.parse().map_err(error)?, im: im.parse().map_err(error)? }),
+            None => Err(Error::Parse(format!("wrong input format: {}", s))),
+        }
+    }
+
     /// Parse output image bounds from string or return error if format is wrong.
     pub fn bounds_from_str(s: &str) -> Result<(usize, usize), Error> {
         parse_pair(s, 'x').ok_or_else(
-            || Error::new(
-                ErrorKind::InvalidData,
-                format!("wrong image size format: {}", s)
-            )
+            || Error::Parse(format!("wrong image size format: {}", s))
         )
     }
 
+    /// Parse escape radius from string or return error if it isn't a number
+    /// `validate_escape_radius` accepts.
+    pub fn escape_radius_from_str(s: &str) -> Result<f64, Error> {
+        f64::from_str(s)
+            .map_err(|_| ESCAPE_RADIUS_RANGE.to_string())
+            .and_then(validate_escape_radius)
+            .map_err(|err| Error::Parse(format!("{}: {}", err, s)))
+    }
+
+    /// Return `radius` or an error if it's below 2, the smallest radius that
+    /// no orbit of the set leaves, or its square isn't finite, since
+    /// renderers compare squared norms with it.
+    pub fn validate_escape_radius(radius: f64) -> Result<f64, String> {
+        if radius >= 2.0 && (radius * radius).is_finite() {
+            Ok(radius)
+        } else {
+            Err(ESCAPE_RADIUS_RANGE.to_string())
+        }
+    }
+
+    /// Parse fractal from string or return error if format is wrong.
+    ///
+    /// The accepted formats are `mandelbrot`, `burning-ship`, `tricorn`,
+    /// `julia:<re>,<im>` and `multibrot:<exponent>` with a finite exponent
+    /// above 1.
+    pub fn fractal_from_str(s: &str) -> Result<Fractal, Error> {
+        let s = s.trim_matches(|c| c == '"');
+        let (name, parameter) = match s.find(':') {
+            None => (s, None),
+            Some(index) => (&s[..index], Some(&s[index + 1..]))
+        };
+        let fractal = match (name, parameter) {
+            ("mandelbrot", None) => Some(Fractal::Mandelbrot),
+            ("burning-ship", None) => Some(Fractal::BurningShip),
+            ("tricorn", None) => Some(Fractal::Tricorn),
+            ("julia", Some(c)) => parse_complex(c).map(Fractal::Julia),
+            ("multibrot", Some(d)) => match f64::from_str(d) {
+                Ok(d) if d > 1.0 && d.is_finite() => Some(Fractal::Multibrot(d)),
+                _ => None
+            },
+            _ => None
+        };
+        fractal.ok_or_else(
+            || Error::Parse(format!("wrong fractal format: {}", s))
+        )
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use crate::parser;
+        use crate::renderer::tests::Random;
+
+        /// Valid inputs of every parser, which the fuzz test mutates.
+        const FUZZ_SEEDS: &[&str] = &[
+            "800x600",
+            "-1.25,0.5",
+            "\"0.25,-1e-3\"",
+            "-0.743643887037158704752191506114774,0.131825904205311970493132056385139",
+            "1.5e-300,-2E3",
+            "mandelbrot",
+            "burning-ship",
+            "julia:-0.8,0.156",
+            "multibrot:3",
+            "1e3",
+        ];
+
+        /// Characters the fuzz test inserts, chosen to reach the branches of the
+        /// parsers, plus a few multi-byte ones.
+        const FUZZ_ALPHABET: &[char] = &[
+            '0', '1', '9', '.', ',', 'x', 'e', 'E', '-', '+', ':', '"', ' ', 'a', 'i', 'n', 'N', 'f',
+            '\u{e9}', '\u{221e}', '\u{1f600}',
+        ];
+
+        /// Apply a few random insertions, deletions, replacements and
+        /// duplications to `s`.
+        fn mutate(s: &str, random: &mut Random) -> String {
+            let mut chars: Vec<char> = s.chars().collect();
+            for _ in 0..random.range(1..=4) {
+                let index = random.range(0..=chars.len());
+                match random.range(0..=3) {
+                    0 => chars.insert(index, *random.pick(FUZZ_ALPHABET)),
+                    1 if index < chars.len() => { chars.remove(index); }
+                    2 if index < chars.len() => chars[index] = *random.pick(FUZZ_ALPHABET),
+                    _ => {
+                        let copy: Vec<char> = chars[index..].iter().take(random.range(1..=8)).copied().collect();
+                        chars.splice(index..index, copy);
+                    }
+                }
+            }
+            chars.into_iter().collect()
+        }
+
+        #[test]
+        fn test_fuzz_parser() {
+            let mut random = Random::new(0xf022);
+
+            for &seed in FUZZ_SEEDS {
+                for _ in 0..1500 {
+                    let input = mutate(seed, &mut random);
+
+                    // None of the parsers may panic, and what they accept must
+                    // survive being formatted and parsed again.
+                    if let Ok(bounds) = parser::bounds_from_str(&input) {
+                        let formatted = format!("{}x{}", bounds.0, bounds.1);
+                        assert_eq!(parser::bounds_from_str(&formatted).ok(), Some(bounds), "{:?}", input);
+                    }
+                    if let Ok(c) = parser::complex_from_str(&input) {
+                        assert!(c.re.is_finite() && c.im.is_finite(), "{:?}", input);
+                        let formatted = format!("{},{}", c.re, c.im);
+                        assert_eq!(parser::complex_from_str(&formatted).ok(), Some(c), "{:?}", input);
+                    }
+                    if let Ok(c) = parser::big_complex_from_str(&input) {
+                        let parsed = parser::big_complex_from_str(&c.to_string()).unwrap();
+                        assert_eq!(parsed.with_scale(c.scale()), c.with_scale(c.scale()), "{:?}", input);
+                    }
+                    if let Ok(radius) = parser::escape_radius_from_str(&input) {
+                        assert!((radius * radius).is_finite() && radius >= 2.0, "{:?}", input);
+                    }
+                    if let Ok(fractal) = parser::fractal_from_str(&input) {
+                        assert_eq!(parser::fractal_from_str(&fractal.to_string()).ok(), Some(fractal), "{:?}", input);
+                    }
+                }
+            }
+        }
+    }
 }
 
 pub mod renderer {
     use super::*;
-    use crossbeam;
-    use image::ColorType;
-    use image::codecs::png::PngEncoder;
-    use std::fs::File;
+    use super::fractal::Fractal;
+    use super::palette::Colorizer;
+    use super::scheduler::{Cancelled, Control};
+    use serde::{Deserialize, Serialize};
+    use std::convert::TryFrom;
+
+    /// Parameters of the escape-time iteration.
+    #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+    pub struct Options {
+        /// Maximum number of iterations before a point is considered to be a
+        /// member of the set.
+        pub limit: u32,
+        /// Radius of the circle centered on the origin an orbit has to leave
+        /// to escape. Larger radii give smoother normalized iteration counts.
+        pub escape_radius: f64,
+        /// The fractal to render.
+        pub fractal: Fractal,
+        /// Supersampling used by `render_image`: every pixel is split into
+        /// `samples` by `samples` cells, one sample per cell, and the colors of
+        /// the samples are averaged.
+        pub samples: usize,
+        /// Take samples at random positions within their cells rather than
+        /// on a regular grid.
+        pub jitter: bool,
+    }
 
-    /// Render a rectangle of the Mandelbrot set into a buffer of pixels.
+    impl Default for Options {
+        fn default() -> Self {
+            Options {
+                limit: 255,
+                escape_radius: 2.0,
+                fractal: Fractal::Mandelbrot,
+                samples: 1,
+                jitter: false,
+            }
+        }
+    }
+
+    /// Render a rectangle of the fractal set into a buffer of pixels.
     ///
-    /// The `bounds` argument gives the which holds one grayscale `pixels`,
+    /// The `bounds` argument gives the width and height of the buffer `pixels`,
     /// which holds one grayscale pixel per byte. The `upper_left` and `lower_right`
     /// arguments specify points on the complex plane corresponding to the upper-
     /// left and lower-right corners of the pixel buffer.
@@ -46,11 +232,60 @@ pub mod renderer {
         pixels: &mut [u8],
         bounds: (usize, usize),
         upper_left: Complex<f64>,
-        lower_right: Complex<f64>
+        lower_right: Complex<f64>,
+        options: &Options
     ) {
         assert_eq!(pixels.len(), bounds.0 * bounds.1);
 
-        debug!("rendering pixels in bounds: {:?}", bounds);
+        let mut counts = vec![0; pixels.len()];
+        render_counts(&mut counts, bounds, upper_left, lower_right, options);
+        pixels.copy_from_slice(&palette::grayscale(&counts, options.limit));
+    }
+
+    /// The same as `render` but multithreaded.
+    pub fn parallel_render(
+        pixels: &mut [u8],
+        bounds: (usize, usize),
+        upper_left: Complex<f64>,
+        lower_right: Complex<f64>,
+        options: &Options,
+        n_threads: usize,
+    ) {
+        assert_eq!(pixels.len(), bounds.0 * bounds.1);
+
+        let mut counts = vec![0; pixels.len()];
+        parallel_render_counts(&mut counts, bounds, upper_left, lower_right, options, n_threads);
+        pixels.copy_from_slice(&palette::grayscale(&counts, options.limit));
+    }
+
+    /// Count the iterations it takes every point of a rectangle of the complex
+    /// plane to escape, storing one count per pixel into `counts`.
+    ///
+    /// The points that didn't escape within `options.limit` iterations get
+    /// the limit itself as their count. The Mandelbrot set is rendered with
+    /// the vectorized kernel from `simd`.
+    pub fn render_counts(
+        counts: &mut [u32],
+        bounds: (usize, usize),
+        upper_left: Complex<f64>,
+        lower_right: Complex<f64>,
+        options: &Options
+    ) {
+        assert_eq!(counts.len(), bounds.0 * bounds.1);
+
+        debug!("rendering iteration counts in bounds: {:?}", bounds);
+
+        if options.fractal == Fractal::Mandelbrot {
+            for (row, counts) in counts.chunks_mut(bounds.0.max(1)).enumerate() {
+                let points: Vec<Complex<f64>> = (0..bounds.0)
+                    .map(|column| pixel_to_point(bounds, (column, row), upper_left, lower_right))
+                    .collect();
+                counts.copy_from_slice(
+                    &simd::escape_times(&points, options.limit, options.escape_radius)
+                );
+            }
+            return;
+        }
 
         for row in 0..bounds.1 {
             for column in 0..bounds.0 {
@@ -58,88 +293,533 @@ pub mod renderer {
                     bounds, (column, row),
                     upper_left, lower_right
                 );
-                pixels[row * bounds.0 + column] =
-                    match escape_time(point, 255) {
-                        None => 0,
-                        Some(count) => 255 - count as u8
-                    };
+                counts[row * bounds.0 + column] =
+                    options.fractal
+                        .escape_time(point, options.limit, options.escape_radius)
+                        .unwrap_or(options.limit);
             }
         }
     }
 
-    /// The same as `render` but multithreaded.
-    pub fn parallel_render(
-        pixels: &mut [u8],
+    /// The same as `render_counts` but multithreaded.
+    pub fn parallel_render_counts(
+        counts: &mut [u32],
         bounds: (usize, usize),
         upper_left: Complex<f64>,
         lower_right: Complex<f64>,
+        options: &Options,
         n_threads: usize,
     ) {
-        let rows_per_band = bounds.1 / n_threads + 1;
-
-        debug!("parallel rendering: n_threads={}, rows_per_band={}", n_threads, rows_per_band);
-
-        {
-            let bands: Vec<&mut [u8]> =
-                pixels.chunks_mut(rows_per_band * bounds.0).collect();
-            crossbeam::scope(|spawner| {
-                for (i, band) in bands.into_iter().enumerate() {
-                    debug!("processing band: {}", i);
-                    let top = rows_per_band * i;
-                    let height = band.len() / bounds.0;
-                    let band_bounds = (bounds.0, height);
-                    let band_upper_left =
-                        pixel_to_point(bounds, (0, top), upper_left, lower_right);
-                    let band_lower_right =
-                        pixel_to_point(bounds, (bounds.0, top + height),
-                                       upper_left, lower_right);
-                    spawner.spawn(move |_| {
-                        render(band, band_bounds, band_upper_left, band_lower_right);
-                    });
+        parallel_render_counts_controlled(counts, bounds, upper_left, lower_right, options, n_threads,
+                                          &mut Control::default())
+            .expect("render without cancel token cancelled");
+    }
+
+    /// The same as `parallel_render_counts` but report the progress to
+    /// `control` and stop early once it's cancelled.
+    pub fn parallel_render_counts_controlled(
+        counts: &mut [u32],
+        bounds: (usize, usize),
+        upper_left: Complex<f64>,
+        lower_right: Complex<f64>,
+        options: &Options,
+        n_threads: usize,
+        control: &mut Control,
+    ) -> Result<(), Cancelled> {
+        parallel_counts(counts, bounds, options, n_threads, control, |pixel| {
+            pixel_to_point(bounds, pixel, upper_left, lower_right)
+        })
+    }
+
+    /// Fill `counts` with the iteration counts of the points `point(pixel)`.
+    pub(crate) fn parallel_counts<P>(
+        counts: &mut [u32],
+        bounds: (usize, usize),
+        options: &Options,
+        n_threads: usize,
+        control: &mut Control,
+        point: P,
+    ) -> Result<(), Cancelled>
+    where
+        P: Fn((usize, usize)) -> Complex<f64> + Sync,
+    {
+        scheduler::render_tiles_controlled(counts, bounds, n_threads, control, |tile| {
+            let points: Vec<Complex<f64>> = tile.pixels().map(&point).collect();
+            escape_counts(&points, options)
+        })
+    }
+
+    /// Iteration counts of `points`, `options.limit` for the points of the
+    /// set.
+    pub(crate) fn escape_counts(points: &[Complex<f64>], options: &Options) -> Vec<u32> {
+        if options.fractal == Fractal::Mandelbrot {
+            return simd::escape_times(points, options.limit, options.escape_radius);
+        }
+
+        points.iter()
+            .map(|&point| {
+                options.fractal
+                    .escape_time(point, options.limit, options.escape_radius)
+                    .unwrap_or(options.limit)
+            })
+            .collect()
+    }
+
+    /// Escape values of `points`, as `render_escape` computes them.
+    pub(crate) fn escape_values(points: &[Complex<f64>], options: &Options) -> Vec<Option<f64>> {
+        if options.fractal == Fractal::Mandelbrot {
+            return simd::smooth_escape_times(points, options.limit, options.escape_radius);
+        }
+
+        points.iter()
+            .map(|&point| options.fractal.smooth_escape_time(point, options.limit, options.escape_radius))
+            .collect()
+    }
+
+    /// Compute the escape value of every point of a rectangle of the complex
+    /// plane into `values`, using the normalized iteration count.
+    ///
+    /// The buffer holds one value per pixel: `None` for the points of the set
+    /// and the fractional number of iterations for the escaped ones. It is
+    /// turned into colors with `palette::Colorizer`. The Mandelbrot set is
+    /// rendered with the vectorized kernel from `simd`.
+    pub fn render_escape(
+        values: &mut [Option<f64>],
+        bounds: (usize, usize),
+        upper_left: Complex<f64>,
+        lower_right: Complex<f64>,
+        options: &Options
+    ) {
+        assert_eq!(values.len(), bounds.0 * bounds.1);
+
+        debug!("rendering escape values in bounds: {:?}", bounds);
+
+        if options.fractal == Fractal::Mandelbrot {
+            for (row, values) in values.chunks_mut(bounds.0.max(1)).enumerate() {
+                let points: Vec<Complex<f64>> = (0..bounds.0)
+                    .map(|column| pixel_to_point(bounds, (column, row), upper_left, lower_right))
+                    .collect();
+                values.copy_from_slice(
+                    &simd::smooth_escape_times(&points, options.limit, options.escape_radius)
+                );
+            }
+            return;
+        }
+
+        for row in 0..bounds.1 {
+            for column in 0..bounds.0 {
+                let point = pixel_to_point(
+                    bounds, (column, row),
+                    upper_left, lower_right
+                );
+                values[row * bounds.0 + column] = options.fractal
+                    .smooth_escape_time(point, options.limit, options.escape_radius);
+            }
+        }
+    }
+
+    /// The same as `render_escape` but multithreaded.
+    pub fn parallel_render_escape(
+        values: &mut [Option<f64>],
+        bounds: (usize, usize),
+        upper_left: Complex<f64>,
+        lower_right: Complex<f64>,
+        options: &Options,
+        n_threads: usize,
+    ) {
+        parallel_render_escape_controlled(values, bounds, upper_left, lower_right, options, n_threads,
+                                          &mut Control::default())
+            .expect("render without cancel token cancelled");
+    }
+
+    /// The same as `parallel_render_escape` but report the progress to
+    /// `control` and stop early once it's cancelled.
+    pub fn parallel_render_escape_controlled(
+        values: &mut [Option<f64>],
+        bounds: (usize, usize),
+        upper_left: Complex<f64>,
+        lower_right: Complex<f64>,
+        options: &Options,
+        n_threads: usize,
+        control: &mut Control,
+    ) -> Result<(), Cancelled> {
+        parallel_escape(values, bounds, options, n_threads, control, |pixel| {
+            pixel_to_point(bounds, pixel, upper_left, lower_right)
+        })
+    }
+
+    /// Fill `values` with the escape values of the points `point(pixel)`.
+    pub(crate) fn parallel_escape<P>(
+        values: &mut [Option<f64>],
+        bounds: (usize, usize),
+        options: &Options,
+        n_threads: usize,
+        control: &mut Control,
+        point: P,
+    ) -> Result<(), Cancelled>
+    where
+        P: Fn((usize, usize)) -> Complex<f64> + Sync,
+    {
+        scheduler::render_tiles_controlled(values, bounds, n_threads, control, |tile| {
+            let points: Vec<Complex<f64>> = tile.pixels().map(&point).collect();
+            escape_values(&points, options)
+        })
+    }
+
+    /// Render a rectangle of the complex plane into an image, supersampled
+    /// as `options` say.
+    ///
+    /// Without a `colorizer` the image is grayscale with one byte per pixel,
+    /// otherwise it has the colorizer's number of channels.
+    pub fn render_image(
+        bounds: (usize, usize),
+        upper_left: Complex<f64>,
+        lower_right: Complex<f64>,
+        options: &Options,
+        colorizer: Option<&Colorizer>,
+        n_threads: usize,
+    ) -> Vec<u8> {
+        render_image_with(bounds, upper_left, lower_right, options, colorizer, n_threads,
+                          palette::grayscale, Colorizer::colorize)
+    }
+
+    /// The same as `render_image` but with 16-bit components.
+    pub fn render_image16(
+        bounds: (usize, usize),
+        upper_left: Complex<f64>,
+        lower_right: Complex<f64>,
+        options: &Options,
+        colorizer: Option<&Colorizer>,
+        n_threads: usize,
+    ) -> Vec<u16> {
+        render_image_with(bounds, upper_left, lower_right, options, colorizer, n_threads,
+                          palette::grayscale16, Colorizer::colorize16)
+    }
+
+    /// Render the samples of an image and turn them into pixels with
+    /// `grayscale` or, given a `colorizer`, with `colorize`.
+    #[allow(clippy::too_many_arguments)]
+    fn render_image_with<T, G, C>(
+        bounds: (usize, usize),
+        upper_left: Complex<f64>,
+        lower_right: Complex<f64>,
+        options: &Options,
+        colorizer: Option<&Colorizer>,
+        n_threads: usize,
+        grayscale: G,
+        colorize: C,
+    ) -> Vec<T>
+    where
+        T: Copy + Default + Into<u64> + TryFrom<u64>,
+        G: Fn(&[u32], u32) -> Vec<T>,
+        C: Fn(&Colorizer, &[Option<f64>], u32) -> Vec<T>,
+    {
+        let samples = options.samples.max(1);
+        let sample_bounds = (bounds.0 * samples, bounds.1 * samples);
+        let point = |sample| sample_point(sample_bounds, sample, upper_left, lower_right, options);
+
+        debug!("rendering image: bounds={:?}, samples={}", bounds, samples);
+
+        let pixels = match colorizer {
+            None => {
+                let mut counts = vec![0; sample_bounds.0 * sample_bounds.1];
+                parallel_counts(&mut counts, sample_bounds, options, n_threads, &mut Control::default(), point)
+                    .expect("render without cancel token cancelled");
+                grayscale(&counts, options.limit)
+            }
+            Some(colorizer) => {
+                let mut values = vec![None; sample_bounds.0 * sample_bounds.1];
+                parallel_escape(&mut values, sample_bounds, options, n_threads, &mut Control::default(), point)
+                    .expect("render without cancel token cancelled");
+                colorize(colorizer, &values, options.limit)
+            }
+        };
+
+        downsample(&pixels, bounds, samples)
+    }
+
+    /// Average every `samples` by `samples` block of `pixels`, an image
+    /// with `samples` times the size `bounds`, into one pixel.
+    ///
+    /// The number of channels is deduced from the buffer size.
+    pub fn downsample<T>(pixels: &[T], bounds: (usize, usize), samples: usize) -> Vec<T>
+    where
+        T: Copy + Default + Into<u64> + TryFrom<u64>,
+    {
+        if samples <= 1 {
+            return pixels.to_vec();
+        }
+
+        let width = bounds.0 * samples;
+        let channels = pixels.len() / (bounds.0 * bounds.1 * samples * samples).max(1);
+        let area = (samples * samples) as u64;
+        let mut output = vec![T::default(); bounds.0 * bounds.1 * channels];
+
+        for row in 0..bounds.1 {
+            for column in 0..bounds.0 {
+                for channel in 0..channels {
+                    let mut sum = 0;
+                    for sy in row * samples..(row + 1) * samples {
+                        for sx in column * samples..(column + 1) * samples {
+                            sum += pixels[(sy * width + sx) * channels + channel].into();
+                        }
+                    }
+                    // The average is never above the largest sample, so it fits.
+                    output[(row * bounds.0 + column) * channels + channel] =
+                        T::try_from((sum + area / 2) / area).unwrap_or_default();
                 }
-            }).unwrap();
+            }
         }
+
+        output
+    }
+
+    /// Point of the `sample` of an image of `sample_bounds` samples, jittered
+    /// if `options` say so.
+    pub(crate) fn sample_point(
+        sample_bounds: (usize, usize),
+        sample: (usize, usize),
+        upper_left: Complex<f64>,
+        lower_right: Complex<f64>,
+        options: &Options,
+    ) -> Complex<f64> {
+        let offset = if options.jitter { jitter(sample) } else { (0.0, 0.0) };
+        sample_to_point(sample_bounds, sample, offset, upper_left, lower_right)
+    }
+
+    /// Pseudo-random offset of a sample within its cell, in `[0, 1)` along
+    /// both axes.
+    ///
+    /// The offset only depends on the sample coordinates, so jittered renders
+    /// are reproducible whatever the number of threads.
+    pub(crate) fn jitter(sample: (usize, usize)) -> (f64, f64) {
+        // SplitMix64 finalizer applied to the sample coordinates.
+        let mix = |mut x: u64| {
+            x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+            x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+            x ^ (x >> 31)
+        };
+        let seed = (sample.0 as u64).wrapping_mul(0x9e3779b97f4a7c15) ^ sample.1 as u64;
+        let (a, b) = (mix(seed), mix(seed.wrapping_add(0x9e3779b97f4a7c15)));
+        let unit = |x: u64| (x >> 11) as f64 / (1u64 << 53) as f64;
+        (unit(a), unit(b))
     }
 
     /// Write the buffer `pixels`, whose dimensions are given by `bounds`, to the
-    /// file named `filename`.
+    /// file named `filename`, in the format given by its extension (see
+    /// `output::Format`).
+    ///
+    /// The color type is deduced from the buffer size: one byte per pixel is
+    /// written as grayscale, three as RGB and four as RGBA.
     pub fn write_image(
         filename: &str,
         pixels: &[u8],
         bounds: (usize, usize)
-    ) -> Result<(), std::io::Error> {
-        let output = File::create(filename)?;
+    ) -> Result<(), Error> {
+        output::write_image(filename, pixels, bounds, &[])
+    }
+
+    #[cfg(test)]
+    pub(crate) mod tests {
+        use crate::fractal::Fractal;
+        use crate::{output, palette, parser, renderer, shading};
+        use image::GenericImageView;
+        use num::Complex;
+        use rstest::rstest;
+
+        /// Deterministic pseudo-random numbers for the property and fuzz tests,
+        /// drawn from `renderer::jitter` so that failures can be reproduced.
+        pub(crate) struct Random {
+            seed: usize,
+            index: usize,
+        }
+
+        impl Random {
+            pub(crate) fn new(seed: usize) -> Random {
+                Random { seed, index: 0 }
+            }
+
+            /// A number in `[0, 1)`.
+            pub(crate) fn unit(&mut self) -> f64 {
+                self.index += 1;
+                renderer::jitter((self.seed, self.index)).0
+            }
+
+            /// A number in `range`.
+            pub(crate) fn range(&mut self, range: std::ops::RangeInclusive<usize>) -> usize {
+                range.start() + (self.unit() * (range.end() - range.start() + 1) as f64) as usize
+            }
+
+            /// An element of `items`.
+            pub(crate) fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+                &items[self.range(0..=items.len() - 1)]
+            }
+        }
+
+        /// A random view, fractal and limit, with views from the whole set down
+        /// to a width of 1e-6.
+        fn random_render(random: &mut Random) -> (Complex<f64>, Complex<f64>, renderer::Options) {
+            let center = Complex { re: random.unit() * 3.0 - 2.0, im: random.unit() * 2.4 - 1.2 };
+            let width = 10f64.powf(-6.0 * random.unit()) * 3.0;
+            let half_size = Complex { re: width / 2.0, im: -width * (0.5 + random.unit()) / 2.0 };
+            let fractal = *random.pick(&[
+                Fractal::Mandelbrot,
+                Fractal::Julia(Complex { re: -0.8, im: 0.156 }),
+                Fractal::BurningShip,
+                Fractal::Tricorn,
+                Fractal::Multibrot(3.0),
+            ]);
+            let options = renderer::Options { limit: random.range(1..=200) as u32, fractal, ..Default::default() };
+            (center - half_size, center + half_size, options)
+        }
+
+        #[test]
+        fn test_parallel_render_matches_render_property() {
+            let mut random = Random::new(46);
+
+            for case in 0..150 {
+                let bounds = (random.range(1..=70), random.range(1..=70));
+                let n_threads = random.range(0..=16);
+                let (upper_left, lower_right, options) = random_render(&mut random);
+                let context = format!("case {}: {:?} with {} threads from {} to {}, {:?}",
+                                      case, bounds, n_threads, upper_left, lower_right, options);
+
+                let mut expected = vec![0; bounds.0 * bounds.1];
+                let mut actual = vec![1; bounds.0 * bounds.1];
+                renderer::render(&mut expected, bounds, upper_left, lower_right, &options);
+                renderer::parallel_render(&mut actual, bounds, upper_left, lower_right, &options, n_threads);
+                assert!(actual == expected, "{}", context);
+
+                let mut expected = vec![None; bounds.0 * bounds.1];
+                let mut actual = vec![Some(-1.0); bounds.0 * bounds.1];
+                renderer::render_escape(&mut expected, bounds, upper_left, lower_right, &options);
+                renderer::parallel_render_escape(&mut actual, bounds, upper_left, lower_right, &options, n_threads);
+                assert!(actual == expected, "{}", context);
+            }
+        }
 
-        let encoder = PngEncoder::new(output);
+        #[test]
+        fn test_render_image_is_independent_of_threads_property() {
+            let mut random = Random::new(4646);
+            let colorizer = palette::Colorizer::new(palette::Palette::by_name("fire").unwrap(), palette::Coloring::Smooth);
 
-        match encoder.encode(
-            &pixels,
-            bounds.0 as u32, bounds.1 as u32,
-            ColorType::L8
+            for case in 0..40 {
+                let bounds = (random.range(1..=40), random.range(1..=40));
+                let (upper_left, lower_right, options) = random_render(&mut random);
+                let options = renderer::Options { samples: random.range(1..=3), jitter: random.unit() < 0.5, ..options };
+
+                let expected = renderer::render_image(bounds, upper_left, lower_right, &options, Some(&colorizer), 1);
+                let actual = renderer::render_image(bounds, upper_left, lower_right, &options, Some(&colorizer),
+                                                    random.range(2..=16));
+
+                assert!(actual == expected, "case {}: {:?} {:?}", case, bounds, options);
+            }
+        }
+
+        /// Directory of the golden images.
+        const GOLDEN_DIRECTORY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/golden");
+
+        /// Largest difference of a color component from the golden image that
+        /// counts as equal, allowing for rounding differences between platforms.
+        const GOLDEN_TOLERANCE: u8 = 8;
+
+        /// Largest fraction of the components allowed to differ by more than
+        /// `GOLDEN_TOLERANCE`, allowing for pixels on the edge of the set.
+        const GOLDEN_OUTLIERS: f64 = 0.01;
+
+        /// Compare `pixels` with the golden image `name`, or replace the golden
+        /// image when `MANDELBROT_BLESS` is set.
+        fn assert_golden(name: &str, pixels: &[u8], bounds: (usize, usize)) {
+            let path = format!("{}/{}.png", GOLDEN_DIRECTORY, name);
+            if std::env::var_os("MANDELBROT_BLESS").is_some() {
+                std::fs::create_dir_all(GOLDEN_DIRECTORY).unwrap();
+                output::write_image(&path, pixels, bounds, &[]).unwrap();
+                return;
+            }
+
+            let golden = image::open(&path)
+                .unwrap_or_else(|err| panic!("{}: {} (set MANDELBROT_BLESS=1 to create it)", path, err));
+            assert_eq!((golden.width() as usize, golden.height() as usize), bounds, "{}", name);
+            assert_eq!(golden.as_bytes().len(), pixels.len(), "{}: different color type", name);
+
+            let outliers = golden.as_bytes().iter()
+                .zip(pixels)
+                .filter(|(&expected, &actual)| expected.abs_diff(actual) > GOLDEN_TOLERANCE)
+                .count();
+            assert!(outliers as f64 <= pixels.len() as f64 * GOLDEN_OUTLIERS,
+                    "{}: {} of {} components differ from the golden image", name, outliers, pixels.len());
+        }
+
+        #[rstest]
+        #[case("mandelbrot", ((-2.5, 1.3125), (1.0, -1.3125)), "mandelbrot", 255, None, palette::Coloring::Smooth, 1)]
+        #[case("seahorse_valley", ((-0.775, 0.11875), (-0.725, 0.08125)), "mandelbrot", 500, Some("fire"),
+               palette::Coloring::Smooth, 1)]
+        #[case("julia", ((-1.6, 1.2), (1.6, -1.2)), "julia:-0.8,0.156", 300, Some("ocean"), palette::Coloring::Banded, 1)]
+        #[case("burning_ship", ((-1.8, 0.08), (-1.7, 0.005)), "burning-ship", 200, Some("rainbow"),
+               palette::Coloring::Smooth, 3)]
+        #[case("multibrot", ((-1.5, 1.125), (1.5, -1.125)), "multibrot:3", 100, Some("grayscale"),
+               palette::Coloring::Histogram, 2)]
+        fn test_golden_images(
+            #[case] name: &str,
+            #[case] corners: ((f64, f64), (f64, f64)),
+            #[case] fractal: &str,
+            #[case] limit: u32,
+            #[case] palette: Option<&str>,
+            #[case] coloring: palette::Coloring,
+            #[case] samples: usize,
         ) {
-            Ok(_) => Ok(()),
-            Err(err) => Err(
-                std::io::Error::new(
-                    std::io::ErrorKind::InvalidData, err.to_string()
-                )
-            )
+            let (upper_left, lower_right) = corners;
+            let bounds = (64, 48);
+            let options = renderer::Options {
+                limit,
+                fractal: parser::fractal_from_str(fractal).unwrap(),
+                samples,
+                jitter: samples > 1,
+                ..Default::default()
+            };
+            let colorizer = palette.map(|name| palette::Colorizer::new(palette::Palette::by_name(name).unwrap(), coloring));
+
+            let pixels = renderer::render_image(bounds,
+                                                Complex { re: upper_left.0, im: upper_left.1 },
+                                                Complex { re: lower_right.0, im: lower_right.1 },
+                                                &options,
+                                                colorizer.as_ref(),
+                                                2);
+
+            assert_golden(name, &pixels, bounds);
         }
-    }
 
+        #[test]
+        fn test_golden_distance_shading() {
+            let bounds = (64, 48);
+            let options = renderer::Options { limit: 500, escape_radius: 1000.0, ..Default::default() };
+
+            let pixels = shading::render(bounds,
+                                         Complex { re: -2.5, im: 1.3125 },
+                                         Complex { re: 1.0, im: -1.3125 },
+                                         &options,
+                                         shading::Shading::Distance,
+                                         &palette::Palette::grayscale(),
+                                         2);
+
+            assert_golden("distance_shading", &pixels, bounds);
+        }
+    }
 }
 
 /// Try to determine if `c` is in the Mandelbrot set, using at most `limit`
 /// iterations to decide.
 ///
 /// If `c` is not a member, return `Some(i)`, where `i` is the number of
-/// iterations it took for `c` to leave the circle of radius 2 centered on the
-/// origin. If `c` seems to be a member (more precisely, if we reached the
-/// iteration limit without being able to prove that `c` is not a member),
-/// return `None`.
-fn escape_time(c: Complex<f64>, limit: usize) -> Option<usize> {
+/// iterations it took for `c` to leave the circle of radius `escape_radius`
+/// centered on the origin. If `c` seems to be a member (more precisely, if we
+/// reached the iteration limit without being able to prove that `c` is not a
+/// member), return `None`.
+fn escape_time(c: Complex<f64>, limit: u32, escape_radius: f64) -> Option<u32> {
+    let bailout = escape_radius * escape_radius;
     let mut z = Complex { re: 0.0, im: 0.0 };
     for i in 0..limit {
-        if z.norm_sqr() > 4.0 {
+        if z.norm_sqr() > bailout {
             return Some(i);
         }
         z = z * z + c;
@@ -168,13 +848,12 @@ fn parse_pair<T: FromStr>(s: &str, separator: char) -> Option<(T, T)> {
     }
 }
 
-/// Parse a pair of floating-point numbers separated by a comma as a complex
-/// number.
+/// Parse a pair of finite floating-point numbers separated by a comma as a
+/// complex number.
 fn parse_complex(s: &str) -> Option<Complex<f64>> {
-    match parse_pair(s, ',') {
-        Some((re, im)) => Some(Complex { re, im }),
-        None => None
-    }
+    parse_pair(s, ',')
+        .map(|(re, im)| Complex { re, im })
+        .filter(|c: &Complex<f64>| c.re.is_finite() && c.im.is_finite())
 }
 
 /// Given the row and column of a pixel in the output image, return the
@@ -203,9 +882,47 @@ fn pixel_to_point(
     }
 }
 
+/// The inverse of `pixel_to_point`: return the pixel of an image of size
+/// `bounds` containing `point`, or `None` if the point is outside the image.
+fn point_to_pixel(
+    bounds: (usize, usize),
+    point: Complex<f64>,
+    upper_left: Complex<f64>,
+    lower_right: Complex<f64>
+) -> Option<(usize, usize)> {
+    let column = (point.re - upper_left.re) / (lower_right.re - upper_left.re) * bounds.0 as f64;
+    let row = (upper_left.im - point.im) / (upper_left.im - lower_right.im) * bounds.1 as f64;
+    // Also rejects NaN.
+    if column >= 0.0 && row >= 0.0 && column < bounds.0 as f64 && row < bounds.1 as f64 {
+        Some((column as usize, row as usize))
+    } else {
+        None
+    }
+}
+
+/// The same as `pixel_to_point` for a point at fractional `offset` within
+/// the pixel, where `(0.0, 0.0)` is the pixel's upper-left corner.
+fn sample_to_point(
+    bounds: (usize, usize),
+    pixel: (usize, usize),
+    offset: (f64, f64),
+    upper_left: Complex<f64>,
+    lower_right: Complex<f64>
+) -> Complex<f64> {
+    let point = pixel_to_point(bounds, pixel, upper_left, lower_right);
+    if offset == (0.0, 0.0) {
+        return point;
+    }
+    Complex {
+        re: point.re + offset.0 * (lower_right.re - upper_left.re) / bounds.0 as f64,
+        im: point.im - offset.1 * (upper_left.im - lower_right.im) / bounds.1 as f64
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use super::fractal::Fractal;
     use rstest::rstest;
 
     #[rstest]
@@ -215,10 +932,10 @@ mod tests {
     fn test_escape_time(
         #[case] re: f64,
         #[case] im: f64,
-        #[case] limit: usize,
-        #[case] result: Option<usize>
+        #[case] limit: u32,
+        #[case] result: Option<u32>
     ) {
-        assert_eq!(escape_time(Complex { re, im }, limit), result);
+        assert_eq!(escape_time(Complex { re, im }, limit, 2.0), result);
     }
 
     #[test]
@@ -236,10 +953,65 @@ mod tests {
     #[case("1.25,-0.0625", Some(Complex { re: 1.25, im: - 0.0625 }))]
     #[case("1,2", Some(Complex { re: 1.0, im: 2.0 }))]
     #[case(",-0.0625", None)]
+    #[case("1e400,0", None)]
+    #[case("0,NaN", None)]
     fn test_parse_complex(#[case] s: &str, #[case] num: Option<Complex<f64>>) {
         assert_eq!(parse_complex(s), num);
     }
 
+    #[rstest]
+    #[case("2", Some(2.0))]
+    #[case("1e3", Some(1000.0))]
+    #[case("1.5", None)]
+    #[case("radius", None)]
+    #[case("1e400", None)]
+    #[case("1.34e154", Some(1.34e154))]
+    #[case("1.35e154", None)]
+    fn test_escape_radius_from_str(#[case] s: &str, #[case] radius: Option<f64>) {
+        assert_eq!(parser::escape_radius_from_str(s).ok(), radius);
+    }
+
+    #[test]
+    fn test_big_complex_from_str() {
+        let digits = "0.".to_string() + &"1".repeat(300);
+        let s = format!("-{},{}", digits, digits);
+
+        let c = parser::big_complex_from_str(&s).unwrap();
+
+        assert!(c.scale() > 300 * 3);
+        assert!((c.to_complex().re + 0.1111111111111111).abs() < 1e-15);
+        assert!(parser::big_complex_from_str("1.0;2.0").is_err());
+        assert!(parser::big_complex_from_str("1.0,").is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = parser::bounds_from_str("800by600").unwrap_err();
+
+        assert!(matches!(err, Error::Parse(_)));
+        assert_eq!(err.to_string(), "wrong image size format: 800by600");
+        assert!(matches!(parser::complex_from_str("1.0"), Err(Error::Parse(_))));
+    }
+
+    #[rstest]
+    #[case("mandelbrot", Some(Fractal::Mandelbrot))]
+    #[case("burning-ship", Some(Fractal::BurningShip))]
+    #[case("tricorn", Some(Fractal::Tricorn))]
+    #[case("julia:-0.8,0.156", Some(Fractal::Julia(Complex { re: -0.8, im: 0.156 })))]
+    #[case("multibrot:3", Some(Fractal::Multibrot(3.0)))]
+    #[case("multibrot:1", None)]
+    #[case("multibrot:inf", None)]
+    #[case("julia", None)]
+    #[case("tricorn:2", None)]
+    #[case("newton", None)]
+    fn test_fractal_from_str(#[case] s: &str, #[case] fractal: Option<Fractal>) {
+        assert_eq!(parser::fractal_from_str(s).ok(), fractal);
+
+        if let Some(fractal) = fractal {
+            assert_eq!(parser::fractal_from_str(&fractal.to_string()).ok(), Some(fractal));
+        }
+    }
+
     #[test]
     fn test_pixel_to_point() {
         assert_eq!(
@@ -250,6 +1022,18 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_point_to_pixel() {
+        let (upper_left, lower_right) = (Complex { re: -1.0, im: 1.0 }, Complex { re: 1.0, im: -1.0 });
+
+        for pixel in &[(0, 0), (25, 175), (99, 199)] {
+            let point = sample_to_point((100, 200), *pixel, (0.5, 0.5), upper_left, lower_right);
+            assert_eq!(point_to_pixel((100, 200), point, upper_left, lower_right), Some(*pixel));
+        }
+        assert_eq!(point_to_pixel((100, 200), Complex { re: 1.0, im: 0.0 }, upper_left, lower_right), None);
+        assert_eq!(point_to_pixel((100, 200), Complex { re: 0.0, im: 1.5 }, upper_left, lower_right), None);
+    }
+
     #[test]
     fn test_render() {
         let mut actual = vec![0; 9];
@@ -257,9 +1041,134 @@ mod tests {
 
         renderer::render(&mut actual, (3, 3),
                          Complex { re: -1.0, im: 1.0 },
-                         Complex { re: 1.0, im: -1.0 });
+                         Complex { re: 1.0, im: -1.0 },
+                         &renderer::Options::default());
+
+        assert_eq!(actual, expected);
+    }
+
+    #[rstest]
+    #[case(2.0, Some(15))]
+    #[case(10.0, Some(16))]
+    #[case(1e3, Some(18))]
+    fn test_escape_radius(#[case] escape_radius: f64, #[case] result: Option<u32>) {
+        assert_eq!(escape_time(Complex { re: 0.3, im: 0.6 }, 1000, escape_radius), result);
+    }
+
+    #[test]
+    fn test_render_counts_beyond_255() {
+        let mut counts = vec![0; 3];
+        let options = renderer::Options { limit: 2000, ..Default::default() };
+
+        renderer::render_counts(&mut counts, (3, 1),
+                                Complex { re: 0.25, im: 0.0 },
+                                Complex { re: 0.250015, im: 0.0 },
+                                &options);
+
+        assert_eq!(counts[0], 2000);
+        assert!(counts[1] > 255 && counts[1] < 2000, "{}", counts[1]);
+    }
+
+    #[test]
+    fn test_parallel_render_counts() {
+        let bounds = (150, 90);
+        let (upper_left, lower_right) = (Complex { re: -2.0, im: 1.2 }, Complex { re: 1.0, im: -1.2 });
+        let options = renderer::Options::default();
+        let mut expected = vec![0; bounds.0 * bounds.1];
+
+        renderer::render_counts(&mut expected, bounds, upper_left, lower_right, &options);
+
+        for &n_threads in &[1, 2, 7, 32] {
+            let mut actual = vec![0; bounds.0 * bounds.1];
+            renderer::parallel_render_counts(&mut actual, bounds, upper_left, lower_right, &options, n_threads);
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[test]
+    fn test_render_image_single_sample() {
+        let bounds = (20, 15);
+        let (upper_left, lower_right) = (Complex { re: -2.0, im: 1.2 }, Complex { re: 1.0, im: -1.2 });
+        let options = renderer::Options { jitter: true, ..Default::default() };
+        let mut expected = vec![0; bounds.0 * bounds.1];
+
+        renderer::render(&mut expected, bounds, upper_left, lower_right, &renderer::Options::default());
+
+        let mut actual = renderer::render_image(bounds, upper_left, lower_right,
+                                                &renderer::Options::default(), None, 2);
+        assert_eq!(actual, expected);
+
+        actual = renderer::render_image(bounds, upper_left, lower_right, &options, None, 2);
+        assert_ne!(actual, expected);
+    }
+
+    #[test]
+    fn test_render_image_supersampled() {
+        let bounds = (12, 8);
+        let (upper_left, lower_right) = (Complex { re: -2.0, im: 1.2 }, Complex { re: 1.0, im: -1.2 });
+        let colorizer = palette::Colorizer::new(palette::Palette::grayscale(), palette::Coloring::Smooth);
+
+        for &jitter in &[false, true] {
+            let options = renderer::Options { samples: 3, jitter, ..Default::default() };
+
+            let pixels = renderer::render_image(bounds, upper_left, lower_right, &options, Some(&colorizer), 3);
+
+            assert_eq!(pixels.len(), bounds.0 * bounds.1 * 3);
+            assert_eq!(pixels, renderer::render_image(bounds, upper_left, lower_right, &options, Some(&colorizer), 1));
+        }
+    }
+
+    #[test]
+    fn test_downsample() {
+        let pixels: Vec<u8> = vec![
+            0, 10, 100, 101, 7, 7,
+            20, 30, 102, 103, 7, 7,
+        ];
+
+        assert_eq!(renderer::downsample(&pixels, (3, 1), 2), vec![15, 102, 7]);
+        assert_eq!(renderer::downsample(&pixels, (6, 2), 1), pixels);
+    }
+
+    #[test]
+    fn test_jitter() {
+        for &sample in &[(0, 0), (1, 0), (0, 1), (1000, 3)] {
+            let (dx, dy) = renderer::jitter(sample);
+            assert!((0.0..1.0).contains(&dx) && (0.0..1.0).contains(&dy));
+            assert_eq!(renderer::jitter(sample), (dx, dy));
+        }
+        assert_ne!(renderer::jitter((1, 0)), renderer::jitter((0, 1)));
+    }
+
+    #[test]
+    fn test_parallel_render_julia() {
+        let bounds = (16, 12);
+        let (upper_left, lower_right) = (Complex { re: -1.5, im: 1.0 }, Complex { re: 1.5, im: -1.0 });
+        let options = renderer::Options {
+            fractal: Fractal::Julia(Complex { re: -0.8, im: 0.156 }),
+            ..Default::default()
+        };
+        let mut expected = vec![0; bounds.0 * bounds.1];
+        let mut actual = vec![0; bounds.0 * bounds.1];
+
+        renderer::render(&mut expected, bounds, upper_left, lower_right, &options);
+        renderer::parallel_render(&mut actual, bounds, upper_left, lower_right, &options, 3);
 
         assert_eq!(actual, expected);
+        assert_eq!(expected[0], 255 - 1);
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_parallel_render_escape() {
+        let bounds = (17, 11);
+        let (upper_left, lower_right) = (Complex { re: -2.0, im: 1.0 }, Complex { re: 1.0, im: -1.0 });
+        let mut expected = vec![None; bounds.0 * bounds.1];
+        let mut actual = vec![None; bounds.0 * bounds.1];
+
+        let options = renderer::Options::default();
+
+        renderer::render_escape(&mut expected, bounds, upper_left, lower_right, &options);
+        renderer::parallel_render_escape(&mut actual, bounds, upper_left, lower_right, &options, 4);
+
+        assert_eq!(actual, expected);
+    }
+}
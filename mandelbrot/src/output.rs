@@ -0,0 +1,500 @@
+//! Writing rendered images and iteration counts to files, in the format
+//! given by the file extension.
+//!
+//! Pixels are written as PNG, TIFF or binary PPM/PGM, with 8 or 16 bits per
+//! component for PNG and TIFF. Iteration counts are dumped without any
+//! coloring, either as raw little-endian `u32` values or as a NumPy `.npy`
+//! array of shape `(height, width)`.
+//!
+//! PNG files can carry text chunks, like the render parameters of a scene,
+//! which `read_png_text` reads back.
+use image::codecs::png::PngEncoder;
+use image::codecs::tiff::TiffEncoder;
+use image::ColorType;
+use super::Error;
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use tiff::encoder::colortype;
+
+/// An output file format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Png,
+    Tiff,
+    /// Binary PPM or PGM, depending on the number of channels.
+    Pnm,
+    /// Iteration counts as little-endian `u32` values, row by row.
+    Raw,
+    /// Iteration counts as a NumPy array.
+    Npy,
+    /// Animated GIF, which only zoom animations write.
+    Gif,
+}
+
+/// Extensions `Format::from_filename` recognizes.
+const EXTENSIONS: &str = ".png, .tif, .tiff, .ppm, .pgm, .pnm, .raw, .bin, .npy, .gif";
+
+impl Format {
+    /// Choose the format from the extension of `filename`, PNG if it has
+    /// none, or return an error listing the supported extensions if it's
+    /// unknown.
+    pub fn from_filename(filename: &str) -> Result<Format, Error> {
+        let extension = match Path::new(filename).extension() {
+            Some(extension) => extension.to_string_lossy().to_ascii_lowercase(),
+            None => return Ok(Format::Png),
+        };
+        match extension.as_str() {
+            "png" => Ok(Format::Png),
+            "tif" | "tiff" => Ok(Format::Tiff),
+            "ppm" | "pgm" | "pnm" => Ok(Format::Pnm),
+            "raw" | "bin" => Ok(Format::Raw),
+            "npy" => Ok(Format::Npy),
+            "gif" => Ok(Format::Gif),
+            _ => Err(Error::Validation(format!(
+                "unknown file extension .{} of {}, expected one of {}", extension, filename, EXTENSIONS
+            ))),
+        }
+    }
+
+    /// Whether the format holds iteration counts rather than pixels.
+    pub fn is_counts(&self) -> bool {
+        matches!(self, Format::Raw | Format::Npy)
+    }
+}
+
+/// Signature of PNG files.
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+/// Write the grayscale, RGB or RGBA `pixels` of an image of size `bounds`
+/// to `filename`, with the `(keyword, text)` pairs of `text` as text chunks
+/// if it's a PNG file.
+pub fn write_image(
+    filename: &str,
+    pixels: &[u8],
+    bounds: (usize, usize),
+    text: &[(String, String)],
+) -> Result<(), Error> {
+    let channels = channels(pixels.len(), bounds)?;
+    let color_type = match channels {
+        1 => ColorType::L8,
+        3 => ColorType::Rgb8,
+        _ => ColorType::Rgba8
+    };
+    let (width, height) = (bounds.0 as u32, bounds.1 as u32);
+
+    match Format::from_filename(filename)? {
+        Format::Png => {
+            let output = BufWriter::new(File::create(filename)?);
+            png_writer(output, bounds, channels, png::BitDepth::Eight, text)?
+                .write_image_data(pixels)
+                .map_err(|err| Error::Encode(err.to_string()))
+        }
+        Format::Tiff => TiffEncoder::new(File::create(filename)?)
+            .encode(pixels, width, height, color_type)
+            .map_err(Error::from),
+        Format::Pnm => write_pnm(filename, pixels, bounds, channels),
+        Format::Gif => Err(Error::Validation("only zoom animations are written as GIF files".to_string())),
+        format => Err(Error::Validation(
+            format!("{:?} files hold iteration counts, not pixels", format)
+        ))
+    }
+}
+
+/// Encode 8-bit grayscale, RGB or RGBA `pixels` as a PNG image in memory.
+pub fn encode_png(pixels: &[u8], bounds: (usize, usize)) -> Result<Vec<u8>, Error> {
+    let color_type = match channels(pixels.len(), bounds)? {
+        1 => ColorType::L8,
+        3 => ColorType::Rgb8,
+        _ => ColorType::Rgba8
+    };
+    let mut png = Vec::new();
+    PngEncoder::new(&mut png)
+        .encode(pixels, bounds.0 as u32, bounds.1 as u32, color_type)?;
+    Ok(png)
+}
+
+/// Write an image of size `bounds` with `channels` bytes per pixel to
+/// `filename` as PNG or PPM/PGM, taking its pixels from `bands`, each one
+/// holding whole rows, so that the image never needs to be in memory. PNG
+/// files get the text chunks of `text`.
+pub fn write_image_bands<I>(
+    filename: &str,
+    bounds: (usize, usize),
+    channels: usize,
+    bands: I,
+    text: &[(String, String)],
+) -> Result<(), Error>
+where
+    I: IntoIterator<Item = Result<Vec<u8>, Error>>,
+{
+    if ![1, 3, 4].contains(&channels) {
+        return Err(Error::Validation(format!("images can't have {} channels", channels)));
+    }
+    let format = Format::from_filename(filename)?;
+    if format != Format::Png && format != Format::Pnm {
+        return Err(Error::Validation(format!("{:?} files can't be written band by band, use PNG or PPM", format)));
+    }
+
+    let row_length = bounds.0 * channels;
+    let mut n_bytes = 0;
+    let mut output = BufWriter::new(File::create(filename)?);
+
+    if format == Format::Png {
+        let mut writer = png_writer(&mut output, bounds, channels, png::BitDepth::Eight, text)?;
+        let mut stream = writer.stream_writer();
+        for band in bands {
+            let band = band?;
+            n_bytes += band.len();
+            stream.write_all(&band)?;
+        }
+        stream.finish().map_err(|err| Error::Encode(err.to_string()))?;
+        // Dropping the writer ends the image.
+    } else {
+        write!(output, "{}\n{} {}\n255\n", if channels == 1 { "P5" } else { "P6" }, bounds.0, bounds.1)?;
+        for band in bands {
+            let band = band?;
+            n_bytes += band.len();
+            if channels == 4 {
+                for pixel in band.chunks(4) {
+                    output.write_all(&pixel[..3])?;
+                }
+            } else {
+                output.write_all(&band)?;
+            }
+        }
+    }
+
+    if n_bytes != row_length * bounds.1 {
+        return Err(Error::Validation(
+            format!("bands of {} bytes don't match image size {:?}", n_bytes, bounds)
+        ));
+    }
+    output.flush()?;
+    Ok(())
+}
+
+/// The same as `write_image` for pixels with 16-bit components, which only
+/// PNG and TIFF support.
+pub fn write_image16(
+    filename: &str,
+    pixels: &[u16],
+    bounds: (usize, usize),
+    text: &[(String, String)],
+) -> Result<(), Error> {
+    let channels = channels(pixels.len(), bounds)?;
+    let (width, height) = (bounds.0 as u32, bounds.1 as u32);
+
+    match Format::from_filename(filename)? {
+        Format::Png => {
+            // PNG stores samples big-endian.
+            let bytes: Vec<u8> = pixels.iter().flat_map(|p| p.to_be_bytes()).collect();
+            let output = BufWriter::new(File::create(filename)?);
+            png_writer(output, bounds, channels, png::BitDepth::Sixteen, text)?
+                .write_image_data(&bytes)
+                .map_err(|err| Error::Encode(err.to_string()))
+        }
+        Format::Tiff => {
+            let mut encoder = tiff::encoder::TiffEncoder::new(File::create(filename)?)
+                .map_err(|err| Error::Encode(err.to_string()))?;
+            match channels {
+                1 => encoder.write_image::<colortype::Gray16>(width, height, pixels),
+                3 => encoder.write_image::<colortype::RGB16>(width, height, pixels),
+                _ => encoder.write_image::<colortype::RGBA16>(width, height, pixels),
+            }.map_err(|err| Error::Encode(err.to_string()))
+        }
+        format => Err(Error::Validation(
+            format!("{:?} files don't support 16-bit pixels, use PNG or TIFF", format)
+        ))
+    }
+}
+
+/// Read the `(keyword, text)` pairs of the text chunks of the PNG file
+/// `filename`, in the order of the file.
+pub fn read_png_text(filename: &str) -> Result<Vec<(String, String)>, Error> {
+    let bytes = fs::read(filename)?;
+    let not_png = || Error::Parse("not a PNG file or a truncated one".to_string());
+    let mut rest = bytes.strip_prefix(&PNG_SIGNATURE[..]).ok_or_else(not_png)?;
+
+    // Chunks are a 4-byte big-endian length, a 4-byte type, the data and a
+    // 4-byte CRC.
+    let mut text = Vec::new();
+    loop {
+        if rest.len() < 8 {
+            return Err(not_png());
+        }
+        let length = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        let (kind, data) = (&rest[4..8], &rest[8..]);
+        if data.len() < length.saturating_add(4) {
+            return Err(not_png());
+        }
+        match kind {
+            b"IEND" => return Ok(text),
+            b"tEXt" => {
+                let data = &data[..length];
+                let separator = data.iter().position(|&byte| byte == 0)
+                    .ok_or_else(|| Error::Parse("text chunk without a keyword".to_string()))?;
+                // Text chunks are in Latin-1, whose code points are the
+                // first 256 of Unicode.
+                let latin1 = |bytes: &[u8]| -> String { bytes.iter().map(|&byte| byte as char).collect() };
+                text.push((latin1(&data[..separator]), latin1(&data[separator + 1..])));
+            }
+            _ => {}
+        }
+        rest = &data[length + 4..];
+    }
+}
+
+/// Write the header of a PNG image of size `bounds` to `output`, followed by
+/// the text chunks of `text`, and return the writer of its pixels.
+fn png_writer<W: Write>(
+    output: W,
+    bounds: (usize, usize),
+    channels: usize,
+    depth: png::BitDepth,
+    text: &[(String, String)],
+) -> Result<png::Writer<W>, Error> {
+    let mut encoder = png::Encoder::new(output, bounds.0 as u32, bounds.1 as u32);
+    encoder.set_color(match channels {
+        1 => png::ColorType::Grayscale,
+        3 => png::ColorType::RGB,
+        _ => png::ColorType::RGBA,
+    });
+    encoder.set_depth(depth);
+    let mut writer = encoder.write_header().map_err(|err| Error::Encode(err.to_string()))?;
+
+    for (keyword, value) in text {
+        let latin1 = |s: &str| -> Option<Vec<u8>> {
+            s.chars().map(|c| u8::try_from(c).ok().filter(|&byte| byte != 0)).collect()
+        };
+        let chunk = match (latin1(keyword), latin1(value)) {
+            (Some(keyword), Some(value)) if (1..80).contains(&keyword.len()) => {
+                Some([keyword, vec![0], value].concat())
+            }
+            _ => None
+        };
+        let chunk = chunk.ok_or_else(|| Error::Validation(format!(
+            "can't write the text chunk {:?}: keywords have 1 to 79 characters and both parts are Latin-1",
+            keyword
+        )))?;
+        writer.write_chunk(*b"tEXt", &chunk).map_err(|err| Error::Encode(err.to_string()))?;
+    }
+    Ok(writer)
+}
+
+/// Write the iteration `counts` of an image of size `bounds` to `filename`
+/// as raw `u32` values or as a NumPy array.
+pub fn write_counts(filename: &str, counts: &[u32], bounds: (usize, usize)) -> Result<(), Error> {
+    if counts.len() != bounds.0 * bounds.1 {
+        return Err(Error::Validation(
+            format!("{} counts don't match image size {:?}", counts.len(), bounds)
+        ));
+    }
+
+    let header = match Format::from_filename(filename)? {
+        Format::Npy => npy_header(bounds),
+        Format::Raw => vec![],
+        format => return Err(Error::Validation(
+            format!("{:?} files hold pixels, not iteration counts", format)
+        ))
+    };
+
+    let mut output = BufWriter::new(File::create(filename)?);
+    output.write_all(&header)?;
+    for count in counts {
+        output.write_all(&count.to_le_bytes())?;
+    }
+    output.flush()?;
+    Ok(())
+}
+
+/// Header of a version 1.0 `.npy` file holding a C-ordered array of
+/// little-endian `u32` of shape `(height, width)`.
+fn npy_header(bounds: (usize, usize)) -> Vec<u8> {
+    let mut dict = format!("{{'descr': '<u4', 'fortran_order': False, 'shape': ({}, {}), }}",
+                           bounds.1, bounds.0);
+    // Magic (6 bytes), version (2), header length (2), then the dictionary
+    // padded with spaces and ended by a newline to a multiple of 64 bytes.
+    let unpadded = 10 + dict.len() + 1;
+    dict.push_str(&" ".repeat((64 - unpadded % 64) % 64));
+    dict.push('\n');
+
+    let mut header = b"\x93NUMPY\x01\x00".to_vec();
+    header.extend_from_slice(&(dict.len() as u16).to_le_bytes());
+    header.extend_from_slice(dict.as_bytes());
+    header
+}
+
+/// Write a binary PGM for grayscale pixels and a binary PPM otherwise,
+/// dropping the alpha channel which neither supports.
+fn write_pnm(filename: &str, pixels: &[u8], bounds: (usize, usize), channels: usize) -> Result<(), Error> {
+    let mut output = BufWriter::new(File::create(filename)?);
+    let magic = if channels == 1 { "P5" } else { "P6" };
+    write!(output, "{}\n{} {}\n255\n", magic, bounds.0, bounds.1)?;
+    if channels == 4 {
+        for pixel in pixels.chunks(4) {
+            output.write_all(&pixel[..3])?;
+        }
+    } else {
+        output.write_all(pixels)?;
+    }
+    output.flush()?;
+    Ok(())
+}
+
+/// Number of channels of a buffer of `len` components for an image of size
+/// `bounds`: 1, 3 or 4.
+fn channels(len: usize, bounds: (usize, usize)) -> Result<usize, Error> {
+    let n_pixels = bounds.0 * bounds.1;
+    match len / n_pixels.max(1) {
+        channels @ (1 | 3 | 4) if len == channels * n_pixels => Ok(channels),
+        _ => Err(Error::Validation(
+            format!("buffer of {} components doesn't match image size {:?}", len, bounds)
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    fn temp_file(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("mandelbrot-output-{}-{}", std::process::id(), name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[rstest]
+    #[case("out.png", Format::Png)]
+    #[case("out", Format::Png)]
+    #[case("dir/out.TIFF", Format::Tiff)]
+    #[case("out.pgm", Format::Pnm)]
+    #[case("out.raw", Format::Raw)]
+    #[case("out.npy", Format::Npy)]
+    #[case("zoom.gif", Format::Gif)]
+    fn test_format_from_filename(#[case] filename: &str, #[case] format: Format) {
+        assert_eq!(Format::from_filename(filename).unwrap(), format);
+    }
+
+    #[rstest]
+    #[case("out.jpg")]
+    #[case("out.BMP")]
+    #[case("out.png.txt")]
+    fn test_format_from_filename_unknown(#[case] filename: &str) {
+        let error = Format::from_filename(filename).unwrap_err();
+
+        assert!(matches!(error, Error::Validation(_)));
+        assert!(error.to_string().ends_with(EXTENSIONS), "{}", error);
+    }
+
+    #[test]
+    fn test_npy_header() {
+        let header = npy_header((640, 480));
+
+        assert_eq!(header.len() % 64, 0);
+        assert_eq!(&header[..8], b"\x93NUMPY\x01\x00");
+        let dict = String::from_utf8_lossy(&header[10..]);
+        assert!(dict.starts_with("{'descr': '<u4', 'fortran_order': False, 'shape': (480, 640), }"));
+        assert!(dict.ends_with(" \n"));
+    }
+
+    #[test]
+    fn test_write_counts() {
+        let filename = temp_file("counts.raw");
+
+        write_counts(&filename, &[1, 256, 70000], (3, 1)).unwrap();
+
+        let bytes = std::fs::read(&filename).unwrap();
+        std::fs::remove_file(&filename).unwrap();
+        assert_eq!(bytes, vec![1, 0, 0, 0, 0, 1, 0, 0, 112, 17, 1, 0]);
+        assert!(write_counts(&filename, &[1, 2], (3, 1)).is_err());
+    }
+
+    #[test]
+    fn test_write_pnm() {
+        let filename = temp_file("image.ppm");
+
+        write_image(&filename, &[1, 2, 3, 0, 4, 5, 6, 255], (2, 1), &[]).unwrap();
+
+        let bytes = std::fs::read(&filename).unwrap();
+        std::fs::remove_file(&filename).unwrap();
+        assert_eq!(bytes, b"P6\n2 1\n255\n\x01\x02\x03\x04\x05\x06".to_vec());
+    }
+
+    #[test]
+    fn test_write_image16_round_trip() {
+        let pixels = vec![0, 1, 256, 65535, 1000, 42];
+
+        for name in &["image16.png", "image16.tiff"] {
+            let filename = temp_file(name);
+
+            write_image16(&filename, &pixels, (3, 2), &[]).unwrap();
+
+            let image = image::open(&filename).unwrap().into_luma16();
+            std::fs::remove_file(&filename).unwrap();
+            assert_eq!(image.into_raw(), pixels);
+        }
+    }
+
+    #[test]
+    fn test_write_image_bands() {
+        let pixels: Vec<u8> = (0..5 * 3 * 3).map(|i| i as u8).collect();
+        let bands = || pixels.chunks(2 * 5 * 3).map(|band| Ok(band.to_vec()));
+
+        for name in &["bands.png", "bands.ppm"] {
+            let filename = temp_file(name);
+
+            write_image_bands(&filename, (5, 3), 3, bands(), &[]).unwrap();
+
+            let image = image::open(&filename).unwrap().into_rgb8();
+            std::fs::remove_file(&filename).unwrap();
+            assert_eq!(image.into_raw(), pixels);
+        }
+        assert!(write_image_bands(&temp_file("short.png"), (5, 4), 3, bands(), &[]).is_err());
+        assert!(write_image_bands(&temp_file("bands.tif"), (5, 3), 3, bands(), &[]).is_err());
+    }
+
+    #[test]
+    fn test_png_text_round_trip() {
+        let text = vec![
+            ("mandelbrot:limit".to_string(), "500".to_string()),
+            ("Comment".to_string(), "rotated by 10\u{b0}".to_string()),
+        ];
+        let (filename, filename16, bands) = (temp_file("text.png"), temp_file("text16.png"), temp_file("bands.png"));
+
+        write_image(&filename, &[0, 128, 255, 64], (2, 2), &text).unwrap();
+        write_image16(&filename16, &[0, 1, 2, 3], (2, 2), &text).unwrap();
+        write_image_bands(&bands, (2, 2), 1, vec![Ok(vec![0; 4])], &text).unwrap();
+
+        for filename in &[&filename, &filename16, &bands] {
+            assert_eq!(read_png_text(filename).unwrap(), text);
+            assert_eq!(image::open(filename).unwrap().into_luma8().dimensions(), (2, 2));
+            std::fs::remove_file(filename).unwrap();
+        }
+        let empty_keyword = vec![("".to_string(), "text".to_string())];
+        assert!(matches!(write_image(&filename, &[0; 4], (2, 2), &empty_keyword), Err(Error::Validation(_))));
+        let not_latin1 = vec![("Comment".to_string(), "\u{1f600}".to_string())];
+        assert!(matches!(write_image(&filename, &[0; 4], (2, 2), &not_latin1), Err(Error::Validation(_))));
+        std::fs::remove_file(&filename).unwrap();
+    }
+
+    #[test]
+    fn test_read_png_text_rejects_other_files() {
+        let filename = temp_file("text.ppm");
+        write_image(&filename, &[0; 4], (2, 2), &[]).unwrap();
+
+        let result = read_png_text(&filename);
+
+        std::fs::remove_file(&filename).unwrap();
+        assert!(matches!(result, Err(Error::Parse(_))));
+    }
+
+    #[test]
+    fn test_write_image_rejects_bad_buffers() {
+        assert!(write_image(&temp_file("bad.png"), &[0; 5], (2, 1), &[]).is_err());
+        assert!(write_image16(&temp_file("bad.ppm"), &[0; 2], (2, 1), &[]).is_err());
+    }
+}
@@ -0,0 +1,407 @@
+//! Color palettes turning escape values into RGB(A) pixels.
+use std::fmt;
+use std::str::FromStr;
+
+/// A color with red, green and blue components.
+pub type Rgb = [u8; 3];
+
+/// How palette positions are derived from escape values.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Coloring {
+    /// Integer iteration counts, which gives the classic banded look.
+    Banded,
+    /// Normalized (continuous) iteration counts without visible bands.
+    Smooth,
+    /// Iteration counts equalized by their cumulative distribution, so the
+    /// palette is spread evenly over the pixels of the image.
+    Histogram,
+}
+
+impl FromStr for Coloring {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "banded" => Ok(Coloring::Banded),
+            "smooth" => Ok(Coloring::Smooth),
+            "histogram" => Ok(Coloring::Histogram),
+            _ => Err(format!("unknown coloring: {}", s))
+        }
+    }
+}
+
+impl fmt::Display for Coloring {
+    /// Format the coloring the way `Coloring::from_str` reads it.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Coloring::Banded => write!(f, "banded"),
+            Coloring::Smooth => write!(f, "smooth"),
+            Coloring::Histogram => write!(f, "histogram"),
+        }
+    }
+}
+
+/// A sequence of color stops interpolated linearly.
+///
+/// Gradient palettes are stretched over the whole iteration range, while
+/// cyclic palettes repeat every `period` iterations.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Palette {
+    stops: Vec<(f64, Rgb)>,
+    period: Option<f64>,
+}
+
+impl Palette {
+    /// Create a gradient from `(position, color)` stops with positions in
+    /// the `[0, 1]` range.
+    ///
+    /// # Panics
+    ///
+    /// The function panics if `stops` is empty or has a position outside of
+    /// the `[0, 1]` range, like NaN.
+    pub fn gradient(mut stops: Vec<(f64, Rgb)>) -> Palette {
+        assert!(!stops.is_empty());
+        assert!(stops.iter().all(|(position, _)| (0.0..=1.0).contains(position)),
+                "gradient stop outside of [0, 1]");
+        stops.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Palette { stops, period: None }
+    }
+
+    /// Create a palette cycling through `colors` every `period` iterations.
+    ///
+    /// The last color blends back into the first one, so there is no seam
+    /// between the cycles.
+    ///
+    /// # Panics
+    ///
+    /// The function panics if `colors` is empty or `period` isn't positive.
+    pub fn cyclic(colors: Vec<Rgb>, period: f64) -> Palette {
+        assert!(!colors.is_empty());
+        assert!(period > 0.0);
+        let n = colors.len() as f64;
+        let mut stops: Vec<(f64, Rgb)> = colors.iter()
+            .enumerate()
+            .map(|(i, &color)| (i as f64 / n, color))
+            .collect();
+        stops.push((1.0, colors[0]));
+        Palette { stops, period: Some(period) }
+    }
+
+    /// White to black gradient, the same look as the grayscale renderer:
+    /// points escaping right away are white and the slowest ones dark.
+    pub fn grayscale() -> Palette {
+        Palette::gradient(vec![(0.0, [255, 255, 255]), (1.0, [0, 0, 0])])
+    }
+
+    /// Names of the built-in palettes.
+    pub const NAMES: [&'static str; 4] = ["grayscale", "fire", "ocean", "rainbow"];
+
+    /// Name of the built-in palette equal to this one, if any.
+    pub fn name(&self) -> Option<&'static str> {
+        Palette::NAMES.iter()
+            .copied()
+            .find(|name| Palette::by_name(name).as_ref() == Some(self))
+    }
+
+    /// Look up one of the built-in palettes by name.
+    pub fn by_name(name: &str) -> Option<Palette> {
+        match name {
+            "grayscale" => Some(Palette::grayscale()),
+            "fire" => Some(Palette::gradient(vec![
+                (0.0, [0, 0, 0]),
+                (0.3, [128, 0, 0]),
+                (0.6, [255, 128, 0]),
+                (0.85, [255, 255, 0]),
+                (1.0, [255, 255, 255]),
+            ])),
+            "ocean" => Some(Palette::gradient(vec![
+                (0.0, [0, 7, 100]),
+                (0.16, [32, 107, 203]),
+                (0.42, [237, 255, 255]),
+                (0.64, [255, 170, 0]),
+                (0.86, [0, 2, 0]),
+                (1.0, [0, 7, 100]),
+            ])),
+            "rainbow" => Some(Palette::cyclic(vec![
+                [255, 0, 0],
+                [255, 255, 0],
+                [0, 255, 0],
+                [0, 255, 255],
+                [0, 0, 255],
+                [255, 0, 255],
+            ], 32.0)),
+            _ => None
+        }
+    }
+
+    /// Return the color at position `t`, clamped to the `[0, 1]` range.
+    pub fn at(&self, t: f64) -> Rgb {
+        let [r, g, b] = self.exact_at(t);
+        [r.round() as u8, g.round() as u8, b.round() as u8]
+    }
+
+    /// The same as `at` but without rounding the components.
+    fn exact_at(&self, t: f64) -> [f64; 3] {
+        let t = t.clamp(0.0, 1.0);
+        let first = self.stops[0];
+        if t <= first.0 {
+            return first.1.map(f64::from);
+        }
+        for pair in self.stops.windows(2) {
+            let ((t0, c0), (t1, c1)) = (pair[0], pair[1]);
+            if t <= t1 {
+                let k = if t1 > t0 { (t - t0) / (t1 - t0) } else { 1.0 };
+                return lerp(c0, c1, k);
+            }
+        }
+        self.stops[self.stops.len() - 1].1.map(f64::from)
+    }
+
+    /// Map an escape `value` to a palette position, given the iteration
+    /// `limit` used to compute it.
+    fn position(&self, value: f64, limit: f64) -> f64 {
+        match self.period {
+            Some(period) => (value / period).fract(),
+            None => value / limit
+        }
+    }
+}
+
+impl FromStr for Palette {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Palette::by_name(s).ok_or_else(|| format!("unknown palette: {}", s))
+    }
+}
+
+fn lerp(a: Rgb, b: Rgb, k: f64) -> [f64; 3] {
+    let mix = |x: u8, y: u8| x as f64 + (y as f64 - x as f64) * k;
+    [mix(a[0], b[0]), mix(a[1], b[1]), mix(a[2], b[2])]
+}
+
+/// Turns a buffer of escape values into RGB or RGBA pixels.
+#[derive(Debug, Clone)]
+pub struct Colorizer {
+    pub palette: Palette,
+    pub coloring: Coloring,
+    /// Color of the points that didn't escape.
+    pub interior: Rgb,
+    /// Produce RGBA pixels, with the interior of the set left transparent.
+    pub alpha: bool,
+}
+
+impl Colorizer {
+    pub fn new(palette: Palette, coloring: Coloring) -> Colorizer {
+        Colorizer { palette, coloring, interior: [0, 0, 0], alpha: false }
+    }
+
+    /// Number of bytes per pixel in the buffers returned by `colorize`.
+    pub fn channels(&self) -> usize {
+        if self.alpha { 4 } else { 3 }
+    }
+
+    /// Color the escape `values` computed with the given iteration `limit`.
+    ///
+    /// Each value is `None` for the points of the set, or the (possibly
+    /// fractional) number of iterations it took the point to escape.
+    pub fn colorize(&self, values: &[Option<f64>], limit: u32) -> Vec<u8> {
+        self.colorize_with(values, limit, u8::MAX, |color| color.map(|c| c.round() as u8))
+    }
+
+    /// The same as `colorize` but with 16-bit components, interpolating the
+    /// palette without rounding it to 8 bits first.
+    pub fn colorize16(&self, values: &[Option<f64>], limit: u32) -> Vec<u16> {
+        self.colorize_with(values, limit, u16::MAX, |color| color.map(|c| (c * 257.0).round() as u16))
+    }
+
+    /// Color the escape `values`, turning colors with components in
+    /// `[0, 255]` into pixel components with `convert`, and using `opaque`
+    /// as the alpha of escaped points.
+    fn colorize_with<T, F>(&self, values: &[Option<f64>], limit: u32, opaque: T, convert: F) -> Vec<T>
+    where
+        T: Copy + Default,
+        F: Fn([f64; 3]) -> [T; 3],
+    {
+        let channels = self.channels();
+        let mut pixels = vec![T::default(); values.len() * channels];
+
+        let cdf = match self.coloring {
+            Coloring::Histogram => Some(cumulative_histogram(values, limit)),
+            _ => None
+        };
+
+        for (value, pixel) in values.iter().zip(pixels.chunks_mut(channels)) {
+            let (color, opacity) = match value {
+                None => (self.interior.map(f64::from), T::default()),
+                Some(v) => {
+                    let v = match self.coloring {
+                        Coloring::Banded => v.floor(),
+                        _ => *v
+                    };
+                    let t = match &cdf {
+                        Some(cdf) => equalize(cdf, v),
+                        None => self.palette.position(v, limit as f64)
+                    };
+                    (self.palette.exact_at(t), opaque)
+                }
+            };
+            pixel[..3].copy_from_slice(&convert(color));
+            if self.alpha {
+                pixel[3] = opacity;
+            }
+        }
+
+        pixels
+    }
+}
+
+/// Map iteration `counts` to grayscale pixels, one byte per pixel.
+///
+/// Counts are scaled to the iteration `limit`, so that the points escaping
+/// right away are white and the points of the set (counted as `limit`) are
+/// black.
+pub fn grayscale(counts: &[u32], limit: u32) -> Vec<u8> {
+    let limit = limit.max(1) as u64;
+    counts.iter()
+        .map(|&count| match count as u64 {
+            count if count >= limit => 0,
+            count => 255 - (count * 255 / limit) as u8
+        })
+        .collect()
+}
+
+/// The same as `grayscale` but with 16-bit pixels, which keeps more than
+/// 256 shades for iteration limits above 255.
+pub fn grayscale16(counts: &[u32], limit: u32) -> Vec<u16> {
+    let limit = limit.max(1) as u64;
+    counts.iter()
+        .map(|&count| match count as u64 {
+            count if count >= limit => 0,
+            count => 65535 - (count * 65535 / limit) as u16
+        })
+        .collect()
+}
+
+/// Normalized cumulative histogram of the integer parts of escaped values:
+/// `cdf[i]` is the fraction of escaped points with fewer than `i` iterations.
+fn cumulative_histogram(values: &[Option<f64>], limit: u32) -> Vec<f64> {
+    let limit = limit as usize;
+    let mut histogram = vec![0usize; limit + 1];
+    for v in values.iter().flatten() {
+        histogram[(v.max(0.0) as usize).min(limit)] += 1;
+    }
+
+    let total = histogram.iter().sum::<usize>().max(1) as f64;
+    let mut cdf = Vec::with_capacity(limit + 2);
+    let mut running = 0;
+    cdf.push(0.0);
+    for count in histogram {
+        running += count;
+        cdf.push(running as f64 / total);
+    }
+    cdf
+}
+
+/// Palette position of value `v`, interpolating the cumulative histogram
+/// between the neighbouring integer counts to keep smooth values smooth.
+fn equalize(cdf: &[f64], v: f64) -> f64 {
+    let v = v.max(0.0);
+    let i = (v as usize).min(cdf.len() - 2);
+    let k = (v - i as f64).min(1.0);
+    cdf[i] + (cdf[i + 1] - cdf[i]) * k
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(0.0, [0, 0, 0])]
+    #[case(0.25, [64, 32, 0])]
+    #[case(0.5, [128, 64, 0])]
+    #[case(1.0, [255, 128, 0])]
+    #[case(2.0, [255, 128, 0])]
+    fn test_gradient_at(#[case] t: f64, #[case] expected: Rgb) {
+        let palette = Palette::gradient(vec![(1.0, [255, 128, 0]), (0.0, [0, 0, 0])]);
+
+        assert_eq!(palette.at(t), expected);
+    }
+
+    #[rstest]
+    #[case(f64::NAN)]
+    #[case(1.5)]
+    #[should_panic(expected = "gradient stop outside of [0, 1]")]
+    fn test_gradient_invalid_stop(#[case] position: f64) {
+        Palette::gradient(vec![(0.0, [0, 0, 0]), (position, [255, 255, 255])]);
+    }
+
+    #[test]
+    fn test_grayscale_palette() {
+        let palette = Palette::grayscale();
+
+        assert_eq!(palette.at(0.0), [255, 255, 255]);
+        assert_eq!(palette.at(1.0), [0, 0, 0]);
+    }
+
+    #[test]
+    fn test_cyclic_palette_repeats() {
+        let palette = Palette::cyclic(vec![[0, 0, 0], [200, 200, 200]], 10.0);
+
+        assert_eq!(palette.position(2.5, 255.0), palette.position(12.5, 255.0));
+        assert_eq!(palette.at(palette.position(5.0, 255.0)), [200, 200, 200]);
+        assert_eq!(palette.at(palette.position(10.0, 255.0)), [0, 0, 0]);
+    }
+
+    #[test]
+    fn test_palette_names() {
+        for name in &Palette::NAMES {
+            assert_eq!(Palette::by_name(name).unwrap().name(), Some(*name));
+        }
+        assert_eq!(Palette::cyclic(vec![[0, 0, 0]], 10.0).name(), None);
+    }
+
+    #[test]
+    fn test_colorize_rgba() {
+        let mut colorizer = Colorizer::new(Palette::grayscale(), Coloring::Banded);
+        colorizer.alpha = true;
+
+        let pixels = colorizer.colorize(&[None, Some(0.0), Some(10.7)], 10);
+
+        assert_eq!(pixels, vec![0, 0, 0, 0, 255, 255, 255, 255, 0, 0, 0, 255]);
+    }
+
+    #[rstest]
+    #[case(255, vec![0, 1, 254, 255], vec![255, 254, 1, 0])]
+    #[case(1000, vec![0, 500, 999, 1000], vec![255, 128, 1, 0])]
+    fn test_grayscale(#[case] limit: u32, #[case] counts: Vec<u32>, #[case] expected: Vec<u8>) {
+        assert_eq!(grayscale(&counts, limit), expected);
+    }
+
+    #[test]
+    fn test_colorize16() {
+        let mut colorizer = Colorizer::new(Palette::grayscale(), Coloring::Smooth);
+        colorizer.alpha = true;
+
+        let pixels = colorizer.colorize16(&[None, Some(0.0), Some(127.5)], 255);
+
+        assert_eq!(pixels, vec![0, 0, 0, 0, 65535, 65535, 65535, 65535, 32768, 32768, 32768, 65535]);
+    }
+
+    #[rstest]
+    #[case(255, vec![0, 1, 254, 255], vec![65535, 65278, 257, 0])]
+    #[case(1000, vec![0, 500, 999, 1000], vec![65535, 32768, 66, 0])]
+    fn test_grayscale16(#[case] limit: u32, #[case] counts: Vec<u32>, #[case] expected: Vec<u16>) {
+        assert_eq!(grayscale16(&counts, limit), expected);
+    }
+
+    #[test]
+    fn test_colorize_histogram() {
+        let colorizer = Colorizer::new(Palette::grayscale(), Coloring::Histogram);
+
+        let pixels = colorizer.colorize(&[Some(1.0), Some(100.0), None], 255);
+
+        assert_eq!(pixels, vec![255, 255, 255, 128, 128, 128, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,426 @@
+//! Previews rendered in the terminal.
+//!
+//! Each character cell shows two pixels stacked with the upper half block
+//! `▀`: its foreground color paints the upper pixel and its background color
+//! the lower one, both as 24-bit ANSI colors. Cells being about twice as tall
+//! as they are wide, the pixels come out roughly square.
+//!
+//! `run` renders a view at the size of the terminal and lets it be explored
+//! with the keyboard, rendering it again after every key, and returns the
+//! last view so that it can be rendered in full.
+use super::error::Error;
+use super::palette::Colorizer;
+use super::renderer::{self, Options};
+use num::Complex;
+use std::fmt::Write;
+
+/// Fraction of the view's width or height a pan moves it by.
+const PAN_STEP: f64 = 0.25;
+
+/// Factor a zoom step divides or multiplies the view's width by.
+const ZOOM_STEP: f64 = 2.0;
+
+/// Keys of the status line.
+const HELP: &str = "arrows/hjkl: pan  +/-: zoom  [/]: limit  q: quit";
+
+/// A command given with the keyboard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Left,
+    Right,
+    Up,
+    Down,
+    ZoomIn,
+    ZoomOut,
+    /// Double the iteration limit.
+    MoreIterations,
+    /// Halve the iteration limit.
+    FewerIterations,
+    Quit,
+}
+
+impl Key {
+    /// Parse the keys of `bytes`, as read from a terminal in raw mode,
+    /// skipping the ones without a command, and return them with the number
+    /// of bytes parsed.
+    ///
+    /// An escape sequence cut at the end of `bytes`, or a lone `ESC` there,
+    /// is left unparsed since the rest may come with the next read, unless
+    /// `at_end` says that nothing follows: a lone `ESC` then quits.
+    pub fn parse(bytes: &[u8], at_end: bool) -> (Vec<Key>, usize) {
+        let mut keys = Vec::new();
+        let mut rest = bytes;
+        while let Some((&byte, tail)) = rest.split_first() {
+            let start = rest;
+            rest = tail;
+            let key = match byte {
+                // Arrow keys send `ESC [ A` to `ESC [ D`, or `ESC O A` to
+                // `ESC O D` in application mode. Other sequences are skipped
+                // up to their final byte.
+                0x1b => match rest {
+                    [b'[' | b'O', sequence @ ..] => {
+                        let end = match sequence.iter().position(|byte| (0x40..=0x7e).contains(byte)) {
+                            Some(end) => end + 1,
+                            None if at_end => sequence.len(),
+                            None => {
+                                rest = start;
+                                break;
+                            }
+                        };
+                        rest = &sequence[end..];
+                        match &sequence[..end] {
+                            b"A" => Some(Key::Up),
+                            b"B" => Some(Key::Down),
+                            b"C" => Some(Key::Right),
+                            b"D" => Some(Key::Left),
+                            _ => None
+                        }
+                    }
+                    [] if at_end => Some(Key::Quit),
+                    [] => {
+                        rest = start;
+                        break;
+                    }
+                    _ => None
+                },
+                b'h' | b'a' => Some(Key::Left),
+                b'l' | b'd' => Some(Key::Right),
+                b'k' | b'w' => Some(Key::Up),
+                b'j' | b's' => Some(Key::Down),
+                b'+' | b'=' => Some(Key::ZoomIn),
+                b'-' | b'_' => Some(Key::ZoomOut),
+                b']' => Some(Key::MoreIterations),
+                b'[' => Some(Key::FewerIterations),
+                // Ctrl-C and Ctrl-D, which raw mode delivers as bytes.
+                b'q' | 0x03 | 0x04 => Some(Key::Quit),
+                _ => None
+            };
+            keys.extend(key);
+        }
+        (keys, bytes.len() - rest.len())
+    }
+}
+
+/// The part of the complex plane shown by the preview, whose height follows
+/// from the size of the terminal.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Viewport {
+    pub center: Complex<f64>,
+    pub width: f64,
+    pub limit: u32,
+}
+
+impl Viewport {
+    /// Move, zoom or change the limit according to `key`, for an image of
+    /// size `bounds`. Return `false` for `Key::Quit`.
+    pub fn apply(&mut self, key: Key, bounds: (usize, usize)) -> bool {
+        let height = self.height(bounds);
+        match key {
+            Key::Left => self.center.re -= self.width * PAN_STEP,
+            Key::Right => self.center.re += self.width * PAN_STEP,
+            Key::Up => self.center.im += height * PAN_STEP,
+            Key::Down => self.center.im -= height * PAN_STEP,
+            Key::ZoomIn => self.width /= ZOOM_STEP,
+            Key::ZoomOut => self.width *= ZOOM_STEP,
+            Key::MoreIterations => self.limit = self.limit.saturating_mul(2),
+            Key::FewerIterations => self.limit = (self.limit / 2).max(1),
+            Key::Quit => return false,
+        }
+        true
+    }
+
+    /// Upper-left and lower-right corners of the viewport shown in an image
+    /// of size `bounds`.
+    pub fn corners(&self, bounds: (usize, usize)) -> (Complex<f64>, Complex<f64>) {
+        let half_size = Complex { re: self.width / 2.0, im: -self.height(bounds) / 2.0 };
+        (self.center - half_size, self.center + half_size)
+    }
+
+    /// Command line options rendering the viewport, fitted to the size of
+    /// the image given with them.
+    pub fn to_args(&self) -> String {
+        format!("--center={},{} --width {} --limit {}", self.center.re, self.center.im, self.width, self.limit)
+    }
+
+    fn height(&self, bounds: (usize, usize)) -> f64 {
+        self.width * bounds.1 as f64 / bounds.0.max(1) as f64
+    }
+}
+
+/// Render the `viewport` as an image of size `bounds` with the rendering
+/// `options` other than the limit, the way `renderer::render_image` does.
+pub fn render(
+    viewport: &Viewport,
+    bounds: (usize, usize),
+    options: &Options,
+    colorizer: Option<&Colorizer>,
+    n_threads: usize,
+) -> Vec<u8> {
+    let (upper_left, lower_right) = viewport.corners(bounds);
+    let options = Options { limit: viewport.limit, ..*options };
+    renderer::render_image(bounds, upper_left, lower_right, &options, colorizer, n_threads)
+}
+
+/// Turn the grayscale, RGB or RGBA `pixels` of an image of size `bounds`
+/// into lines of half blocks, each ending with a reset of the colors.
+///
+/// Colors are only written when they change. The lower half of the last
+/// line of an image of odd height keeps the terminal's background.
+pub fn frame(pixels: &[u8], bounds: (usize, usize)) -> Vec<String> {
+    let channels = pixels.len() / (bounds.0 * bounds.1).max(1);
+    let rgb = |column: usize, row: usize| -> [u8; 3] {
+        let pixel = &pixels[(row * bounds.0 + column) * channels..];
+        if channels < 3 { [pixel[0]; 3] } else { [pixel[0], pixel[1], pixel[2]] }
+    };
+
+    (0..bounds.1).step_by(2)
+        .map(|row| {
+            let mut line = String::new();
+            let (mut foreground, mut background) = (None, None);
+            for column in 0..bounds.0 {
+                let upper = rgb(column, row);
+                if foreground != Some(upper) {
+                    write!(line, "\x1b[38;2;{};{};{}m", upper[0], upper[1], upper[2]).unwrap();
+                    foreground = Some(upper);
+                }
+                if row + 1 < bounds.1 {
+                    let lower = rgb(column, row + 1);
+                    if background != Some(lower) {
+                        write!(line, "\x1b[48;2;{};{};{}m", lower[0], lower[1], lower[2]).unwrap();
+                        background = Some(lower);
+                    }
+                }
+                line.push('▀');
+            }
+            line.push_str("\x1b[0m");
+            line
+        })
+        .collect()
+}
+
+/// How long to wait for the rest of an escape sequence after an `ESC`,
+/// before taking it for the key itself.
+#[cfg(unix)]
+const ESCAPE_DELAY: std::time::Duration = std::time::Duration::from_millis(50);
+
+/// Run the preview of `viewport` in the terminal until `Key::Quit`, with
+/// the rendering `options` and `colorizer`, and return the last viewport.
+///
+/// The terminal is restored when the preview ends, even by an error or a
+/// panic.
+#[cfg(unix)]
+pub fn run(
+    mut viewport: Viewport,
+    options: &Options,
+    colorizer: Option<&Colorizer>,
+    n_threads: usize,
+) -> Result<Viewport, Error> {
+    use std::io::{self, Read, Write};
+
+    let _screen = terminal::Screen::enter()?;
+    let mut stdout = io::stdout();
+    let mut input = [0; 64];
+    let mut pending = Vec::new();
+    let mut redraw = true;
+    loop {
+        // One line is left for the status.
+        let (columns, rows) = terminal::size();
+        let bounds = (columns.max(1), rows.saturating_sub(1).max(1) * 2);
+
+        if redraw {
+            let pixels = render(&viewport, bounds, options, colorizer, n_threads);
+            let mut screen = String::from("\x1b[H");
+            for line in frame(&pixels, bounds) {
+                screen.push_str(&line);
+                screen.push_str("\r\n");
+            }
+            let status = format!("{}  |  {}", viewport.to_args(), HELP);
+            screen.extend(status.chars().take(columns));
+            screen.push_str("\x1b[K");
+            stdout.write_all(screen.as_bytes())?;
+            stdout.flush()?;
+        }
+
+        // Apply all the keys pressed while rendering at once.
+        let n = io::stdin().read(&mut input)?;
+        if n == 0 {
+            return Ok(viewport);
+        }
+        pending.extend_from_slice(&input[..n]);
+        let (mut keys, mut parsed) = Key::parse(&pending, false);
+        if parsed < pending.len() && !terminal::wait_for_input(ESCAPE_DELAY)? {
+            (keys, parsed) = Key::parse(&pending, true);
+        }
+        pending.drain(..parsed);
+
+        redraw = !keys.is_empty();
+        for key in keys {
+            if !viewport.apply(key, bounds) {
+                return Ok(viewport);
+            }
+        }
+    }
+}
+
+/// The same as the Unix `run`, which other systems don't support.
+#[cfg(not(unix))]
+pub fn run(
+    _viewport: Viewport,
+    _options: &Options,
+    _colorizer: Option<&Colorizer>,
+    _n_threads: usize,
+) -> Result<Viewport, Error> {
+    Err(Error::Validation("the preview needs a Unix terminal".to_string()))
+}
+
+#[cfg(unix)]
+mod terminal {
+    use std::io::{self, Write};
+    use std::time::Duration;
+
+    /// Terminal size used when it can't be queried.
+    const DEFAULT_SIZE: (usize, usize) = (80, 24);
+
+    /// The alternate screen of the terminal, with the cursor hidden and the
+    /// standard input in raw mode, in which keys are read one by one without
+    /// being echoed, until dropped.
+    pub struct Screen {
+        original: libc::termios,
+    }
+
+    impl Screen {
+        pub fn enter() -> io::Result<Screen> {
+            // Safety: `termios` is a plain C struct that `tcgetattr` fills
+            // in, and both calls only read or write the struct given.
+            let original = unsafe {
+                let mut termios: libc::termios = std::mem::zeroed();
+                if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                let original = termios;
+                termios.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+                termios.c_cc[libc::VMIN] = 1;
+                termios.c_cc[libc::VTIME] = 0;
+                if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                original
+            };
+            // From now on, dropping the screen restores the terminal.
+            let screen = Screen { original };
+            let mut stdout = io::stdout();
+            stdout.write_all(b"\x1b[?1049h\x1b[?25l")?;
+            stdout.flush()?;
+            Ok(screen)
+        }
+    }
+
+    impl Drop for Screen {
+        fn drop(&mut self) {
+            // Show the cursor and leave the alternate screen, ignoring
+            // errors since there's nothing left to do about them.
+            let mut stdout = io::stdout();
+            let _ = stdout.write_all(b"\x1b[?25h\x1b[?1049l").and_then(|_| stdout.flush());
+            // Safety: restores the settings read by `enter`.
+            unsafe {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+            }
+        }
+    }
+
+    /// Wait at most `timeout` for the standard input to have bytes to read,
+    /// returning whether it has.
+    pub fn wait_for_input(timeout: Duration) -> io::Result<bool> {
+        let mut poll = libc::pollfd { fd: libc::STDIN_FILENO, events: libc::POLLIN, revents: 0 };
+        // Safety: `poll` only reads and writes the one struct given.
+        match unsafe { libc::poll(&mut poll, 1, timeout.as_millis() as libc::c_int) } {
+            -1 => Err(io::Error::last_os_error()),
+            n => Ok(n > 0),
+        }
+    }
+
+    /// Number of columns and rows of the terminal on the standard output,
+    /// falling back on `COLUMNS` and `LINES`, then on 80 by 24.
+    pub fn size() -> (usize, usize) {
+        // Safety: `TIOCGWINSZ` only writes the `winsize` struct given.
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0 && size.ws_col > 0 {
+            return (size.ws_col as usize, size.ws_row as usize);
+        }
+        let variable = |name: &str| std::env::var(name).ok().and_then(|value| value.parse().ok());
+        (variable("COLUMNS").unwrap_or(DEFAULT_SIZE.0), variable("LINES").unwrap_or(DEFAULT_SIZE.1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::palette::{Coloring, Palette};
+    use rstest::rstest;
+
+    const VIEWPORT: Viewport = Viewport { center: Complex { re: -0.5, im: 0.0 }, width: 4.0, limit: 100 };
+
+    #[rstest]
+    #[case(b"hjkl", vec![Key::Left, Key::Down, Key::Up, Key::Right])]
+    #[case(b"\x1b[A\x1b[B\x1bOC\x1b[D", vec![Key::Up, Key::Down, Key::Right, Key::Left])]
+    #[case(b"+-=_][", vec![Key::ZoomIn, Key::ZoomOut, Key::ZoomIn, Key::ZoomOut,
+                           Key::MoreIterations, Key::FewerIterations])]
+    #[case(b"x\x1b[Zq\x1b[1;5A", vec![Key::Quit])]
+    #[case(b"\x1b", vec![Key::Quit])]
+    #[case(b"\x03", vec![Key::Quit])]
+    #[case(b"k\x1b[1;5", vec![Key::Up])]
+    fn test_parse_keys(#[case] bytes: &[u8], #[case] expected: Vec<Key>) {
+        assert_eq!(Key::parse(bytes, true), (expected, bytes.len()));
+    }
+
+    #[rstest]
+    #[case(b"h\x1b", vec![Key::Left], 1)]
+    #[case(b"\x1b[", vec![], 0)]
+    #[case(b"j\x1bO", vec![Key::Down], 1)]
+    #[case(b"\x1b[1;5Aq", vec![Key::Quit], 7)]
+    fn test_parse_keys_cut(#[case] bytes: &[u8], #[case] expected: Vec<Key>, #[case] parsed: usize) {
+        assert_eq!(Key::parse(bytes, false), (expected, parsed));
+    }
+
+    #[test]
+    fn test_apply() {
+        let bounds = (80, 40);
+        let mut viewport = VIEWPORT;
+
+        assert!(viewport.apply(Key::Right, bounds));
+        assert!(viewport.apply(Key::Up, bounds));
+        assert!(viewport.apply(Key::ZoomIn, bounds));
+        assert!(viewport.apply(Key::MoreIterations, bounds));
+        assert!(!viewport.apply(Key::Quit, bounds));
+
+        assert_eq!(viewport, Viewport { center: Complex { re: 0.5, im: 0.5 }, width: 2.0, limit: 200 });
+        assert_eq!(viewport.corners(bounds), (Complex { re: -0.5, im: 1.0 }, Complex { re: 1.5, im: 0.0 }));
+        assert_eq!(viewport.to_args(), "--center=0.5,0.5 --width 2 --limit 200");
+        viewport.limit = 1;
+        viewport.apply(Key::FewerIterations, bounds);
+        assert_eq!(viewport.limit, 1);
+    }
+
+    #[test]
+    fn test_frame() {
+        let pixels = [10, 10, 20, 30, 40, 50];
+
+        let lines = frame(&pixels, (2, 3));
+
+        assert_eq!(lines, vec![
+            "\x1b[38;2;10;10;10m\x1b[48;2;20;20;20m▀\x1b[48;2;30;30;30m▀\x1b[0m",
+            "\x1b[38;2;40;40;40m▀\x1b[38;2;50;50;50m▀\x1b[0m",
+        ]);
+    }
+
+    #[test]
+    fn test_render() {
+        let (upper_left, lower_right) = VIEWPORT.corners((8, 6));
+        let options = Options { limit: 10, samples: 2, ..Options::default() };
+        let expected = renderer::render_image((8, 6), upper_left, lower_right, &Options { limit: 100, ..options }, None, 1);
+
+        assert_eq!(render(&VIEWPORT, (8, 6), &options, None, 2), expected);
+        let colorizer = Colorizer::new(Palette::by_name("fire").unwrap(), Coloring::Smooth);
+        assert_eq!(render(&VIEWPORT, (8, 6), &options, Some(&colorizer), 2).len(), 8 * 6 * 3);
+    }
+}
@@ -0,0 +1,187 @@
+//! Progressive rendering, showing a coarse image early and refining it.
+//!
+//! The first pass computes one point per block of `COARSEST_BLOCK` by
+//! `COARSEST_BLOCK` samples and fills the whole block with its value. Every
+//! following pass halves the block size and only computes the points the
+//! previous passes haven't, so the last pass, with blocks of one sample,
+//! completes the image with no more work than a plain render.
+use super::palette::{self, Colorizer};
+use super::renderer::{self, Options};
+use super::scheduler::{self, Cancelled, Control, TILE_SIZE};
+use log::debug;
+use num::Complex;
+
+/// Size in samples of the blocks of the first pass, a power of two.
+pub const COARSEST_BLOCK: usize = 16;
+
+/// Render an image like `renderer::render_image`, calling `on_pass` with
+/// the block size and the pixels of the image after each pass.
+///
+/// Progress is reported to `control` pass after pass, each pass counting
+/// its tiles from zero. Once `control` is cancelled the render stops at the
+/// end of the current tiles and no further pass is shown.
+#[allow(clippy::too_many_arguments)]
+pub fn render_progressive<F>(
+    bounds: (usize, usize),
+    upper_left: Complex<f64>,
+    lower_right: Complex<f64>,
+    options: &Options,
+    colorizer: Option<&Colorizer>,
+    n_threads: usize,
+    control: &mut Control,
+    mut on_pass: F,
+) -> Result<Vec<u8>, Cancelled>
+where
+    F: FnMut(usize, &[u8]),
+{
+    let samples = options.samples.max(1);
+    let sample_bounds = (bounds.0 * samples, bounds.1 * samples);
+    let point = |sample| renderer::sample_point(sample_bounds, sample, upper_left, lower_right, options);
+
+    let mut counts = vec![0; sample_bounds.0 * sample_bounds.1];
+    let mut values = vec![None; sample_bounds.0 * sample_bounds.1];
+    let mut pixels = Vec::new();
+
+    let mut block = COARSEST_BLOCK;
+    let mut first = true;
+    while block >= 1 {
+        debug!("progressive pass: block={}", block);
+
+        let sample_pixels = match colorizer {
+            None => {
+                refine(&mut counts, sample_bounds, n_threads, control, block, first, point,
+                       |points| renderer::escape_counts(points, options))?;
+                palette::grayscale(&counts, options.limit)
+            }
+            Some(colorizer) => {
+                refine(&mut values, sample_bounds, n_threads, control, block, first, point,
+                       |points| renderer::escape_values(points, options))?;
+                colorizer.colorize(&values, options.limit)
+            }
+        };
+        pixels = renderer::downsample(&sample_pixels, bounds, samples);
+        on_pass(block, &pixels);
+
+        block /= 2;
+        first = false;
+    }
+
+    Ok(pixels)
+}
+
+/// Compute the points at the corners of blocks of `block` samples not
+/// computed by the pass with twice as large blocks, unless this is the
+/// `first` pass, and fill the blocks of `buffer` with their values.
+#[allow(clippy::too_many_arguments)]
+fn refine<T, P, C>(
+    buffer: &mut [T],
+    bounds: (usize, usize),
+    n_threads: usize,
+    control: &mut Control,
+    block: usize,
+    first: bool,
+    point: P,
+    compute: C,
+) -> Result<(), Cancelled>
+where
+    T: Clone + Send + Sync,
+    P: Fn((usize, usize)) -> Complex<f64> + Sync,
+    C: Fn(&[Complex<f64>]) -> Vec<T> + Sync,
+{
+    // Blocks must not straddle tiles.
+    assert_eq!(TILE_SIZE % block, 0);
+
+    let previous = if first { Vec::new() } else { buffer.to_vec() };
+    let is_new = |(column, row): (usize, usize)| {
+        first || column % (2 * block) != 0 || row % (2 * block) != 0
+    };
+
+    scheduler::render_tiles_controlled(buffer, bounds, n_threads, control, |tile| {
+        let columns = tile.width.div_ceil(block);
+        let corners: Vec<(usize, usize)> = (tile.top..tile.top + tile.height).step_by(block)
+            .flat_map(|row| (tile.left..tile.left + tile.width).step_by(block).map(move |column| (column, row)))
+            .collect();
+
+        let points: Vec<Complex<f64>> = corners.iter().copied().filter(|&c| is_new(c)).map(&point).collect();
+        let mut computed = compute(&points).into_iter();
+        let corner_values: Vec<T> = corners.iter()
+            .map(|&(column, row)| {
+                if is_new((column, row)) {
+                    computed.next().expect("one value per point")
+                } else {
+                    previous[row * bounds.0 + column].clone()
+                }
+            })
+            .collect();
+
+        tile.pixels()
+            .map(|(column, row)| {
+                corner_values[(row - tile.top) / block * columns + (column - tile.left) / block].clone()
+            })
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::palette::{Coloring, Palette};
+    use crate::scheduler::CancelToken;
+
+    const UPPER_LEFT: Complex<f64> = Complex { re: -2.0, im: 1.2 };
+    const LOWER_RIGHT: Complex<f64> = Complex { re: 1.0, im: -1.2 };
+
+    #[test]
+    fn test_progressive_matches_render_image() {
+        let bounds = (150, 70);
+        let colorizer = Colorizer::new(Palette::grayscale(), Coloring::Smooth);
+
+        for colorizer in &[None, Some(&colorizer)] {
+            let options = Options { samples: 2, ..Default::default() };
+            let mut blocks = Vec::new();
+
+            let pixels = render_progressive(bounds, UPPER_LEFT, LOWER_RIGHT, &options, *colorizer, 3,
+                                            &mut Control::default(), |block, _| blocks.push(block))
+                .unwrap();
+
+            assert_eq!(blocks, vec![16, 8, 4, 2, 1]);
+            assert_eq!(pixels, renderer::render_image(bounds, UPPER_LEFT, LOWER_RIGHT, &options, *colorizer, 3));
+        }
+    }
+
+    #[test]
+    fn test_coarse_pass_fills_blocks() {
+        let bounds = (64, 64);
+        let mut coarse = Vec::new();
+
+        render_progressive(bounds, UPPER_LEFT, LOWER_RIGHT, &Options::default(), None, 1,
+                           &mut Control::default(), |block, pixels| {
+                               if block == COARSEST_BLOCK {
+                                   coarse = pixels.to_vec();
+                               }
+                           })
+            .unwrap();
+
+        for row in 0..bounds.1 {
+            for column in 0..bounds.0 {
+                let corner = (row - row % COARSEST_BLOCK) * bounds.0 + column - column % COARSEST_BLOCK;
+                assert_eq!(coarse[row * bounds.0 + column], coarse[corner]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_progressive_cancelled() {
+        let cancel = CancelToken::new();
+        let mut passes = 0;
+
+        let result = render_progressive((64, 64), UPPER_LEFT, LOWER_RIGHT, &Options::default(), None, 1,
+                                        &mut Control::new(cancel.clone()), |_, _| {
+                                            passes += 1;
+                                            cancel.cancel();
+                                        });
+
+        assert_eq!(result, Err(Cancelled));
+        assert_eq!(passes, 1);
+    }
+}
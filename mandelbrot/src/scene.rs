@@ -0,0 +1,518 @@
+//! Scene files describing renders, so that they can be reproduced.
+//!
+//! A scene is written in TOML or JSON, chosen by the file extension. Values
+//! use the formats of the command line options and are checked with the
+//! same `parser` functions:
+//!
+//! ```toml
+//! fractal = "julia:-0.8,0.156"
+//! size = "800x600"
+//! limit = 500
+//! palette = "fire"
+//! output = "julia.png"
+//!
+//! [view]
+//! center = "0,0"
+//! zoom = 1.5
+//! ```
+//!
+//! The view is given either by `upper_left` and `lower_right` or by `center`
+//! and one of `zoom` and `width`, and always fitted to the aspect ratio of
+//! the image. Relative output paths are relative to the scene file.
+//!
+//! Scenes are also saved in the text chunks of the PNG images they render,
+//! one `mandelbrot:<field>` chunk per parameter, so that an image can be
+//! rendered again from the image itself.
+use super::cache;
+use super::deep::BigComplex;
+use super::fractal::Fractal;
+use super::output::Format;
+use super::palette::{Colorizer, Coloring, Palette};
+use super::parser;
+use super::renderer::Options;
+use super::shading::Shading;
+use super::view::View;
+use super::error::{self, Error};
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Extensions of the files read as scenes.
+pub const EXTENSIONS: [&str; 2] = ["toml", "json"];
+
+/// Prefix of the keywords of the text chunks holding a scene.
+pub const METADATA_PREFIX: &str = "mandelbrot:";
+
+/// Everything needed to render an image.
+#[derive(Debug, Clone)]
+pub struct Scene {
+    pub bounds: (usize, usize),
+    pub view: View,
+    pub options: Options,
+    /// Palette of the image, plain grayscale without one.
+    pub palette: Option<Palette>,
+    pub coloring: Coloring,
+    pub alpha: bool,
+    pub shading: Option<Shading>,
+    /// Render with arbitrary precision.
+    pub deep: bool,
+    /// Bits per color component, 8 or 16.
+    pub depth: u32,
+    pub output: String,
+}
+
+impl Scene {
+    /// Check that the image fits in memory, that the view isn't inverted,
+    /// and that the settings of the scene work together.
+    pub fn validate(&self) -> Result<(), Error> {
+        error::validate_bounds(self.bounds, self.options.samples)?;
+        self.validate_settings()
+    }
+
+    /// Check the scene like `validate` for a render whose tiles are cached,
+    /// which holds a tile and a band of tiles in memory rather than the
+    /// whole image.
+    pub fn validate_cached(&self) -> Result<(), Error> {
+        error::validate_tiled_bounds(self.bounds, self.options.samples, cache::TILE_PIXELS)?;
+        self.validate_settings()
+    }
+
+    /// Check that the view isn't inverted and that the settings of the
+    /// scene work together.
+    fn validate_settings(&self) -> Result<(), Error> {
+        error::validate_size(self.view.width, self.view.height)?;
+
+        let format = Format::from_filename(&self.output)?;
+        let conflict = if self.deep && self.options.fractal != Fractal::Mandelbrot {
+            Some("deep zooms support the Mandelbrot set only")
+        } else if self.deep && self.options.jitter {
+            Some("jittered samples can't be used with deep zooms")
+        } else if self.depth != 8 && self.depth != 16 {
+            Some("the depth must be 8 or 16 bits")
+        } else if self.shading.is_some() && self.deep {
+            Some("shading can't be used with deep zooms")
+        } else if self.shading.is_some() && (self.depth == 16 || format.is_counts()) {
+            Some("shading only writes 8-bit images")
+        } else if self.shading.is_some() && !self.options.fractal.is_analytic() {
+            Some("shading doesn't support the burning-ship and tricorn fractals")
+        } else {
+            None
+        };
+        match conflict {
+            Some(conflict) => Err(Error::Validation(conflict.to_string())),
+            None => Ok(())
+        }
+    }
+
+    /// The colorizer of the scene's palette, if it has one.
+    pub fn colorizer(&self) -> Option<Colorizer> {
+        self.palette.clone().map(|palette| {
+            let mut colorizer = Colorizer::new(palette, self.coloring);
+            colorizer.alpha = self.alpha;
+            colorizer
+        })
+    }
+
+    /// Parse a scene written in TOML.
+    pub fn from_toml(s: &str) -> Result<Scene, Error> {
+        let file: SceneFile = toml::from_str(s)
+            .map_err(|err| Error::Parse(err.to_string()))?;
+        file.into_scene()
+    }
+
+    /// Parse a scene written in JSON.
+    pub fn from_json(s: &str) -> Result<Scene, Error> {
+        let file: SceneFile = serde_json::from_str(s)
+            .map_err(|err| Error::Parse(err.to_string()))?;
+        file.into_scene()
+    }
+
+    /// The parameters of the scene as `(keyword, text)` pairs for the text
+    /// chunks of a PNG image, along with the version of the crate.
+    ///
+    /// The view is saved by its exact center, with the number of fractional
+    /// bits it's computed with, and by its size, so that `from_metadata`
+    /// gives back the same view without fitting it again.
+    /// Palettes other than the built-in ones are saved as `custom`, which
+    /// can't be read back.
+    pub fn metadata(&self) -> Vec<(String, String)> {
+        let mut fields = vec![
+            ("version", env!("CARGO_PKG_VERSION").to_string()),
+            ("size", format!("{}x{}", self.bounds.0, self.bounds.1)),
+            ("center", self.view.center.to_string()),
+            ("precision", self.view.center.scale().to_string()),
+            ("width", self.view.width.to_string()),
+            ("height", self.view.height.to_string()),
+            ("fractal", self.options.fractal.to_string()),
+            ("limit", self.options.limit.to_string()),
+            ("escape_radius", self.options.escape_radius.to_string()),
+            ("samples", self.options.samples.to_string()),
+            ("jitter", self.options.jitter.to_string()),
+            ("coloring", self.coloring.to_string()),
+            ("alpha", self.alpha.to_string()),
+            ("deep", self.deep.to_string()),
+            ("depth", self.depth.to_string()),
+        ];
+        if let Some(palette) = &self.palette {
+            fields.push(("palette", palette.name().unwrap_or("custom").to_string()));
+        }
+        if let Some(shading) = self.shading {
+            fields.push(("shading", shading.to_string()));
+        }
+
+        fields.into_iter()
+            .map(|(field, value)| (format!("{}{}", METADATA_PREFIX, field), value))
+            .collect()
+    }
+
+    /// Rebuild the scene saved by `metadata` in the text chunks `text`,
+    /// writing to `output`.
+    pub fn from_metadata(text: &[(String, String)], output: &str) -> Result<Scene, Error> {
+        let field = |name: &str| text.iter()
+            .find(|(keyword, _)| keyword.strip_prefix(METADATA_PREFIX) == Some(name))
+            .map(|(_, value)| value.as_str());
+        if field("version").is_none() {
+            return Err(Error::Parse("no render parameters in the metadata".to_string()));
+        }
+        let required = |name: &str| field(name).ok_or_else(|| field_error(name, "missing"));
+
+        let bounds = parser::bounds_from_str(required("size")?).map_err(|err| err.context("size"))?;
+        // The exact decimals of the center are parsed with more fractional
+        // bits than they need, which the precision brings back down.
+        let center = parser::big_complex_from_str(required("center")?).map_err(|err| err.context("center"))?;
+        let precision: u32 = parse_field("precision", required("precision")?)?;
+        if precision > center.scale() {
+            return Err(field_error("precision", "more fractional bits than the center has"));
+        }
+        let view = View {
+            center: center.with_scale(precision),
+            width: parse_field("width", required("width")?)?,
+            height: parse_field("height", required("height")?)?,
+        };
+        let options = Options {
+            limit: parse_field("limit", required("limit")?)?,
+            escape_radius: parser::escape_radius_from_str(required("escape_radius")?)
+                .map_err(|err| err.context("escape_radius"))?,
+            fractal: parser::fractal_from_str(required("fractal")?).map_err(|err| err.context("fractal"))?,
+            samples: parse_field::<usize>("samples", required("samples")?)?.max(1),
+            jitter: parse_field("jitter", required("jitter")?)?,
+        };
+
+        let scene = Scene {
+            bounds,
+            view,
+            options,
+            palette: field("palette").map(|palette| parse_field("palette", palette)).transpose()?,
+            coloring: parse_field("coloring", required("coloring")?)?,
+            alpha: parse_field("alpha", required("alpha")?)?,
+            shading: field("shading").map(|shading| parse_field("shading", shading)).transpose()?,
+            deep: parse_field("deep", required("deep")?)?,
+            depth: parse_field("depth", required("depth")?)?,
+            output: output.to_string(),
+        };
+        // Whether the image fits in memory depends on its tiles being cached,
+        // which only the render knows: check the least it needs.
+        scene.validate_cached()?;
+        Ok(scene)
+    }
+
+    /// Read the scene file `path`, prefixing errors with the path.
+    pub fn load(path: &Path) -> Result<Scene, Error> {
+        let in_file = |err: Error| err.context(path.display());
+
+        let contents = fs::read_to_string(path).map_err(|err| in_file(err.into()))?;
+        let mut scene = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => Scene::from_json(&contents),
+            _ => Scene::from_toml(&contents),
+        }.map_err(in_file)?;
+
+        if let Some(directory) = path.parent() {
+            scene.output = directory.join(&scene.output).to_string_lossy().into_owned();
+        }
+        Ok(scene)
+    }
+}
+
+/// Scene files of `directory`, sorted by name.
+pub fn scene_files(directory: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        let is_scene = path.extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| EXTENSIONS.contains(&extension));
+        if path.is_file() && is_scene {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// A scene as written in a file, before validation.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneFile {
+    size: String,
+    view: ViewFile,
+    output: String,
+    #[serde(default = "default_fractal")]
+    fractal: String,
+    #[serde(default = "default_limit")]
+    limit: u32,
+    #[serde(default = "default_escape_radius")]
+    escape_radius: f64,
+    palette: Option<String>,
+    #[serde(default = "default_coloring")]
+    coloring: String,
+    #[serde(default)]
+    alpha: bool,
+    shading: Option<String>,
+    #[serde(default = "default_samples")]
+    samples: usize,
+    #[serde(default)]
+    jitter: bool,
+    #[serde(default)]
+    deep: bool,
+    #[serde(default = "default_depth")]
+    depth: u32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ViewFile {
+    upper_left: Option<String>,
+    lower_right: Option<String>,
+    center: Option<String>,
+    zoom: Option<f64>,
+    width: Option<f64>,
+}
+
+fn default_fractal() -> String { "mandelbrot".to_string() }
+fn default_limit() -> u32 { 255 }
+fn default_escape_radius() -> f64 { 2.0 }
+fn default_coloring() -> String { "smooth".to_string() }
+fn default_samples() -> usize { 1 }
+fn default_depth() -> u32 { 8 }
+
+/// Parse error of the `field` of a scene file.
+fn field_error(field: &str, err: impl ToString) -> Error {
+    Error::Parse(format!("{}: {}", field, err.to_string()))
+}
+
+/// Parse the `value` of the `field` of a scene's metadata.
+fn parse_field<T>(field: &str, value: &str) -> Result<T, Error>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    value.parse().map_err(|err: T::Err| field_error(field, err))
+}
+
+impl SceneFile {
+    fn into_scene(self) -> Result<Scene, Error> {
+        let bounds = parser::bounds_from_str(&self.size).map_err(|err| err.context("size"))?;
+        let view = self.view.into_view(bounds)?;
+
+        let options = Options {
+            limit: self.limit,
+            escape_radius: parser::validate_escape_radius(self.escape_radius)
+                .map_err(|err| field_error("escape_radius", err))?,
+            fractal: parser::fractal_from_str(&self.fractal).map_err(|err| err.context("fractal"))?,
+            samples: self.samples.max(1),
+            jitter: self.jitter,
+        };
+
+        let palette = self.palette
+            .map(|palette| Palette::from_str(&palette).map_err(|err| field_error("palette", err)))
+            .transpose()?;
+        let coloring = Coloring::from_str(&self.coloring).map_err(|err| field_error("coloring", err))?;
+        let shading = self.shading
+            .map(|shading| Shading::from_str(&shading).map_err(|err| field_error("shading", err)))
+            .transpose()?;
+
+        let scene = Scene {
+            bounds,
+            view,
+            options,
+            palette,
+            coloring,
+            alpha: self.alpha,
+            shading,
+            deep: self.deep,
+            depth: self.depth,
+            output: self.output,
+        };
+        // As in `from_metadata`, the render checks the size of the image.
+        scene.validate_cached()?;
+        Ok(scene)
+    }
+}
+
+impl ViewFile {
+    fn into_view(self, bounds: (usize, usize)) -> Result<View, Error> {
+        let complex = |field: &str, s: &str| -> Result<BigComplex, Error> {
+            parser::big_complex_from_str(s).map_err(|err| err.context(field))
+        };
+
+        let view = match self {
+            ViewFile { upper_left: Some(upper_left), lower_right: Some(lower_right), center: None, zoom: None, width: None } => {
+                View::from_corners(&complex("view.upper_left", &upper_left)?,
+                                   &complex("view.lower_right", &lower_right)?)
+            }
+            ViewFile { center: Some(center), zoom: Some(zoom), width: None, upper_left: None, lower_right: None } => {
+                if zoom <= 0.0 {
+                    return Err(field_error("view.zoom", "must be positive"));
+                }
+                View::from_zoom(complex("view.center", &center)?, zoom, bounds)
+            }
+            ViewFile { center: Some(center), width: Some(width), zoom: None, upper_left: None, lower_right: None } => {
+                if width <= 0.0 {
+                    return Err(field_error("view.width", "must be positive"));
+                }
+                View::from_width(complex("view.center", &center)?, width, bounds)
+            }
+            _ => return Err(field_error(
+                "view",
+                "expected either upper_left and lower_right, or center with zoom or width"
+            ))
+        };
+        Ok(view.fit(bounds))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num::Complex;
+
+    const TOML: &str = r#"
+        fractal = "julia:-0.8,0.156"
+        size = "80x60"
+        limit = 500
+        palette = "fire"
+        samples = 2
+        output = "julia.png"
+
+        [view]
+        center = "0,0"
+        zoom = 2
+    "#;
+
+    #[test]
+    fn test_from_toml() {
+        let scene = Scene::from_toml(TOML).unwrap();
+
+        assert_eq!(scene.bounds, (80, 60));
+        assert_eq!(scene.options.fractal, Fractal::Julia(Complex { re: -0.8, im: 0.156 }));
+        assert_eq!((scene.options.limit, scene.options.samples), (500, 2));
+        assert_eq!(scene.options.escape_radius, 2.0);
+        assert_eq!(scene.coloring, Coloring::Smooth);
+        assert_eq!((scene.view.width, scene.view.height), (2.0, 1.5));
+        assert!(scene.colorizer().is_some());
+        assert_eq!(scene.output, "julia.png");
+    }
+
+    #[test]
+    fn test_from_json() {
+        let scene = Scene::from_json(r#"{
+            "size": "30x30",
+            "output": "out.pgm",
+            "view": { "upper_left": "-2,1", "lower_right": "1,-1" }
+        }"#).unwrap();
+
+        assert_eq!(scene.options, Options::default());
+        assert_eq!(scene.view.complex_corners(),
+                   (Complex { re: -2.0, im: 1.5 }, Complex { re: 1.0, im: -1.5 }));
+        assert!(scene.palette.is_none());
+    }
+
+    #[test]
+    fn test_errors_name_fields() {
+        let error = |toml: &str| Scene::from_toml(toml).unwrap_err().to_string();
+        let scene = |extra: &str, view: &str| {
+            format!("size = \"10x10\"\noutput = \"a.png\"\n{}\n[view]\n{}\n", extra, view)
+        };
+        let corners = "upper_left = \"-2,1\"\nlower_right = \"1,-1\"";
+
+        assert!(error(&scene("fractal = \"julia:1\"", corners)).starts_with("fractal: wrong fractal format"));
+        assert!(error(&scene("escape_radius = 1.5", corners)).starts_with("escape_radius: escape radius"));
+        assert!(error(&scene("palette = \"mauve\"", corners)).starts_with("palette: "));
+        assert!(error(&scene("", "upper_left = \"-2;1\"\nlower_right = \"1,-1\"")).starts_with("view.upper_left: "));
+        assert!(error(&scene("", "center = \"0,0\"")).starts_with("view: expected"));
+        assert!(error(&scene("colour = \"red\"", corners)).contains("unknown field `colour`"));
+        assert!(error(&scene("deep = true\njitter = true", corners)).contains("jittered samples"));
+        assert!(error(&scene("", corners).replace("10x10", "0x10")).contains("image size 0x10 is empty"));
+    }
+
+    #[test]
+    fn test_validate_cached() {
+        let scene = Scene::from_toml(&TOML.replace("80x60", "40000x40000")).unwrap();
+
+        assert!(scene.validate().unwrap_err().to_string().contains("too large"));
+        assert!(scene.validate_cached().is_ok());
+
+        let scene = Scene { bounds: (1 << 20, 40000), ..scene };
+        assert!(scene.validate_cached().unwrap_err().to_string().contains("too large"));
+        let scene = Scene { bounds: (0, 40000), ..scene };
+        assert!(scene.validate_cached().unwrap_err().to_string().contains("empty"));
+    }
+
+    #[test]
+    fn test_metadata_round_trip() {
+        let mut scene = Scene::from_toml(TOML).unwrap();
+        scene.shading = Some(Shading::Lighting);
+        scene.options.fractal = Fractal::Mandelbrot;
+        scene.view.center = parser::big_complex_from_str("-0.1,0.65").unwrap();
+
+        let metadata = scene.metadata();
+        let read = Scene::from_metadata(&metadata, "copy.png").unwrap();
+
+        assert!(metadata.contains(&("mandelbrot:palette".to_string(), "fire".to_string())));
+        assert_eq!((read.bounds, &read.view, &read.options), (scene.bounds, &scene.view, &scene.options));
+        assert_eq!((&read.palette, read.coloring, read.shading), (&scene.palette, scene.coloring, scene.shading));
+        assert_eq!(read.output, "copy.png");
+        assert_eq!(read.metadata(), metadata);
+    }
+
+    #[test]
+    fn test_from_metadata_errors() {
+        let metadata = Scene::from_toml(TOML).unwrap().metadata();
+        let without = |field: &str| -> Vec<(String, String)> {
+            metadata.iter().filter(|(keyword, _)| !keyword.ends_with(field)).cloned().collect()
+        };
+        let with = |field: &str, value: &str| -> Vec<(String, String)> {
+            let mut text = without(field);
+            text.push((format!("{}{}", METADATA_PREFIX, field), value.to_string()));
+            text
+        };
+        let error = |text: Vec<(String, String)>| Scene::from_metadata(&text, "a.png").unwrap_err().to_string();
+
+        assert!(error(without(":version")).contains("no render parameters"));
+        assert_eq!(error(without(":limit")), "limit: missing");
+        assert!(error(with("jitter", "maybe")).starts_with("jitter: "));
+        assert!(error(with("palette", "custom")).starts_with("palette: unknown palette"));
+        assert!(error(with("width", "-1")).contains("corners are swapped"));
+        assert!(error(with("precision", "100000")).starts_with("precision: "));
+    }
+
+    #[test]
+    fn test_load_and_scene_files() {
+        let directory = std::env::temp_dir().join(format!("mandelbrot-scenes-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("b.toml"), TOML).unwrap();
+        fs::write(directory.join("a.json"), "{").unwrap();
+        fs::write(directory.join("notes.txt"), "").unwrap();
+
+        let files = scene_files(&directory).unwrap();
+        let scene = Scene::load(&files[1]);
+        let error = Scene::load(&files[0]).unwrap_err().to_string();
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(files, vec![directory.join("a.json"), directory.join("b.toml")]);
+        assert_eq!(scene.unwrap().output, directory.join("julia.png").to_string_lossy());
+        assert!(error.starts_with(&directory.join("a.json").display().to_string()));
+    }
+}
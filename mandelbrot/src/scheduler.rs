@@ -0,0 +1,300 @@
+//! Splitting images into tiles and rendering them on several threads.
+//!
+//! Threads pull small tiles from a shared queue until it's empty, so the
+//! slow tiles covering the interior of the set don't hold everything up the
+//! way fixed per-thread bands do.
+//!
+//! A `Control` lets the caller follow the progress of a render tile by tile
+//! and stop it early.
+use crossbeam::channel;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Default width and height of a tile in pixels.
+pub const TILE_SIZE: usize = 64;
+
+/// A rectangle of pixels of an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Tile {
+    pub left: usize,
+    pub top: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Tile {
+    /// Number of pixels in the tile.
+    pub fn len(&self) -> usize {
+        self.width * self.height
+    }
+
+    /// Whether the tile has no pixels.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterate over `(column, row)` image coordinates of the tile's pixels,
+    /// row by row.
+    pub fn pixels(&self) -> impl Iterator<Item = (usize, usize)> {
+        let tile = *self;
+        (tile.top..tile.top + tile.height)
+            .flat_map(move |row| (tile.left..tile.left + tile.width).map(move |column| (column, row)))
+    }
+}
+
+/// Flag shared between a render and whoever may want to stop it.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    /// Ask the renders holding a clone of the token to stop.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Number of tiles rendered so far out of the `total` of a render.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub done: usize,
+    pub total: usize,
+}
+
+/// Error of a render stopped by its `CancelToken`, leaving the buffer
+/// partially filled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rendering cancelled")
+    }
+}
+
+impl Error for Cancelled {}
+
+/// Cancellation token and progress callback of a render.
+///
+/// The callback runs on the thread that started the render, after each
+/// tile is copied into the buffer.
+#[derive(Default)]
+pub struct Control<'a> {
+    pub cancel: CancelToken,
+    progress: Option<Box<dyn FnMut(Progress) + 'a>>,
+}
+
+impl<'a> Control<'a> {
+    /// Control stopping the render once `cancel` is cancelled.
+    pub fn new(cancel: CancelToken) -> Control<'a> {
+        Control { cancel, progress: None }
+    }
+
+    /// Call `progress` whenever a tile is done.
+    pub fn on_progress(mut self, progress: impl FnMut(Progress) + 'a) -> Control<'a> {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    pub(crate) fn report(&mut self, progress: Progress) {
+        if let Some(report) = &mut self.progress {
+            report(progress);
+        }
+    }
+}
+
+/// Split an image of size `bounds` into tiles of at most `tile_size` by
+/// `tile_size` pixels, in row-major order.
+pub fn tiles(bounds: (usize, usize), tile_size: usize) -> Vec<Tile> {
+    let tile_size = tile_size.max(1);
+    let mut tiles = Vec::new();
+    for top in (0..bounds.1).step_by(tile_size) {
+        for left in (0..bounds.0).step_by(tile_size) {
+            tiles.push(Tile {
+                left,
+                top,
+                width: tile_size.min(bounds.0 - left),
+                height: tile_size.min(bounds.1 - top),
+            });
+        }
+    }
+    tiles
+}
+
+/// Copy the pixels of a `tile`, stored row by row, into the image `buffer`
+/// of width `width`.
+pub fn copy_tile<T>(buffer: &mut [T], width: usize, tile: &Tile, pixels: Vec<T>) {
+    assert_eq!(pixels.len(), tile.len());
+    let mut pixels = pixels.into_iter();
+    for row in tile.top..tile.top + tile.height {
+        let start = row * width + tile.left;
+        for (dst, src) in buffer[start..start + tile.width].iter_mut().zip(&mut pixels) {
+            *dst = src;
+        }
+    }
+}
+
+/// Fill `buffer`, an image of size `bounds`, with `value(column, row)` of
+/// every pixel.
+///
+/// The image is split into tiles of `TILE_SIZE` pixels which `n_threads`
+/// threads (at least one) take from a shared queue and render.
+pub fn render_tiles<T, F>(buffer: &mut [T], bounds: (usize, usize), n_threads: usize, value: F)
+where
+    T: Send,
+    F: Fn(usize, usize) -> T + Sync,
+{
+    render_tiles_with(buffer, bounds, n_threads, |tile| {
+        tile.pixels().map(|(column, row)| value(column, row)).collect()
+    });
+}
+
+/// The same as `render_tiles` but render a whole tile at once with
+/// `render_tile`, which returns the tile's pixels row by row.
+pub fn render_tiles_with<T, F>(buffer: &mut [T], bounds: (usize, usize), n_threads: usize, render_tile: F)
+where
+    T: Send,
+    F: Fn(&Tile) -> Vec<T> + Sync,
+{
+    render_tiles_controlled(buffer, bounds, n_threads, &mut Control::default(), render_tile)
+        .expect("render without cancel token cancelled");
+}
+
+/// The same as `render_tiles_with` but report progress to `control` and
+/// stop taking tiles once it's cancelled.
+pub fn render_tiles_controlled<T, F>(
+    buffer: &mut [T],
+    bounds: (usize, usize),
+    n_threads: usize,
+    control: &mut Control,
+    render_tile: F,
+) -> Result<(), Cancelled>
+where
+    T: Send,
+    F: Fn(&Tile) -> Vec<T> + Sync,
+{
+    assert_eq!(buffer.len(), bounds.0 * bounds.1);
+
+    let queue = tiles(bounds, TILE_SIZE);
+    let next = AtomicUsize::new(0);
+    let n_threads = n_threads.max(1).min(queue.len().max(1));
+
+    debug!("parallel rendering: n_threads={}, n_tiles={}", n_threads, queue.len());
+
+    let (sender, receiver) = channel::unbounded();
+    let cancel = control.cancel.clone();
+
+    crossbeam::scope(|spawner| {
+        for _ in 0..n_threads {
+            let (queue, next, render_tile, sender) = (&queue, &next, &render_tile, sender.clone());
+            let cancel = &cancel;
+            spawner.spawn(move |_| {
+                while let Some(tile) = queue.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    if cancel.is_cancelled() {
+                        break;
+                    }
+                    sender.send((tile, render_tile(tile))).unwrap();
+                }
+            });
+        }
+        drop(sender);
+
+        let mut progress = Progress { done: 0, total: queue.len() };
+        for (tile, pixels) in receiver {
+            copy_tile(buffer, bounds.0, tile, pixels);
+            progress.done += 1;
+            control.report(progress);
+        }
+
+        if progress.done < progress.total {
+            debug!("parallel rendering cancelled after {} tiles", progress.done);
+            Err(Cancelled)
+        } else {
+            Ok(())
+        }
+    }).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tiles_cover_image() {
+        let bounds = (150, 70);
+
+        let tiles = tiles(bounds, 64);
+
+        assert_eq!(tiles.len(), 6);
+        assert_eq!(tiles[2], Tile { left: 128, top: 0, width: 22, height: 64 });
+        assert_eq!(tiles[5], Tile { left: 128, top: 64, width: 22, height: 6 });
+        assert_eq!(tiles.iter().map(Tile::len).sum::<usize>(), bounds.0 * bounds.1);
+    }
+
+    #[test]
+    fn test_render_tiles() {
+        let bounds = (130, 67);
+        let mut buffer = vec![(0, 0); bounds.0 * bounds.1];
+
+        render_tiles(&mut buffer, bounds, 3, |column, row| (column, row));
+
+        for (i, &pixel) in buffer.iter().enumerate() {
+            assert_eq!(pixel, (i % bounds.0, i / bounds.0));
+        }
+    }
+
+    #[test]
+    fn test_render_tiles_reports_progress() {
+        let bounds = (130, 67);
+        let mut buffer = vec![0; bounds.0 * bounds.1];
+        let mut reports = Vec::new();
+
+        let result = render_tiles_controlled(
+            &mut buffer, bounds, 2,
+            &mut Control::default().on_progress(|progress| reports.push(progress)),
+            |tile| vec![1; tile.len()]
+        );
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(reports.len(), 6);
+        assert_eq!(reports.last(), Some(&Progress { done: 6, total: 6 }));
+        assert!(buffer.iter().all(|&pixel| pixel == 1));
+    }
+
+    #[test]
+    fn test_render_tiles_cancelled() {
+        let bounds = (640, 640);
+        let mut buffer = vec![0; bounds.0 * bounds.1];
+        let cancel = CancelToken::new();
+        let mut control = Control::new(cancel.clone());
+
+        // Cancel on reaching the second row of tiles.
+        let result = render_tiles_controlled(&mut buffer, bounds, 1, &mut control, |tile| {
+            if tile.top > 0 {
+                cancel.cancel();
+            }
+            vec![1; tile.len()]
+        });
+
+        assert_eq!(result, Err(Cancelled));
+        assert_eq!(buffer.iter().filter(|&&pixel| pixel == 1).count(), 11 * 64 * 64);
+    }
+
+    #[test]
+    fn test_render_tiles_empty_image() {
+        let mut buffer: Vec<u8> = vec![];
+
+        render_tiles(&mut buffer, (0, 10), 4, |_, _| 1);
+    }
+}
@@ -0,0 +1,411 @@
+//! Tile server for exploring fractals in a browser.
+//!
+//! The plane is cut into square tiles the way slippy maps cut the world: at
+//! zoom `z` the square of side `view::BASE_WIDTH` centered on `-0.5` is split
+//! into `2^z` by `2^z` tiles of `TILE_PIXELS` pixels, and the tile in column
+//! `x` and row `y` is served as `/{z}/{x}/{y}.png`. Rendered tiles are kept
+//! in a least-recently-used cache. The root path serves a small viewer page,
+//! which loads nothing but the tiles.
+//!
+//! The server speaks just enough HTTP/1.1 for browsers: it reads the request
+//! line, ignores the headers and closes the connection after each response.
+//! Connections are answered by a fixed number of threads, and clients that
+//! take longer than `TIMEOUT` to send a request or read a response, or send
+//! lines longer than `MAX_LINE`, are dropped.
+use super::output;
+use super::palette::Colorizer;
+use super::renderer::{self, Options};
+use super::view::BASE_WIDTH;
+use super::Error;
+use crossbeam::channel;
+use log::{debug, info, warn};
+use num::Complex;
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Width and height of a tile in pixels.
+pub const TILE_PIXELS: usize = 256;
+
+/// Deepest zoom level served, where tiles are about as wide as the `f64`
+/// precision allows.
+pub const MAX_ZOOM: u32 = 40;
+
+/// Number of connections answered at once; further ones wait to be
+/// accepted.
+pub const MAX_CONNECTIONS: usize = 16;
+
+/// How long reading a whole request, or writing a response, may take.
+pub const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Longest request or header line read.
+pub const MAX_LINE: u64 = 8192;
+
+/// Center of the square covered by the tile at zoom 0.
+const ORIGIN: Complex<f64> = Complex { re: -0.5, im: 0.0 };
+
+/// The page served at `/`.
+const VIEWER: &str = include_str!("viewer.html");
+
+/// Address of a tile: zoom level, column and row.
+pub type TileKey = (u32, u32, u32);
+
+/// Return the upper-left and lower-right corners of the tile `(z, x, y)`.
+pub fn tile_corners((z, x, y): TileKey) -> (Complex<f64>, Complex<f64>) {
+    let size = BASE_WIDTH / (1u64 << z) as f64;
+    let upper_left = Complex {
+        re: ORIGIN.re - BASE_WIDTH / 2.0 + x as f64 * size,
+        im: ORIGIN.im + BASE_WIDTH / 2.0 - y as f64 * size,
+    };
+    (upper_left, upper_left + Complex { re: size, im: -size })
+}
+
+/// Parse a tile path like `/3/5/2.png`, returning `None` for other paths
+/// and for tiles outside the map.
+pub fn parse_tile_path(path: &str) -> Option<TileKey> {
+    let mut parts = path.strip_prefix('/')?.strip_suffix(".png")?.split('/');
+    let z: u32 = parts.next()?.parse().ok()?;
+    let x: u32 = parts.next()?.parse().ok()?;
+    let y: u32 = parts.next()?.parse().ok()?;
+    if parts.next().is_some() || z > MAX_ZOOM || x as u64 >= 1 << z || y as u64 >= 1 << z {
+        return None;
+    }
+    Some((z, x, y))
+}
+
+/// Fixed-capacity map dropping its least recently used entry when full.
+#[derive(Debug)]
+pub struct LruCache<K, V> {
+    capacity: usize,
+    clock: u64,
+    entries: HashMap<K, (V, u64)>,
+}
+
+impl<K: Eq + std::hash::Hash + Clone, V: Clone> LruCache<K, V> {
+    pub fn new(capacity: usize) -> LruCache<K, V> {
+        LruCache { capacity, clock: 0, entries: HashMap::new() }
+    }
+
+    /// Return a copy of the value of `key`, marking it as recently used.
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        self.clock += 1;
+        let clock = self.clock;
+        self.entries.get_mut(key).map(|(value, used)| {
+            *used = clock;
+            value.clone()
+        })
+    }
+
+    /// Insert `value` for `key`, evicting the least recently used entry if
+    /// the cache is full.
+    pub fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&key) {
+            let oldest = self.entries.iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.clock += 1;
+        self.entries.insert(key, (value, self.clock));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Status, content type and body of an HTTP response.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: &'static str,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    fn new(status: &'static str, content_type: &'static str, body: Vec<u8>) -> Response {
+        Response { status, content_type, body }
+    }
+
+    fn not_found() -> Response {
+        Response::new("404 Not Found", "text/plain", b"not found\n".to_vec())
+    }
+
+    /// Write the response to `stream`, closing the connection afterwards.
+    fn write_to(&self, stream: &mut impl Write) -> io::Result<()> {
+        write!(stream,
+               "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+               self.status, self.content_type, self.body.len())?;
+        stream.write_all(&self.body)?;
+        stream.flush()
+    }
+}
+
+/// Renders tiles on request and keeps the recent ones.
+pub struct TileServer {
+    options: Options,
+    colorizer: Option<Colorizer>,
+    n_threads: usize,
+    timeout: Duration,
+    cache: Mutex<LruCache<TileKey, Arc<Vec<u8>>>>,
+}
+
+impl TileServer {
+    /// Create a server rendering tiles with `options`, in grayscale without
+    /// a `colorizer`, keeping at most `cache_size` tiles in memory and using
+    /// `n_threads` threads per tile.
+    pub fn new(options: Options, colorizer: Option<Colorizer>, cache_size: usize, n_threads: usize) -> TileServer {
+        TileServer { options, colorizer, n_threads, timeout: TIMEOUT, cache: Mutex::new(LruCache::new(cache_size)) }
+    }
+
+    /// Return the PNG image of the tile `key`, rendering it unless it's
+    /// cached.
+    pub fn tile(&self, key: TileKey) -> Result<Arc<Vec<u8>>, Error> {
+        if let Some(png) = self.cache.lock().unwrap().get(&key) {
+            debug!("tile {:?} found in cache", key);
+            return Ok(png);
+        }
+
+        let (upper_left, lower_right) = tile_corners(key);
+        let bounds = (TILE_PIXELS, TILE_PIXELS);
+        let pixels = renderer::render_image(bounds, upper_left, lower_right,
+                                            &self.options, self.colorizer.as_ref(), self.n_threads);
+        let png = Arc::new(output::encode_png(&pixels, bounds)?);
+
+        debug!("rendered tile {:?}", key);
+        self.cache.lock().unwrap().insert(key, png.clone());
+        Ok(png)
+    }
+
+    /// Answer a `GET` request for `path`.
+    pub fn respond(&self, path: &str) -> Response {
+        let path = path.split('?').next().unwrap_or(path);
+        if path == "/" || path == "/index.html" {
+            return Response::new("200 OK", "text/html; charset=utf-8", VIEWER.as_bytes().to_vec());
+        }
+        match parse_tile_path(path) {
+            None => Response::not_found(),
+            Some(key) => match self.tile(key) {
+                Ok(png) => Response::new("200 OK", "image/png", png.to_vec()),
+                Err(err) => Response::new("500 Internal Server Error", "text/plain",
+                                          format!("{}\n", err).into_bytes()),
+            }
+        }
+    }
+
+    /// Read one request from `stream` and answer it, giving up if the
+    /// request takes longer than `TIMEOUT` to arrive, a line of it is longer
+    /// than `MAX_LINE` or writing blocks for longer than `TIMEOUT`.
+    pub fn handle(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_write_timeout(Some(self.timeout))?;
+        let deadline = Instant::now() + self.timeout;
+        let mut reader = BufReader::new(DeadlineReader { stream: &stream, deadline });
+        let request_line = read_line(&mut reader)?;
+
+        // Skip the headers, up to the empty line.
+        while !read_line(&mut reader)?.trim_end().is_empty() {}
+
+        let response = match request_line.split_whitespace().collect::<Vec<_>>()[..] {
+            ["GET", path, _] => self.respond(path),
+            _ => Response::new("400 Bad Request", "text/plain", b"bad request\n".to_vec()),
+        };
+        debug!("{} -> {}", request_line.trim_end(), response.status);
+        response.write_to(&mut stream)
+    }
+
+    /// Answer the connections of `listener` on `MAX_CONNECTIONS` threads
+    /// until accepting fails.
+    pub fn serve(self: Arc<Self>, listener: TcpListener) -> io::Result<()> {
+        info!("serving tiles on http://{}/", listener.local_addr()?);
+        // Accepted connections wait in a channel of one slot per thread,
+        // beyond which they queue in the listener's backlog.
+        let (sender, receiver) = channel::bounded::<TcpStream>(MAX_CONNECTIONS);
+        for _ in 0..MAX_CONNECTIONS {
+            let (server, receiver) = (self.clone(), receiver.clone());
+            thread::spawn(move || {
+                for stream in receiver {
+                    if let Err(err) = server.handle(stream) {
+                        warn!("error answering request: {}", err);
+                    }
+                }
+            });
+        }
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => sender.send(stream).expect("server threads exited"),
+                // Errors like running out of file descriptors pass.
+                Err(err) => warn!("error accepting connection: {}", err),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads from a TCP stream failing once `deadline` has passed, however the
+/// data trickles in.
+struct DeadlineReader<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "request deadline passed"));
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.read(buffer)
+    }
+}
+
+/// Read a line of at most `MAX_LINE` bytes from `reader`, returning an
+/// empty string at the end of the stream.
+fn read_line(reader: &mut impl BufRead) -> io::Result<String> {
+    let mut line = String::new();
+    let read = reader.take(MAX_LINE).read_line(&mut line)?;
+    if read as u64 == MAX_LINE && !line.ends_with('\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "request line too long"));
+    }
+    Ok(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+    use std::io::Read;
+
+    #[test]
+    fn test_tile_corners() {
+        assert_eq!(tile_corners((0, 0, 0)),
+                   (Complex { re: -2.5, im: 2.0 }, Complex { re: 1.5, im: -2.0 }));
+        assert_eq!(tile_corners((2, 1, 3)),
+                   (Complex { re: -1.5, im: -1.0 }, Complex { re: -0.5, im: -2.0 }));
+    }
+
+    #[rstest]
+    #[case("/0/0/0.png", Some((0, 0, 0)))]
+    #[case("/3/7/2.png", Some((3, 7, 2)))]
+    #[case("/3/8/2.png", None)]
+    #[case("/3/7/2", None)]
+    #[case("/3/7/2/1.png", None)]
+    #[case("/a/b/c.png", None)]
+    #[case("/99/0/0.png", None)]
+    fn test_parse_tile_path(#[case] path: &str, #[case] expected: Option<TileKey>) {
+        assert_eq!(parse_tile_path(path), expected);
+    }
+
+    #[test]
+    fn test_lru_cache() {
+        let mut cache = LruCache::new(2);
+
+        cache.insert(1, "one");
+        cache.insert(2, "two");
+        assert_eq!(cache.get(&1), Some("one"));
+        cache.insert(3, "three");
+
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.get(&1), Some("one"));
+        assert_eq!(cache.get(&3), Some("three"));
+    }
+
+    #[test]
+    fn test_respond() {
+        let server = TileServer::new(Options::default(), None, 4, 2);
+
+        let page = server.respond("/");
+        let tile = server.respond("/1/0/1.png?t=1");
+
+        assert_eq!(page.content_type, "text/html; charset=utf-8");
+        assert!(!String::from_utf8(page.body).unwrap().contains("://"), "the viewer loads external resources");
+        assert_eq!((tile.status, tile.content_type), ("200 OK", "image/png"));
+        assert_eq!(&tile.body[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(server.cache.lock().unwrap().len(), 1);
+        assert_eq!(server.respond("/1/2/0.png").status, "404 Not Found");
+    }
+
+    #[test]
+    fn test_serve() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = Arc::new(TileServer::new(Options::default(), None, 4, 1));
+        thread::spawn(move || server.serve(listener));
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(b"GET /0/0/0.png HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+
+        let response = String::from_utf8_lossy(&response);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: image/png\r\n"));
+    }
+
+    #[test]
+    fn test_serve_silent_clients() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut server = TileServer::new(Options::default(), None, 4, 1);
+        server.timeout = Duration::from_millis(200);
+        thread::spawn(move || Arc::new(server).serve(listener));
+
+        // Clients sending nothing take up every thread until they time out.
+        let silent: Vec<TcpStream> = (0..2 * MAX_CONNECTIONS).map(|_| TcpStream::connect(address).unwrap()).collect();
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        drop(silent);
+    }
+
+    #[test]
+    fn test_handle_slow_and_long_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut server = TileServer::new(Options::default(), None, 4, 1);
+        server.timeout = Duration::from_millis(200);
+        let server = Arc::new(server);
+        let handler = thread::spawn(move || {
+            listener.incoming().take(3).map(|stream| server.handle(stream.unwrap())).collect::<Vec<_>>()
+        });
+
+        // A header line of one character ends with a bare LF.
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\nA\n\n").unwrap();
+        stream.read_to_end(&mut Vec::new()).unwrap();
+        // A client sending a byte at a time is dropped at the deadline.
+        let mut slow = TcpStream::connect(address).unwrap();
+        slow.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        for _ in 0..20 {
+            if slow.write_all(b"X").is_err() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        let mut long = TcpStream::connect(address).unwrap();
+        let _ = long.write_all(&vec![b'A'; 2 * MAX_LINE as usize]);
+
+        let results = handler.join().unwrap();
+        assert!(results[0].is_ok());
+        // Timed out reads fail with `WouldBlock` on some platforms.
+        let kind = results[1].as_ref().unwrap_err().kind();
+        assert!(kind == io::ErrorKind::TimedOut || kind == io::ErrorKind::WouldBlock, "{:?}", kind);
+        assert_eq!(results[2].as_ref().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,291 @@
+//! Shading modes bringing out structure that escape counts hide.
+//!
+//! All modes follow each orbit together with its derivative with respect to
+//! the point, which only exists for analytic fractals (not for the Burning
+//! Ship or the Tricorn):
+//!
+//! - `Distance` shades the exterior by its estimated distance to the set,
+//!   `|z| ln |z| / |dz|`, so that filaments thinner than a pixel stay visible.
+//! - `Interior` colors the points of the set by the period of the cycle their
+//!   orbit falls into, darkened by how close the orbit comes to the origin
+//!   (an orbit trap), and the exterior by smooth escape counts.
+//! - `Lighting` treats the exterior as a relief lit from the upper right,
+//!   using the direction of `z / dz` as the surface normal.
+//!
+//! Large escape radii make the estimates more accurate.
+use super::fractal::Fractal;
+use super::palette::{Palette, Rgb};
+use super::renderer::{self, Options};
+use super::scheduler;
+use num::Complex;
+use std::fmt;
+use std::str::FromStr;
+
+/// Direction the relief of `Shading::Lighting` is lit from.
+const LIGHT_ANGLE: f64 = std::f64::consts::FRAC_PI_4;
+
+/// Height of the light above the relief, relative to the normal's length.
+const LIGHT_HEIGHT: f64 = 1.5;
+
+/// Longest cycle the interior detection looks for.
+const MAX_PERIOD: u32 = 1 << 12;
+
+/// How close two values of an orbit have to be to count as a cycle.
+const PERIOD_TOLERANCE: f64 = 1e-24;
+
+/// A shading mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shading {
+    /// Exterior distance estimation.
+    Distance,
+    /// Interior period and orbit-trap coloring.
+    Interior,
+    /// Normal-map lighting of the exterior.
+    Lighting,
+}
+
+impl FromStr for Shading {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "distance" => Ok(Shading::Distance),
+            "interior" => Ok(Shading::Interior),
+            "lighting" => Ok(Shading::Lighting),
+            _ => Err(format!("unknown shading: {} (expected distance, interior or lighting)", s))
+        }
+    }
+}
+
+impl fmt::Display for Shading {
+    /// Format the shading the way `Shading::from_str` reads it.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Shading::Distance => write!(f, "distance"),
+            Shading::Interior => write!(f, "interior"),
+            Shading::Lighting => write!(f, "lighting"),
+        }
+    }
+}
+
+/// What an orbit reveals about its point.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sample {
+    /// The orbit escaped.
+    Exterior {
+        /// Normalized iteration count.
+        escape: f64,
+        /// Estimated distance to the set.
+        distance: f64,
+        /// Unit normal of the relief used for lighting.
+        normal: Complex<f64>,
+    },
+    /// The orbit didn't escape.
+    Interior {
+        /// Period of the cycle the orbit falls into, if one was found.
+        period: Option<u32>,
+        /// Smallest `|z|` along the orbit.
+        trap: f64,
+    },
+}
+
+/// Follow the orbit of `point` for at most `limit` iterations.
+///
+/// # Panics
+///
+/// The fractal must be analytic, see `Fractal::is_analytic`.
+pub fn sample(fractal: &Fractal, point: Complex<f64>, limit: u32, escape_radius: f64) -> Sample {
+    let bailout = escape_radius * escape_radius;
+    let (mut z, c) = fractal.start(point);
+    let mut dz = fractal.start_derivative();
+    let mut trap = f64::INFINITY;
+
+    // Brent's cycle detection: compare z to the value saved at the last
+    // power of two.
+    let mut saved = z;
+    let mut saved_at = 0;
+    let mut period = None;
+
+    for i in 0..limit {
+        let norm_sqr = z.norm_sqr();
+        if norm_sqr > bailout {
+            let norm = norm_sqr.sqrt();
+            let u = z / dz;
+            return Sample::Exterior {
+                escape: fractal.smooth_count(i, norm_sqr, escape_radius),
+                distance: norm * norm.ln() / dz.norm(),
+                normal: u / u.norm(),
+            };
+        }
+        if i > 0 {
+            trap = trap.min(norm_sqr.sqrt());
+        }
+
+        dz = fractal.step_derivative(z, dz).expect("shading needs an analytic fractal");
+        z = fractal.step(z, c);
+
+        if period.is_none() {
+            if (z - saved).norm_sqr() < PERIOD_TOLERANCE {
+                period = Some(i + 1 - saved_at);
+            } else if (i + 1).is_power_of_two() && i < MAX_PERIOD {
+                saved = z;
+                saved_at = i + 1;
+            }
+        }
+    }
+
+    Sample::Interior { period, trap }
+}
+
+/// Turns samples into colors.
+#[derive(Debug, Clone)]
+pub struct Shader {
+    pub shading: Shading,
+    pub palette: Palette,
+    /// Width of a pixel in the complex plane, the unit of distances.
+    pub pixel_size: f64,
+    /// Iteration limit the samples were computed with.
+    pub limit: u32,
+}
+
+impl Shader {
+    /// Color of `sample`.
+    pub fn color(&self, sample: &Sample) -> Rgb {
+        match (self.shading, *sample) {
+            (Shading::Distance, Sample::Exterior { distance, .. }) => {
+                self.palette.at((distance / self.pixel_size).tanh())
+            }
+            (Shading::Interior, Sample::Exterior { escape, .. }) => {
+                self.palette.at(escape / self.limit as f64)
+            }
+            (Shading::Interior, Sample::Interior { period: Some(period), trap }) => {
+                let hue = (period - 1) % 8;
+                let color = self.palette.at(1.0 - hue as f64 / 8.0);
+                scale(color, trap.min(1.0).sqrt())
+            }
+            (Shading::Lighting, Sample::Exterior { escape, normal, .. }) => {
+                let light = Complex::from_polar(1.0, LIGHT_ANGLE);
+                let brightness = (normal.re * light.re + normal.im * light.im + LIGHT_HEIGHT)
+                    / (1.0 + LIGHT_HEIGHT);
+                let color = self.palette.at(1.0 - escape / self.limit as f64);
+                scale(color, brightness.max(0.0))
+            }
+            _ => [0, 0, 0]
+        }
+    }
+}
+
+fn scale(color: Rgb, k: f64) -> Rgb {
+    color.map(|c| (c as f64 * k).round().min(255.0) as u8)
+}
+
+/// Render a rectangle of the complex plane with `shading`, supersampled as
+/// `options` say, into RGB pixels colored with `palette`.
+///
+/// # Panics
+///
+/// The fractal of `options` must be analytic, see `Fractal::is_analytic`.
+pub fn render(
+    bounds: (usize, usize),
+    upper_left: Complex<f64>,
+    lower_right: Complex<f64>,
+    options: &Options,
+    shading: Shading,
+    palette: &Palette,
+    n_threads: usize,
+) -> Vec<u8> {
+    assert!(options.fractal.is_analytic(), "shading needs an analytic fractal");
+
+    let samples = options.samples.max(1);
+    let sample_bounds = (bounds.0 * samples, bounds.1 * samples);
+    let shader = Shader {
+        shading,
+        palette: palette.clone(),
+        pixel_size: (lower_right.re - upper_left.re).abs() / sample_bounds.0.max(1) as f64,
+        limit: options.limit,
+    };
+
+    let mut pixels = vec![[0; 3]; sample_bounds.0 * sample_bounds.1];
+    scheduler::render_tiles(&mut pixels, sample_bounds, n_threads, |column, row| {
+        let point = renderer::sample_point(sample_bounds, (column, row), upper_left, lower_right, options);
+        shader.color(&sample(&options.fractal, point, options.limit, options.escape_radius))
+    });
+
+    let pixels: Vec<u8> = pixels.into_iter().flatten().collect();
+    renderer::downsample(&pixels, bounds, samples)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(0.0, 0.0, Some(1))]
+    #[case(-1.0, 0.0, Some(2))]
+    #[case(-0.1, 0.75, Some(3))]
+    #[case(-1.3, 0.0, Some(4))]
+    fn test_interior_period(#[case] re: f64, #[case] im: f64, #[case] expected: Option<u32>) {
+        match sample(&Fractal::Mandelbrot, Complex { re, im }, 10000, 2.0) {
+            Sample::Interior { period, .. } => assert_eq!(period, expected),
+            exterior => panic!("{:?} is not interior", exterior),
+        }
+    }
+
+    #[test]
+    fn test_distance_estimate() {
+        // The distance from 1 to the set is about 0.75 (the cusp is at 0.25).
+        match sample(&Fractal::Mandelbrot, Complex { re: 1.0, im: 0.0 }, 1000, 1e6) {
+            Sample::Exterior { distance, normal, .. } => {
+                assert!(distance > 0.75 / 4.0 && distance < 0.75 * 4.0, "{}", distance);
+                assert!((normal.norm() - 1.0).abs() < 1e-12);
+                assert!(normal.re > 0.99);
+            }
+            interior => panic!("{:?} is not exterior", interior),
+        }
+    }
+
+    #[test]
+    fn test_distance_shrinks_near_set() {
+        let distance = |re| match sample(&Fractal::Mandelbrot, Complex { re, im: 0.0 }, 1000, 1e6) {
+            Sample::Exterior { distance, .. } => distance,
+            interior => panic!("{:?} is not exterior", interior),
+        };
+
+        assert!(distance(0.26) < distance(0.3));
+        assert!(distance(0.3) < distance(0.5));
+    }
+
+    #[test]
+    fn test_julia_and_multibrot_samples() {
+        let julia = Fractal::Julia(Complex { re: -1.0, im: 0.0 });
+        let multibrot = Fractal::Multibrot(3.0);
+
+        assert!(matches!(sample(&julia, Complex { re: 0.1, im: 0.0 }, 1000, 2.0),
+                         Sample::Interior { period: Some(2), .. }));
+        assert!(matches!(sample(&multibrot, Complex { re: 0.0, im: 0.0 }, 1000, 2.0),
+                         Sample::Interior { period: Some(1), .. }));
+        assert!(matches!(sample(&multibrot, Complex { re: 1.0, im: 1.0 }, 1000, 2.0),
+                         Sample::Exterior { .. }));
+    }
+
+    #[rstest]
+    #[case(Shading::Distance)]
+    #[case(Shading::Interior)]
+    #[case(Shading::Lighting)]
+    fn test_render(#[case] shading: Shading) {
+        let options = Options { escape_radius: 100.0, ..Default::default() };
+        let (upper_left, lower_right) = (Complex { re: -2.0, im: 1.2 }, Complex { re: 1.0, im: -1.2 });
+
+        let pixels = render((30, 20), upper_left, lower_right, &options, shading, &Palette::grayscale(), 2);
+
+        assert_eq!(pixels.len(), 30 * 20 * 3);
+        assert!(pixels.iter().any(|&c| c > 0));
+    }
+
+    #[test]
+    fn test_shading_from_str() {
+        assert_eq!("lighting".parse::<Shading>(), Ok(Shading::Lighting));
+        assert!("phong".parse::<Shading>().is_err());
+    }
+}
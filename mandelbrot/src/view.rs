@@ -0,0 +1,143 @@
+//! Rectangles of the complex plane, given either by their corners or by
+//! their center and size.
+//!
+//! The center is kept with arbitrary precision so that the same views serve
+//! deep zooms, while the size is an `f64`: its exponent, not its precision,
+//! is what matters.
+use super::deep::{BigComplex, Fixed};
+use num::Complex;
+
+/// Width of the view at zoom 1, which shows the whole Mandelbrot set.
+pub const BASE_WIDTH: f64 = 4.0;
+
+/// A rectangle of the complex plane.
+#[derive(Debug, Clone, PartialEq)]
+pub struct View {
+    pub center: BigComplex,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl View {
+    /// The view with corners `upper_left` and `lower_right`.
+    pub fn from_corners(upper_left: &BigComplex, lower_right: &BigComplex) -> View {
+        let scale = upper_left.scale().max(lower_right.scale());
+        let (upper_left, lower_right) = (upper_left.with_scale(scale), lower_right.with_scale(scale));
+        View {
+            center: BigComplex {
+                re: (&upper_left.re + &lower_right.re).half(),
+                im: (&upper_left.im + &lower_right.im).half(),
+            },
+            width: (&lower_right.re - &upper_left.re).to_f64(),
+            height: (&upper_left.im - &lower_right.im).to_f64(),
+        }
+    }
+
+    /// The view of width `width` around `center`, with the aspect ratio of
+    /// an image of size `bounds`.
+    pub fn from_width(center: BigComplex, width: f64, bounds: (usize, usize)) -> View {
+        let height = width * bounds.1 as f64 / bounds.0.max(1) as f64;
+        View { center, width, height }
+    }
+
+    /// The view `zoom` times narrower than `BASE_WIDTH` around `center`,
+    /// with the aspect ratio of an image of size `bounds`.
+    pub fn from_zoom(center: BigComplex, zoom: f64, bounds: (usize, usize)) -> View {
+        View::from_width(center, BASE_WIDTH / zoom, bounds)
+    }
+
+    /// How many times narrower than `BASE_WIDTH` the view is.
+    pub fn zoom(&self) -> f64 {
+        BASE_WIDTH / self.width
+    }
+
+    /// Return the smallest view with the same center containing this one
+    /// and with the aspect ratio of an image of size `bounds`, so that the
+    /// image isn't stretched.
+    pub fn fit(&self, bounds: (usize, usize)) -> View {
+        let aspect = bounds.0 as f64 / bounds.1.max(1) as f64;
+        let (width, height) = if self.width.abs() < self.height.abs() * aspect {
+            (self.height.abs() * aspect * self.width.signum(), self.height)
+        } else {
+            (self.width, self.width.abs() / aspect * self.height.signum())
+        };
+        View { center: self.center.clone(), width, height }
+    }
+
+    /// Upper-left and lower-right corners of the view.
+    pub fn corners(&self) -> (BigComplex, BigComplex) {
+        // Keep enough fractional bits for the offsets on top of the center's.
+        let scale = self.center.scale()
+            + (-self.width.abs().min(self.height.abs()).log2()).ceil().max(0.0) as u32;
+        let center = self.center.with_scale(scale);
+        let (half_width, half_height) = (Fixed::from_f64(self.width / 2.0, scale),
+                                         Fixed::from_f64(self.height / 2.0, scale));
+        let upper_left = BigComplex { re: &center.re - &half_width, im: &center.im + &half_height };
+        let lower_right = BigComplex { re: &center.re + &half_width, im: &center.im - &half_height };
+        (upper_left, lower_right)
+    }
+
+    /// Upper-left and lower-right corners of the view rounded to `f64`.
+    pub fn complex_corners(&self) -> (Complex<f64>, Complex<f64>) {
+        let center = self.center.to_complex();
+        let half_size = Complex { re: self.width / 2.0, im: -self.height / 2.0 };
+        (center - half_size, center + half_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn big(re: &str, im: &str) -> BigComplex {
+        BigComplex { re: re.parse().unwrap(), im: im.parse().unwrap() }
+    }
+
+    #[test]
+    fn test_corners_round_trip() {
+        let (upper_left, lower_right) = (big("-2", "1.25"), big("1", "-1.25"));
+
+        let view = View::from_corners(&upper_left, &lower_right);
+
+        assert_eq!(view.center.to_complex(), Complex { re: -0.5, im: 0.0 });
+        assert_eq!((view.width, view.height), (3.0, 2.5));
+        assert_eq!(view.complex_corners(), (upper_left.to_complex(), lower_right.to_complex()));
+        let (ul, lr) = view.corners();
+        assert_eq!((ul.to_complex(), lr.to_complex()), view.complex_corners());
+    }
+
+    #[test]
+    fn test_from_zoom() {
+        let view = View::from_zoom(big("-0.75", "0.1"), 2.0, (100, 50));
+
+        assert_eq!((view.width, view.height), (2.0, 1.0));
+        assert_eq!(view.zoom(), 2.0);
+        assert_eq!(view.complex_corners(), (Complex { re: -1.75, im: 0.6 }, Complex { re: 0.25, im: -0.4 }));
+    }
+
+    #[test]
+    fn test_fit() {
+        let view = View { center: big("0", "0"), width: 4.0, height: 1.0 };
+
+        let taller = view.fit((100, 100));
+        let wider = View { width: 1.0, height: 2.0, ..view.clone() }.fit((200, 100));
+
+        assert_eq!((taller.width, taller.height), (4.0, 4.0));
+        assert_eq!((wider.width, wider.height), (4.0, 2.0));
+        assert_eq!(view.fit((400, 100)), view);
+    }
+
+    #[test]
+    fn test_deep_corners_keep_precision() {
+        let center = big("-1.7685736562992577", "0.000964296850972570");
+        let view = View::from_zoom(center.clone(), 1e40, (100, 100));
+
+        let (upper_left, lower_right) = view.corners();
+
+        let back = View::from_corners(&upper_left, &lower_right);
+        assert!((back.width / view.width - 1.0).abs() < 1e-9);
+        let scale = back.center.scale();
+        let offset = &back.center.re - &center.with_scale(scale).re;
+        assert!(offset.to_f64().abs() < view.width * 1e-9);
+    }
+}
@@ -1,21 +1,26 @@
 use std::{fs, env, thread};
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
 use std::time::Duration;
-use web::{Config, ThreadPool};
+use web::handler::Handler;
+use web::http::Request;
+use web::response::{Response, StatusCode};
+use web::router::Router;
+use web::Config;
 
 fn main() {
     let config = Config::default();
-    let listener = TcpListener::bind(config.address()).unwrap();
-    let pool = ThreadPool::new(4);
+    let mut router: Router<Box<dyn Handler>> = Router::new();
 
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
+    router
+        .get("/", Box::new(|_: Request| Response::html(read_template("hello"))))
+        .get("/sleep", Box::new(|_: Request| {
+            thread::sleep(Duration::from_secs(5));
+            Response::html(read_template("hello"))
+        }))
+        .get("/*", Box::new(|_: Request| {
+            Response::html(read_template("404")).with_status(StatusCode::NOT_FOUND)
+        }));
 
-        pool.execute(|| {
-            handle_connection(stream);
-        });
-    }
+    web::server::serve(&config, 4, router).unwrap();
 
     println!("Shutting down.");
 }
@@ -25,32 +30,3 @@ fn read_template(name: &str) -> String {
     let path = format!("{}/templates/{}.html", root, name);
     fs::read_to_string(path).unwrap()
 }
-
-fn handle_connection(mut stream: TcpStream) {
-    let mut buffer = [0; 1024];
-    stream.read(&mut buffer).unwrap();
-
-    let get = b"GET / HTTP/1.1\r\n";
-    let sleep= b"GET /sleep HTTP/1.1\r\n";
-
-    let (status, template) = if buffer.starts_with(get) {
-        ("HTTP/1.1 200 OK", "hello")
-    } else if buffer.starts_with(sleep) {
-        thread::sleep(Duration::from_secs(5));
-        ("HTTP/1.1 200 OK", "hello")
-    } else {
-        ("HTTP/1.1 404 NOT FOUND", "404")
-    };
-
-    let contents = read_template(template);
-
-    let response = format!(
-        "{}\r\nContent-Length: {}\r\n\r\n{}",
-        status,
-        contents.len(),
-        contents
-    );
-
-    stream.write(response.as_bytes()).unwrap();
-    stream.flush().unwrap();
-}
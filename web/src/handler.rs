@@ -0,0 +1,93 @@
+//! The `Handler` trait turning requests into responses.
+use crate::http::Request;
+use crate::response::{Response, StatusCode};
+use crate::router::{RouteError, Router};
+
+/// Something that answers requests.
+///
+/// It is implemented for closures and functions taking a `Request` and
+/// returning a `Response`, and for routers of handlers.
+pub trait Handler: Send + Sync + 'static {
+    fn handle(&self, request: Request) -> Response;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(Request) -> Response + Send + Sync + 'static,
+{
+    fn handle(&self, request: Request) -> Response {
+        self(request)
+    }
+}
+
+impl Handler for Box<dyn Handler> {
+    fn handle(&self, request: Request) -> Response {
+        (**self).handle(request)
+    }
+}
+
+impl<H: Handler> Handler for Router<H> {
+    /// Pass the request to the handler of its route, with the parameters of
+    /// the route in `request.params`, or answer `404 Not Found` or `405
+    /// Method Not Allowed` when there is no such route.
+    fn handle(&self, mut request: Request) -> Response {
+        match self.find(request.method, request.path()) {
+            Ok(found) => {
+                request.params = found.params;
+                found.handler.handle(request)
+            }
+            Err(RouteError::NotFound) => Response::error(StatusCode::NOT_FOUND),
+            Err(RouteError::MethodNotAllowed(allowed)) => {
+                let allowed: Vec<&str> = allowed.iter().map(|method| method.as_str()).collect();
+                Response::error(StatusCode::METHOD_NOT_ALLOWED).with_header("Allow", &allowed.join(", "))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+
+    fn greet(request: Request) -> Response {
+        Response::text(format!("hello, {}", request.param("name").unwrap_or("stranger")))
+    }
+
+    fn router() -> Router<Box<dyn Handler>> {
+        let mut router: Router<Box<dyn Handler>> = Router::new();
+        router
+            .get("/hello/:name", Box::new(greet))
+            .post("/echo", Box::new(|request: Request| Response::bytes("application/octet-stream", request.body)));
+        router
+    }
+
+    #[test]
+    fn test_closure_handler() {
+        let suffix = String::from("!");
+        let handler = move |request: Request| Response::text(format!("{}{}", request.path(), suffix));
+
+        let response = handler.handle(Request::new(Method::Get, "/page?x=1"));
+
+        assert_eq!(response.body, b"/page!");
+    }
+
+    #[test]
+    fn test_router_handler() {
+        let router = router();
+
+        let response = router.handle(Request::new(Method::Get, "/hello/J%C3%BCrgen"));
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.body, "hello, J\u{fc}rgen".as_bytes());
+
+        let response = router.handle(Request::new(Method::Post, "/echo").with_body("ping"));
+        assert_eq!(response.body, b"ping");
+
+        let response = router.handle(Request::new(Method::Get, "/missing"));
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+
+        let response = router.handle(Request::new(Method::Delete, "/hello/you"));
+        assert_eq!(response.status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers.get("Allow"), Some("GET, HEAD"));
+    }
+}
@@ -0,0 +1,766 @@
+//! HTTP/1.1 request parsing.
+//!
+//! `RequestReader` reads requests from any `Read`, buffering what it has read
+//! beyond the end of one request for the next, so requests may span several
+//! reads and several requests may arrive in one.
+use crate::response::StatusCode;
+use crate::router::Params;
+use std::fmt;
+use std::io::{self, Read};
+use std::str;
+
+/// Largest size of a request line and its headers.
+pub const MAX_HEAD_SIZE: usize = 8 * 1024;
+
+/// Largest size of a request body, whether sized by `Content-Length` or
+/// chunked.
+pub const MAX_BODY_SIZE: usize = 8 * 1024 * 1024;
+
+/// Number of bytes requested from the reader at a time.
+const READ_SIZE: usize = 4096;
+
+/// Request method.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch,
+}
+
+impl Method {
+    fn parse(s: &str) -> Option<Method> {
+        match s {
+            "GET" => Some(Method::Get),
+            "HEAD" => Some(Method::Head),
+            "POST" => Some(Method::Post),
+            "PUT" => Some(Method::Put),
+            "DELETE" => Some(Method::Delete),
+            "CONNECT" => Some(Method::Connect),
+            "OPTIONS" => Some(Method::Options),
+            "TRACE" => Some(Method::Trace),
+            "PATCH" => Some(Method::Patch),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Connect => "CONNECT",
+            Method::Options => "OPTIONS",
+            Method::Trace => "TRACE",
+            Method::Patch => "PATCH",
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Protocol version of a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+        })
+    }
+}
+
+/// Header fields in the order they were received.
+///
+/// Names are compared case-insensitively, as HTTP requires.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Headers {
+    fields: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Headers {
+        Headers::default()
+    }
+
+    /// Append a field, keeping the fields with the same name.
+    pub fn add(&mut self, name: &str, value: &str) {
+        self.fields.push((String::from(name), String::from(value)));
+    }
+
+    /// The value of the first field called `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The values of all fields called `name`.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.fields
+            .iter()
+            .filter(move |(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Whether any field called `name` lists `token` among its
+    /// comma-separated values, ignoring case.
+    pub fn contains_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name)
+            .flat_map(|value| value.split(','))
+            .any(|item| item.trim().eq_ignore_ascii_case(token))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
+
+/// A parsed HTTP request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub method: Method,
+    /// The request target as sent, like `/search?q=rust` or `*`.
+    pub target: String,
+    pub version: Version,
+    pub headers: Headers,
+    /// The body with any chunked encoding removed.
+    pub body: Vec<u8>,
+    /// The values of the path parameters of the route handling the request.
+    pub params: Params,
+}
+
+impl Request {
+    /// An HTTP/1.1 request without headers or a body, mostly for testing
+    /// handlers.
+    pub fn new(method: Method, target: &str) -> Request {
+        Request {
+            method,
+            target: String::from(target),
+            version: Version::Http11,
+            headers: Headers::new(),
+            body: Vec::new(),
+            params: Params::default(),
+        }
+    }
+
+    /// Add a header, keeping the headers with the same name.
+    pub fn with_header(mut self, name: &str, value: &str) -> Request {
+        self.headers.add(name, value);
+        self
+    }
+
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Request {
+        self.body = body.into();
+        self
+    }
+
+    /// The value of the path parameter called `name`.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name)
+    }
+
+    /// The body as text, if it is UTF-8.
+    pub fn body_text(&self) -> Option<&str> {
+        str::from_utf8(&self.body).ok()
+    }
+
+    /// The target without its query string.
+    pub fn path(&self) -> &str {
+        match self.target.find('?') {
+            Some(index) => &self.target[..index],
+            None => &self.target,
+        }
+    }
+
+    /// The query string after `?`, if any.
+    pub fn query(&self) -> Option<&str> {
+        self.target.find('?').map(|index| &self.target[index + 1..])
+    }
+
+    /// The name-value pairs of the query string, percent-decoded.
+    pub fn query_pairs(&self) -> Vec<(String, String)> {
+        self.query()
+            .unwrap_or("")
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| match pair.find('=') {
+                Some(index) => (decode_query(&pair[..index]), decode_query(&pair[index + 1..])),
+                None => (decode_query(pair), String::new()),
+            })
+            .collect()
+    }
+
+    /// The decoded value of the first query parameter called `name`.
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        self.query_pairs()
+            .into_iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value)
+    }
+}
+
+/// Decode `%XX` escapes in `s`, keeping malformed escapes as they are and
+/// replacing bytes that aren't UTF-8.
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let escaped = bytes.get(index + 1..index + 3)
+            .filter(|hex| bytes[index] == b'%' && hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            None => {
+                decoded.push(bytes[index]);
+                index += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Decode a query string component, where `+` stands for a space.
+fn decode_query(s: &str) -> String {
+    percent_decode(&s.replace('+', " "))
+}
+
+/// Reasons a request couldn't be read.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The connection closed before the end of the request.
+    UnexpectedEof,
+    /// The request doesn't follow the HTTP/1.1 syntax.
+    Malformed(String),
+    /// The request line and headers exceed `MAX_HEAD_SIZE`.
+    HeadTooLarge,
+    /// The body exceeds `MAX_BODY_SIZE`.
+    BodyTooLarge,
+    /// A read timed out in the middle of the request.
+    Timeout,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "I/O error: {}", error),
+            Error::UnexpectedEof => f.write_str("connection closed in the middle of a request"),
+            Error::Malformed(message) => write!(f, "malformed request: {}", message),
+            Error::HeadTooLarge => write!(f, "request head larger than {} bytes", MAX_HEAD_SIZE),
+            Error::BodyTooLarge => write!(f, "request body larger than {} bytes", MAX_BODY_SIZE),
+            Error::Timeout => f.write_str("timed out in the middle of a request"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+    /// The status of the response rejecting the request, or `None` when the
+    /// connection failed and no response can be sent.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            Error::Io(_) => None,
+            Error::UnexpectedEof | Error::Malformed(_) => Some(StatusCode::BAD_REQUEST),
+            Error::HeadTooLarge => Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE),
+            Error::BodyTooLarge => Some(StatusCode::PAYLOAD_TOO_LARGE),
+            Error::Timeout => Some(StatusCode::REQUEST_TIMEOUT),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error::Io(error)
+    }
+}
+
+fn malformed<T>(message: &str) -> Result<T, Error> {
+    Err(Error::Malformed(String::from(message)))
+}
+
+/// Reads requests one after another from `reader`.
+pub struct RequestReader<R> {
+    reader: R,
+    buffer: Vec<u8>,
+}
+
+impl<R: Read> RequestReader<R> {
+    pub fn new(reader: R) -> RequestReader<R> {
+        RequestReader { reader, buffer: Vec::new() }
+    }
+
+    /// The underlying reader, for instance to write responses to a stream.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    /// Read the next request, or return `None` if the reader ends before
+    /// its first byte.
+    ///
+    /// A read failing before the first byte of the request, as a read
+    /// timeout on an idle connection does, returns `Error::Io`; a read
+    /// timing out in the middle of the request returns `Error::Timeout`.
+    pub fn read_request(&mut self) -> Result<Option<Request>, Error> {
+        let head_end = loop {
+            // Empty lines before a request are ignored, as some clients
+            // send one after the body of the previous request.
+            while self.buffer.starts_with(b"\r\n") {
+                self.consume(2);
+            }
+            if let Some(index) = find(&self.buffer, b"\r\n\r\n") {
+                break index;
+            }
+            if self.buffer.len() >= MAX_HEAD_SIZE {
+                return Err(Error::HeadTooLarge);
+            }
+            let idle = self.buffer.is_empty();
+            match self.fill() {
+                Ok(0) if idle => return Ok(None),
+                Ok(0) => return Err(Error::UnexpectedEof),
+                Ok(_) => {}
+                Err(error) if idle => return Err(error),
+                Err(error) => return Err(timed_out(error)),
+            }
+        };
+        if head_end + 4 > MAX_HEAD_SIZE {
+            return Err(Error::HeadTooLarge);
+        }
+
+        let head = self.consume(head_end + 4);
+        let head = match str::from_utf8(&head[..head_end]) {
+            Ok(head) => head,
+            Err(_) => return malformed("request head isn't UTF-8"),
+        };
+        let mut lines = head.split("\r\n");
+        let (method, target, version) = parse_request_line(lines.next().unwrap_or(""))?;
+        let mut headers = Headers::new();
+        for line in lines {
+            let (name, value) = parse_header(line)?;
+            headers.add(name, value);
+        }
+        if version == Version::Http11 && headers.get_all("Host").count() != 1 {
+            return malformed("an HTTP/1.1 request needs exactly one Host header");
+        }
+
+        let body = self.read_body(&headers).map_err(timed_out)?;
+        Ok(Some(Request {
+            method,
+            target: String::from(target),
+            version,
+            headers,
+            body,
+            params: Params::default(),
+        }))
+    }
+
+    /// Read the body framed as `headers` describe.
+    fn read_body(&mut self, headers: &Headers) -> Result<Vec<u8>, Error> {
+        let codings: Vec<&str> = headers.get_all("Transfer-Encoding")
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect();
+        if !codings.is_empty() {
+            if headers.get("Content-Length").is_some() {
+                return malformed("both Transfer-Encoding and Content-Length");
+            }
+            // Chunked is the only coding supported.
+            if codings.len() != 1 || !codings[0].eq_ignore_ascii_case("chunked") {
+                return malformed("unsupported Transfer-Encoding");
+            }
+            return self.read_chunked_body();
+        }
+
+        let mut lengths = headers.get_all("Content-Length")
+            .flat_map(|value| value.split(','))
+            .map(|value| parse_content_length(value.trim()));
+        let length = match lengths.next() {
+            None => 0,
+            Some(length) => {
+                let length = length?;
+                for other in lengths {
+                    if other? != length {
+                        return malformed("conflicting Content-Length values");
+                    }
+                }
+                length
+            }
+        };
+        if length > MAX_BODY_SIZE {
+            return Err(Error::BodyTooLarge);
+        }
+        self.fill_to(length)?;
+        Ok(self.consume(length))
+    }
+
+    /// Read a chunked body and its trailer, which is discarded.
+    fn read_chunked_body(&mut self) -> Result<Vec<u8>, Error> {
+        let mut body = Vec::new();
+        loop {
+            let line = self.read_line()?;
+            let size = line.split(';').next().unwrap_or("").trim_end_matches([' ', '\t']);
+            if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return malformed("invalid chunk size");
+            }
+            let size = match usize::from_str_radix(size, 16) {
+                Ok(size) if size <= MAX_BODY_SIZE - body.len() => size,
+                _ => return Err(Error::BodyTooLarge),
+            };
+            if size == 0 {
+                break;
+            }
+            self.fill_to(size + 2)?;
+            let chunk = self.consume(size + 2);
+            if !chunk.ends_with(b"\r\n") {
+                return malformed("chunk data not followed by CRLF");
+            }
+            body.extend_from_slice(&chunk[..size]);
+        }
+
+        let mut trailer_size = 0;
+        loop {
+            let line = self.read_line()?;
+            if line.is_empty() {
+                break;
+            }
+            trailer_size += line.len() + 2;
+            if trailer_size > MAX_HEAD_SIZE {
+                return Err(Error::HeadTooLarge);
+            }
+            parse_header(&line)?;
+        }
+        Ok(body)
+    }
+
+    /// Read a line ending with CRLF, without the CRLF.
+    fn read_line(&mut self) -> Result<String, Error> {
+        loop {
+            if let Some(index) = find(&self.buffer, b"\r\n") {
+                let line = self.consume(index + 2);
+                return match String::from_utf8(line[..index].to_vec()) {
+                    Ok(line) => Ok(line),
+                    Err(_) => malformed("line isn't UTF-8"),
+                };
+            }
+            if self.buffer.len() > MAX_HEAD_SIZE {
+                return Err(Error::HeadTooLarge);
+            }
+            if self.fill()? == 0 {
+                return Err(Error::UnexpectedEof);
+            }
+        }
+    }
+
+    /// Read until the buffer holds at least `size` bytes.
+    fn fill_to(&mut self, size: usize) -> Result<(), Error> {
+        while self.buffer.len() < size {
+            if self.fill()? == 0 {
+                return Err(Error::UnexpectedEof);
+            }
+        }
+        Ok(())
+    }
+
+    /// Append the next read to the buffer, returning its size.
+    fn fill(&mut self) -> Result<usize, Error> {
+        let mut chunk = [0; READ_SIZE];
+        loop {
+            match self.reader.read(&mut chunk) {
+                Ok(size) => {
+                    self.buffer.extend_from_slice(&chunk[..size]);
+                    return Ok(size);
+                }
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => return Err(Error::Io(error)),
+            }
+        }
+    }
+
+    /// Remove the first `size` bytes of the buffer and return them.
+    fn consume(&mut self, size: usize) -> Vec<u8> {
+        let rest = self.buffer.split_off(size);
+        std::mem::replace(&mut self.buffer, rest)
+    }
+}
+
+/// Turn an error of a read that timed out into `Error::Timeout`.
+fn timed_out(error: Error) -> Error {
+    match error {
+        Error::Io(ref io) if is_timeout(io) => Error::Timeout,
+        error => error,
+    }
+}
+
+/// Whether `error` is a read or write timing out, which platforms report
+/// with different kinds.
+pub fn is_timeout(error: &io::Error) -> bool {
+    matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+/// Whether `s` is a token, the syntax of methods and header names.
+fn is_token(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+fn parse_request_line(line: &str) -> Result<(Method, &str, Version), Error> {
+    let mut parts = line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) => (method, target, version),
+        _ => return malformed("request line isn't `method target version`"),
+    };
+
+    let method = match Method::parse(method) {
+        Some(method) => method,
+        None if is_token(method) => return malformed("unsupported method"),
+        None => return malformed("invalid method"),
+    };
+    let valid_target = (target.starts_with('/') || (target == "*" && method == Method::Options))
+        && target.bytes().all(|b| b.is_ascii_graphic())
+        && !target.contains('#');
+    if !valid_target {
+        return malformed("invalid request target");
+    }
+    let version = match version {
+        "HTTP/1.1" => Version::Http11,
+        "HTTP/1.0" => Version::Http10,
+        _ => return malformed("unsupported HTTP version"),
+    };
+    Ok((method, target, version))
+}
+
+fn parse_header(line: &str) -> Result<(&str, &str), Error> {
+    if line.starts_with([' ', '\t']) {
+        return malformed("obsolete header line folding");
+    }
+    let index = match line.find(':') {
+        Some(index) => index,
+        None => return malformed("header without a colon"),
+    };
+    let name = &line[..index];
+    let value = line[index + 1..].trim_matches([' ', '\t']);
+    if !is_token(name) {
+        return malformed("invalid header name");
+    }
+    if value.chars().any(|c| c.is_ascii_control() && c != '\t') {
+        return malformed("control character in a header value");
+    }
+    Ok((name, value))
+}
+
+fn parse_content_length(value: &str) -> Result<usize, Error> {
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return malformed("invalid Content-Length");
+    }
+    // Lengths that don't fit are certainly too large.
+    value.parse().map_err(|_| Error::BodyTooLarge)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A reader that returns at most `step` bytes per read.
+    struct Trickle<'a> {
+        data: &'a [u8],
+        step: usize,
+    }
+
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            let size = self.step.min(buffer.len()).min(self.data.len());
+            buffer[..size].copy_from_slice(&self.data[..size]);
+            self.data = &self.data[size..];
+            Ok(size)
+        }
+    }
+
+    fn parse(data: &[u8]) -> Result<Option<Request>, Error> {
+        RequestReader::new(data).read_request()
+    }
+
+    fn assert_malformed(data: &[u8]) {
+        match parse(data) {
+            Err(Error::Malformed(_)) => {}
+            other => panic!("{:?} parsed as {:?}", String::from_utf8_lossy(data), other),
+        }
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let data = b"GET /search?q=rust+web&page=2 HTTP/1.1\r\n\
+                     Host: localhost:8080\r\n\
+                     Accept: text/html\r\n\
+                     accept:  application/json \r\n\r\n";
+
+        let request = parse(data).unwrap().unwrap();
+
+        assert_eq!(request.method, Method::Get);
+        assert_eq!(request.target, "/search?q=rust+web&page=2");
+        assert_eq!(request.version, Version::Http11);
+        assert_eq!(request.path(), "/search");
+        assert_eq!(request.query(), Some("q=rust+web&page=2"));
+        assert_eq!(request.query_param("q"), Some(String::from("rust web")));
+        assert_eq!(request.query_param("missing"), None);
+        assert_eq!(request.headers.get("host"), Some("localhost:8080"));
+        assert_eq!(request.headers.get_all("ACCEPT").collect::<Vec<_>>(), ["text/html", "application/json"]);
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn test_parse_content_length_body() {
+        let data = b"POST /users HTTP/1.1\r\nHost: a\r\nContent-Length: 11\r\n\r\nhello world";
+
+        let request = parse(data).unwrap().unwrap();
+
+        assert_eq!(request.method, Method::Post);
+        assert_eq!(request.body, b"hello world");
+    }
+
+    #[test]
+    fn test_parse_chunked_body() {
+        let data = b"PUT /file HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+                     5\r\nhello\r\n1;name=value\r\n \r\nA\r\n0123456789\r\n0\r\nExpires: never\r\n\r\n";
+
+        let request = parse(data).unwrap().unwrap();
+
+        assert_eq!(request.body, b"hello 0123456789");
+    }
+
+    #[test]
+    fn test_parse_across_reads() {
+        let data = b"POST /echo HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+                     3\r\nabc\r\n0\r\n\r\n\
+                     GET /next HTTP/1.0\r\nContent-Length: 2\r\n\r\nhi";
+
+        for step in 1..8 {
+            let mut reader = RequestReader::new(Trickle { data, step });
+
+            let first = reader.read_request().unwrap().unwrap();
+            let second = reader.read_request().unwrap().unwrap();
+
+            assert_eq!(first.body, b"abc");
+            assert_eq!((second.method, second.path(), second.version), (Method::Get, "/next", Version::Http10));
+            assert_eq!(second.body, b"hi");
+            assert!(reader.read_request().unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_parse_large_request() {
+        let body = vec![b'x'; 3 * READ_SIZE + 17];
+        let mut data = format!("POST / HTTP/1.1\r\nHost: a\r\nContent-Length: {}\r\n\r\n", body.len()).into_bytes();
+        data.extend_from_slice(&body);
+
+        let request = parse(&data).unwrap().unwrap();
+
+        assert_eq!(request.body, body);
+    }
+
+    #[test]
+    fn test_parse_malformed() {
+        assert_malformed(b"GET / HTTP/1.1\r\n\r\n");
+        assert_malformed(b"GET /  HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert_malformed(b"get / HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert_malformed(b"BREW / HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert_malformed(b"GET index.html HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert_malformed(b"GET / HTTP/2.0\r\nHost: a\r\n\r\n");
+        assert_malformed(b"GET / HTTP/1.1\r\nHost: a\r\nHost: b\r\n\r\n");
+        assert_malformed(b"GET / HTTP/1.1\r\nHost: a\r\nBad Name: x\r\n\r\n");
+        assert_malformed(b"GET / HTTP/1.1\r\nHost: a\r\nNo-Colon\r\n\r\n");
+        assert_malformed(b"GET / HTTP/1.1\r\nHost: a\r\nX: 1\r\n 2\r\n\r\n");
+        assert_malformed(b"GET / HTTP/1.1\r\nHost: a\r\nContent-Length: -1\r\n\r\n");
+        assert_malformed(b"GET / HTTP/1.1\r\nHost: a\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nab");
+        assert_malformed(b"GET / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: gzip\r\n\r\n");
+        assert_malformed(b"GET / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\nContent-Length: 1\r\n\r\n");
+        assert_malformed(b"GET / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\nz\r\n");
+        assert_malformed(b"GET / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n1\r\nab\r\n0\r\n\r\n");
+    }
+
+    #[test]
+    fn test_parse_limits() {
+        let mut data = b"GET / HTTP/1.1\r\nHost: a\r\nX: ".to_vec();
+        data.extend_from_slice(&vec![b'x'; MAX_HEAD_SIZE]);
+        data.extend_from_slice(b"\r\n\r\n");
+        assert!(matches!(parse(&data), Err(Error::HeadTooLarge)));
+
+        let data = format!("POST / HTTP/1.1\r\nHost: a\r\nContent-Length: {}\r\n\r\n", MAX_BODY_SIZE + 1);
+        assert!(matches!(parse(data.as_bytes()), Err(Error::BodyTooLarge)));
+
+        let data = b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\nfffffffffffffffffffff\r\n";
+        assert!(matches!(parse(data), Err(Error::BodyTooLarge)));
+    }
+
+    #[test]
+    fn test_parse_end_of_input() {
+        assert!(parse(b"").unwrap().is_none());
+        assert!(matches!(parse(b"GET / HTTP/1.1\r\nHost"), Err(Error::UnexpectedEof)));
+        assert!(matches!(parse(b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nab"),
+                         Err(Error::UnexpectedEof)));
+    }
+
+    /// A reader whose reads time out.
+    struct Stalled;
+
+    impl Read for Stalled {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::WouldBlock, "timed out"))
+        }
+    }
+
+    #[test]
+    fn test_parse_timeout() {
+        let read = |data: &'static [u8]| RequestReader::new(data.chain(Stalled)).read_request();
+
+        assert!(matches!(read(b""), Err(Error::Io(ref error)) if is_timeout(error)));
+        assert!(matches!(read(b"GET / HT"), Err(Error::Timeout)));
+        assert!(matches!(read(b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nab"), Err(Error::Timeout)));
+
+        let mut reader = RequestReader::new((&b"\r\nGET / HTTP/1.0\r\n\r\n"[..]).chain(Stalled));
+        assert!(reader.read_request().unwrap().is_some());
+        assert!(matches!(reader.read_request(), Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("a%20b%2Fc"), "a b/c");
+        assert_eq!(percent_decode("%E2%82%AC"), "\u{20ac}");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+        assert_eq!(percent_decode("%+1%-1"), "%+1%-1");
+    }
+}
@@ -2,13 +2,24 @@ use std::{env, thread};
 use std::net::TcpListener;
 use std::thread::JoinHandle;
 use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+pub mod handler;
+pub mod http;
+pub mod response;
+pub mod router;
+pub mod server;
 
 static HOST_ENV: &str = "RUST_HOST";
 static PORT_ENV: &str = "RUST_PORT";
 
+/// How long a connection may stay idle by default.
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub struct Config {
     host: String,
     port: String,
+    idle_timeout: Duration,
 }
 
 impl Config {
@@ -16,9 +27,26 @@ impl Config {
         Config {
             host: String::from(host),
             port: String::from(port),
+            idle_timeout: IDLE_TIMEOUT,
         }
     }
 
+    /// Set how long a connection may take to send its next request in full
+    /// before the server closes it and frees its worker.
+    ///
+    /// # Panics
+    ///
+    /// The `with_idle_timeout` function will panic if the timeout is zero.
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Config {
+        assert!(idle_timeout > Duration::ZERO);
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+
     pub fn from_env() -> Config {
         Config::new(
             &env::var(HOST_ENV).unwrap(),
@@ -26,20 +54,26 @@ impl Config {
         )
     }
 
-    pub fn default() -> Config {
-        Config::new("127.0.0.1", "8080")
-    }
-
     pub fn address(&self) -> String {
         format!("{}:{}", self.host, self.port)
     }
 }
 
+impl Default for Config {
+    fn default() -> Config {
+        Config::new("127.0.0.1", "8080")
+    }
+}
+
+/// Bind to the configured address and accept a single connection on another
+/// thread, which returns whether accepting succeeded.
+///
+/// The listener is bound before this function returns, so connecting to the
+/// address right away doesn't race with the binding.
 pub fn listen(config: &Config) -> JoinHandle<bool> {
-    let address = config.address();
+    let listener = TcpListener::bind(config.address()).unwrap();
 
     let handle = thread::spawn(move || {
-        let listener = TcpListener::bind(address).unwrap();
         let stream = listener.incoming().next().unwrap();
         stream.is_ok()
     });
@@ -154,6 +188,8 @@ mod tests {
         let config = Config::new("localhost", "8080");
 
         assert_eq!(config.address(), "localhost:8080");
+        assert_eq!(config.idle_timeout(), IDLE_TIMEOUT);
+        assert_eq!(config.with_idle_timeout(Duration::from_secs(1)).idle_timeout(), Duration::from_secs(1));
     }
 
     #[test]
@@ -172,7 +208,7 @@ mod tests {
 
         let handle = listen(&config);
         let _ = TcpStream::connect(config.address()).unwrap();
-        let accepted = handle.join().is_ok();
+        let accepted = handle.join().unwrap();
 
         assert!(accepted);
     }
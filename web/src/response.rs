@@ -0,0 +1,245 @@
+//! HTTP responses and their status codes.
+use crate::http::{Headers, Method, Version};
+use serde::Serialize;
+use std::fmt;
+use std::io::{self, Write};
+
+/// Status code of a response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StatusCode(u16);
+
+impl StatusCode {
+    pub const OK: StatusCode = StatusCode(200);
+    pub const CREATED: StatusCode = StatusCode(201);
+    pub const NO_CONTENT: StatusCode = StatusCode(204);
+    pub const MOVED_PERMANENTLY: StatusCode = StatusCode(301);
+    pub const FOUND: StatusCode = StatusCode(302);
+    pub const SEE_OTHER: StatusCode = StatusCode(303);
+    pub const NOT_MODIFIED: StatusCode = StatusCode(304);
+    pub const BAD_REQUEST: StatusCode = StatusCode(400);
+    pub const UNAUTHORIZED: StatusCode = StatusCode(401);
+    pub const FORBIDDEN: StatusCode = StatusCode(403);
+    pub const NOT_FOUND: StatusCode = StatusCode(404);
+    pub const METHOD_NOT_ALLOWED: StatusCode = StatusCode(405);
+    pub const REQUEST_TIMEOUT: StatusCode = StatusCode(408);
+    pub const PAYLOAD_TOO_LARGE: StatusCode = StatusCode(413);
+    pub const REQUEST_HEADER_FIELDS_TOO_LARGE: StatusCode = StatusCode(431);
+    pub const INTERNAL_SERVER_ERROR: StatusCode = StatusCode(500);
+    pub const NOT_IMPLEMENTED: StatusCode = StatusCode(501);
+    pub const SERVICE_UNAVAILABLE: StatusCode = StatusCode(503);
+
+    /// Create a status code from its number.
+    ///
+    /// # Panics
+    ///
+    /// The `new` function will panic if the code isn't a three-digit number
+    /// from 100 to 599.
+    pub fn new(code: u16) -> StatusCode {
+        assert!((100..600).contains(&code), "invalid status code: {}", code);
+        StatusCode(code)
+    }
+
+    pub fn code(&self) -> u16 {
+        self.0
+    }
+
+    /// The standard reason phrase of the code, or an empty string for codes
+    /// without one here.
+    pub fn reason(&self) -> &'static str {
+        match self.0 {
+            200 => "OK",
+            201 => "Created",
+            204 => "No Content",
+            301 => "Moved Permanently",
+            302 => "Found",
+            303 => "See Other",
+            304 => "Not Modified",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            408 => "Request Timeout",
+            413 => "Payload Too Large",
+            431 => "Request Header Fields Too Large",
+            500 => "Internal Server Error",
+            501 => "Not Implemented",
+            503 => "Service Unavailable",
+            _ => "",
+        }
+    }
+
+    /// Whether responses with this status never have a body.
+    fn is_bodiless(&self) -> bool {
+        self.0 < 200 || self.0 == 204 || self.0 == 304
+    }
+}
+
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.0, self.reason())
+    }
+}
+
+/// An HTTP response.
+///
+/// `Content-Length` is added when the response is written, so it shouldn't
+/// be among the headers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// An empty response with `status`.
+    pub fn new(status: StatusCode) -> Response {
+        Response { status, headers: Headers::new(), body: Vec::new() }
+    }
+
+    /// A `200 OK` response with a body of `content_type`.
+    pub fn bytes(content_type: &str, body: Vec<u8>) -> Response {
+        Response::new(StatusCode::OK)
+            .with_header("Content-Type", content_type)
+            .with_body(body)
+    }
+
+    /// A `200 OK` plain text response.
+    pub fn text(body: impl Into<String>) -> Response {
+        Response::bytes("text/plain; charset=utf-8", body.into().into_bytes())
+    }
+
+    /// A `200 OK` HTML response.
+    pub fn html(body: impl Into<String>) -> Response {
+        Response::bytes("text/html; charset=utf-8", body.into().into_bytes())
+    }
+
+    /// A `200 OK` response with `value` serialized as JSON, or a `500
+    /// Internal Server Error` if it can't be.
+    pub fn json<T: Serialize + ?Sized>(value: &T) -> Response {
+        match serde_json::to_vec(value) {
+            Ok(body) => Response::bytes("application/json", body),
+            Err(error) => Response::text(format!("cannot serialize the response: {}", error))
+                .with_status(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+
+    /// A plain text response with `status` and its reason phrase as the body.
+    pub fn error(status: StatusCode) -> Response {
+        Response::text(status.to_string()).with_status(status)
+    }
+
+    /// A redirect to `location` with `status`, like `StatusCode::FOUND`.
+    pub fn redirect(status: StatusCode, location: &str) -> Response {
+        Response::new(status).with_header("Location", location)
+    }
+
+    pub fn with_status(mut self, status: StatusCode) -> Response {
+        self.status = status;
+        self
+    }
+
+    /// Add a header, keeping the headers with the same name.
+    ///
+    /// Carriage returns and line feeds are left out when the response is
+    /// written, so that a value can't end the header and start another.
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.add(name, value);
+        self
+    }
+
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Response {
+        self.body = body.into();
+        self
+    }
+
+    /// Write the response to a request with `method` and `version`, which
+    /// leaves out the body for `HEAD` requests and the line breaks of the
+    /// headers.
+    pub fn write_to<W: Write>(&self, writer: &mut W, method: Method, version: Version) -> io::Result<()> {
+        let mut head = format!("{} {}\r\n", version, self.status);
+        for (name, value) in self.headers.iter() {
+            let line = format!("{}: {}", name, value).replace(['\r', '\n'], "");
+            head.push_str(&line);
+            head.push_str("\r\n");
+        }
+        if !self.status.is_bodiless() {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        head.push_str("\r\n");
+
+        writer.write_all(head.as_bytes())?;
+        if method != Method::Head && !self.status.is_bodiless() {
+            writer.write_all(&self.body)?;
+        }
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn written(response: &Response, method: Method) -> String {
+        let mut output = Vec::new();
+        response.write_to(&mut output, method, Version::Http11).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_status_code() {
+        assert_eq!(StatusCode::NOT_FOUND.code(), 404);
+        assert_eq!(StatusCode::NOT_FOUND.to_string(), "404 Not Found");
+        assert_eq!(StatusCode::new(299).to_string(), "299 ");
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid status code")]
+    fn test_status_code_invalid() {
+        StatusCode::new(1000);
+    }
+
+    #[test]
+    fn test_response_builders() {
+        let response = Response::html("<p>hi</p>").with_status(StatusCode::CREATED).with_header("X-Id", "7");
+        assert_eq!(response.status, StatusCode::CREATED);
+        assert_eq!(response.headers.get("content-type"), Some("text/html; charset=utf-8"));
+        assert_eq!(response.headers.get("x-id"), Some("7"));
+        assert_eq!(response.body, b"<p>hi</p>");
+
+        let response = Response::json(&HashMap::from([("id", 7)]));
+        assert_eq!(response.headers.get("Content-Type"), Some("application/json"));
+        assert_eq!(response.body, br#"{"id":7}"#);
+
+        let response = Response::json(&HashMap::from([((1, 2), 3)]));
+        assert_eq!(response.status, StatusCode::INTERNAL_SERVER_ERROR);
+
+        let response = Response::bytes("image/png", vec![0x89, b'P']);
+        assert_eq!(response.body, [0x89, b'P']);
+        assert_eq!(Response::text("plain").headers.get("Content-Type"), Some("text/plain; charset=utf-8"));
+    }
+
+    #[test]
+    fn test_write_response() {
+        let response = Response::text("hello").with_header("Cache-Control", "no-cache");
+
+        assert_eq!(written(&response, Method::Get),
+                   "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\n\
+                    Cache-Control: no-cache\r\nContent-Length: 5\r\n\r\nhello");
+        assert!(written(&response, Method::Head).ends_with("Content-Length: 5\r\n\r\n"));
+        assert_eq!(written(&Response::new(StatusCode::NO_CONTENT).with_body("ignored"), Method::Get),
+                   "HTTP/1.1 204 No Content\r\n\r\n");
+    }
+
+    #[test]
+    fn test_write_response_line_breaks() {
+        let response = Response::redirect(StatusCode::FOUND, "/next\r\nSet-Cookie: id=1")
+            .with_header("X-Note\n", "a\rb");
+
+        assert_eq!(written(&response, Method::Get),
+                   "HTTP/1.1 302 Found\r\nLocation: /nextSet-Cookie: id=1\r\nX-Note: ab\r\n\
+                    Content-Length: 0\r\n\r\n");
+    }
+}
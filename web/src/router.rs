@@ -0,0 +1,347 @@
+//! Routing of requests to handlers by method and path.
+//!
+//! A path pattern is a sequence of `/`-separated segments, each of them one
+//! of:
+//!
+//! * a literal, like `users`, matching only itself;
+//! * a parameter, like `:id`, matching any single non-empty segment;
+//! * a wildcard, `*` or a named one like `*path`, which may only come last
+//!   and matches the rest of the path, even an empty one: `/static/*`
+//!   matches `/static/` and `/static/css/site.css` but not `/static`.
+//!
+//! When several patterns match a path, the most specific one wins: the one
+//! with a literal at the first segment where they differ, or else a
+//! parameter rather than a wildcard. `/users/new` thus takes precedence over
+//! `/users/:id`, whatever the order they were added in.
+use crate::http::{percent_decode, Method};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Wildcard(Option<String>),
+}
+
+impl Segment {
+    /// Rank of the segment in the precedence of routes, lower first.
+    fn rank(&self) -> u8 {
+        match self {
+            Segment::Literal(_) => 0,
+            Segment::Param(_) => 1,
+            Segment::Wildcard(_) => 2,
+        }
+    }
+}
+
+/// A parsed path pattern.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pattern {
+    segments: Vec<Segment>,
+}
+
+impl Pattern {
+    /// Parse a pattern like `/users/:id/files/*path`.
+    ///
+    /// # Panics
+    ///
+    /// The `parse` function will panic if the pattern doesn't start with
+    /// `/`, has a parameter without a name or a wildcard before its last
+    /// segment, or uses the same name twice.
+    pub fn parse(pattern: &str) -> Pattern {
+        assert!(pattern.starts_with('/'), "pattern should start with '/': {}", pattern);
+
+        let parts: Vec<&str> = pattern[1..].split('/').collect();
+        let mut segments = Vec::with_capacity(parts.len());
+        for (index, part) in parts.iter().enumerate() {
+            let segment = if let Some(name) = part.strip_prefix(':') {
+                assert!(!name.is_empty(), "parameter without a name: {}", pattern);
+                Segment::Param(String::from(name))
+            } else if let Some(name) = part.strip_prefix('*') {
+                assert!(index == parts.len() - 1, "wildcard before the last segment: {}", pattern);
+                Segment::Wildcard(Some(String::from(name)).filter(|name| !name.is_empty()))
+            } else {
+                Segment::Literal(String::from(*part))
+            };
+            segments.push(segment);
+        }
+
+        let names: Vec<&String> = segments.iter()
+            .filter_map(|segment| match segment {
+                Segment::Param(name) | Segment::Wildcard(Some(name)) => Some(name),
+                _ => None,
+            })
+            .collect();
+        for (index, name) in names.iter().enumerate() {
+            assert!(!names[..index].contains(name), "parameter used twice: {}", pattern);
+        }
+
+        Pattern { segments }
+    }
+
+    /// Match `path` against the pattern, returning the parameters it binds.
+    pub fn matches(&self, path: &str) -> Option<Params> {
+        let mut parts = path.strip_prefix('/')?.split('/');
+        let mut params = Params::default();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) => {
+                    if percent_decode(parts.next()?) != *literal {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    let part = parts.next().filter(|part| !part.is_empty())?;
+                    params.values.push((name.clone(), percent_decode(part)));
+                }
+                Segment::Wildcard(name) => {
+                    let first = parts.next()?;
+                    let rest: Vec<String> = std::iter::once(first)
+                        .chain(parts.by_ref())
+                        .map(percent_decode)
+                        .collect();
+                    if let Some(name) = name {
+                        params.values.push((name.clone(), rest.join("/")));
+                    }
+                }
+            }
+        }
+        match parts.next() {
+            None => Some(params),
+            Some(_) => None,
+        }
+    }
+
+    fn ranks(&self) -> impl Iterator<Item = u8> + '_ {
+        self.segments.iter().map(Segment::rank)
+    }
+}
+
+/// Values bound to the parameters and wildcard of a pattern, percent-decoded.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Params {
+    values: Vec<(String, String)>,
+}
+
+impl Params {
+    /// The value bound to `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.values.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+/// The handler of a route matching a request and the parameters it bound.
+#[derive(Debug)]
+pub struct Match<'a, H> {
+    pub handler: &'a H,
+    pub params: Params,
+}
+
+/// Reasons no route matches a request.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RouteError {
+    /// No pattern matches the path.
+    NotFound,
+    /// Patterns match the path, but only with these other methods.
+    MethodNotAllowed(Vec<Method>),
+}
+
+struct Route<H> {
+    method: Method,
+    pattern: Pattern,
+    handler: H,
+}
+
+/// Routes requests to handlers of any type `H`.
+pub struct Router<H> {
+    routes: Vec<Route<H>>,
+}
+
+impl<H> Default for Router<H> {
+    fn default() -> Router<H> {
+        Router { routes: Vec::new() }
+    }
+}
+
+impl<H> Router<H> {
+    pub fn new() -> Router<H> {
+        Router::default()
+    }
+
+    /// Route requests with `method` and a path matching `pattern` to
+    /// `handler`. A later route with the same method and pattern replaces
+    /// an earlier one.
+    ///
+    /// # Panics
+    ///
+    /// The `add` function will panic if the pattern is invalid, as
+    /// `Pattern::parse` describes.
+    pub fn add(&mut self, method: Method, pattern: &str, handler: H) -> &mut Router<H> {
+        let pattern = Pattern::parse(pattern);
+        self.routes.retain(|route| route.method != method || route.pattern != pattern);
+        self.routes.push(Route { method, pattern, handler });
+        self
+    }
+
+    pub fn get(&mut self, pattern: &str, handler: H) -> &mut Router<H> {
+        self.add(Method::Get, pattern, handler)
+    }
+
+    pub fn post(&mut self, pattern: &str, handler: H) -> &mut Router<H> {
+        self.add(Method::Post, pattern, handler)
+    }
+
+    pub fn put(&mut self, pattern: &str, handler: H) -> &mut Router<H> {
+        self.add(Method::Put, pattern, handler)
+    }
+
+    pub fn delete(&mut self, pattern: &str, handler: H) -> &mut Router<H> {
+        self.add(Method::Delete, pattern, handler)
+    }
+
+    /// Find the most specific route for `method` and `path`.
+    ///
+    /// `HEAD` requests fall back to the `GET` routes when no `HEAD` route
+    /// matches, since a `HEAD` response is a `GET` response without a body.
+    pub fn find(&self, method: Method, path: &str) -> Result<Match<'_, H>, RouteError> {
+        let mut matching: Vec<(&Route<H>, Params)> = self.routes
+            .iter()
+            .filter_map(|route| route.pattern.matches(path).map(|params| (route, params)))
+            .collect();
+        if matching.is_empty() {
+            return Err(RouteError::NotFound);
+        }
+        matching.sort_by(|(a, _), (b, _)| a.pattern.ranks().cmp(b.pattern.ranks()));
+
+        let fallback = if method == Method::Head { Some(Method::Get) } else { None };
+        let best = |method| matching.iter().position(|(route, _)| route.method == method);
+        match best(method).or_else(|| fallback.and_then(best)) {
+            Some(index) => {
+                let (route, params) = matching.swap_remove(index);
+                Ok(Match { handler: &route.handler, params })
+            }
+            None => {
+                let mut allowed: Vec<Method> = Vec::new();
+                for (route, _) in &matching {
+                    if !allowed.contains(&route.method) {
+                        allowed.push(route.method);
+                    }
+                    if route.method == Method::Get && !allowed.contains(&Method::Head) {
+                        allowed.push(Method::Head);
+                    }
+                }
+                Err(RouteError::MethodNotAllowed(allowed))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router() -> Router<&'static str> {
+        let mut router = Router::new();
+        router
+            .get("/", "index")
+            .get("/users/:id", "user")
+            .put("/users/:id", "update user")
+            .get("/users/new", "new user form")
+            .post("/users", "create user")
+            .get("/users/:id/files/*path", "user file")
+            .get("/static/*", "static")
+            .get("/*rest", "fallback");
+        router
+    }
+
+    fn find<'a>(router: &'a Router<&'static str>, method: Method, path: &str) -> Result<(&'a str, Params), RouteError> {
+        router.find(method, path).map(|found| (*found.handler, found.params))
+    }
+
+    #[test]
+    fn test_route_literals_and_params() {
+        let router = router();
+
+        assert_eq!(find(&router, Method::Get, "/").unwrap().0, "index");
+        assert_eq!(find(&router, Method::Get, "/users/new").unwrap().0, "new user form");
+        assert_eq!(find(&router, Method::Post, "/users").unwrap().0, "create user");
+
+        let (handler, params) = find(&router, Method::Get, "/users/42").unwrap();
+        assert_eq!(handler, "user");
+        assert_eq!(params.get("id"), Some("42"));
+        assert_eq!(params.get("name"), None);
+
+        let (handler, params) = find(&router, Method::Put, "/users/j%C3%BCrgen").unwrap();
+        assert_eq!(handler, "update user");
+        assert_eq!(params.get("id"), Some("j\u{fc}rgen"));
+    }
+
+    #[test]
+    fn test_route_wildcards() {
+        let router = router();
+
+        let (handler, params) = find(&router, Method::Get, "/users/7/files/docs/a%20b.txt").unwrap();
+        assert_eq!(handler, "user file");
+        assert_eq!(params.iter().collect::<Vec<_>>(), [("id", "7"), ("path", "docs/a b.txt")]);
+
+        let (handler, params) = find(&router, Method::Get, "/static/css/site.css").unwrap();
+        assert_eq!(handler, "static");
+        assert!(params.is_empty());
+
+        assert_eq!(find(&router, Method::Get, "/static").unwrap().0, "fallback");
+        assert_eq!(find(&router, Method::Get, "/static/").unwrap().0, "static");
+        assert_eq!(find(&router, Method::Get, "/users/7/files").unwrap().0, "fallback");
+        assert_eq!(find(&router, Method::Get, "/elsewhere/deep").unwrap().1.get("rest"), Some("elsewhere/deep"));
+    }
+
+    #[test]
+    fn test_route_not_found() {
+        let mut router = Router::new();
+        router.get("/users/:id", ());
+
+        assert_eq!(router.find(Method::Get, "/users").unwrap_err(), RouteError::NotFound);
+        assert_eq!(router.find(Method::Get, "/users/").unwrap_err(), RouteError::NotFound);
+        assert_eq!(router.find(Method::Get, "/users/1/2").unwrap_err(), RouteError::NotFound);
+        assert_eq!(router.find(Method::Get, "*").unwrap_err(), RouteError::NotFound);
+    }
+
+    #[test]
+    fn test_route_method_not_allowed() {
+        let router = router();
+
+        assert_eq!(find(&router, Method::Delete, "/users/1").unwrap_err(),
+                   RouteError::MethodNotAllowed(vec![Method::Get, Method::Head, Method::Put]));
+        assert_eq!(find(&router, Method::Head, "/users/1").unwrap().0, "user");
+    }
+
+    #[test]
+    fn test_route_replaced() {
+        let mut router = Router::new();
+        router.get("/", 1).get("/", 2).post("/", 3);
+
+        assert_eq!(router.find(Method::Get, "/").unwrap().handler, &2);
+        assert_eq!(router.find(Method::Post, "/").unwrap().handler, &3);
+    }
+
+    #[test]
+    #[should_panic(expected = "wildcard before the last segment")]
+    fn test_pattern_wildcard_not_last() {
+        Pattern::parse("/files/*/edit");
+    }
+
+    #[test]
+    #[should_panic(expected = "parameter used twice")]
+    fn test_pattern_duplicate_names() {
+        Pattern::parse("/:id/:id");
+    }
+}
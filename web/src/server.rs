@@ -0,0 +1,294 @@
+//! Serving a `Handler` over TCP.
+use crate::handler::Handler;
+use crate::http::{self, Method, Request, RequestReader, Version};
+use crate::response::{Response, StatusCode};
+use crate::{Config, ThreadPool};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Accept connections on the configured address and answer their requests
+/// with `handler` on a pool of `threads` workers.
+///
+/// Connections are persistent: a worker keeps answering the requests of
+/// its connection until the client asks to close it or doesn't send its
+/// next request in full within the configured idle timeout.
+pub fn serve<H: Handler>(config: &Config, threads: usize, handler: H) -> io::Result<()> {
+    let listener = TcpListener::bind(config.address())?;
+    let pool = ThreadPool::new(threads);
+    let handler = Arc::new(handler);
+    let idle_timeout = config.idle_timeout();
+
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let handler = Arc::clone(&handler);
+
+        pool.execute(move || {
+            if let Err(error) = handle_stream(stream, &*handler, idle_timeout) {
+                println!("Connection failed: {}", error);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Serve the requests of a TCP connection, closing it when the next
+/// request doesn't arrive in full within `idle_timeout` of the previous
+/// response, or of the connection for the first one, or when writing blocks
+/// for longer than that.
+fn handle_stream<H: Handler>(stream: TcpStream, handler: &H, idle_timeout: Duration) -> io::Result<()> {
+    stream.set_write_timeout(Some(idle_timeout))?;
+    handle_connection(DeadlineStream::new(stream, idle_timeout), handler)
+}
+
+/// A TCP stream whose reads time out at a deadline, which starts over after
+/// every write, rather than after a while without data: a client sending a
+/// request a byte at a time can't hold the connection longer than one that
+/// sends nothing.
+struct DeadlineStream {
+    stream: TcpStream,
+    timeout: Duration,
+    deadline: Instant,
+}
+
+impl DeadlineStream {
+    fn new(stream: TcpStream, timeout: Duration) -> DeadlineStream {
+        DeadlineStream { stream, timeout, deadline: Instant::now() + timeout }
+    }
+}
+
+impl Read for DeadlineStream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "request deadline passed"));
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.read(buffer)
+    }
+}
+
+impl Write for DeadlineStream {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let written = self.stream.write(data)?;
+        self.deadline = Instant::now() + self.timeout;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Answer the requests read from `stream` with `handler`, in order, until
+/// the connection ends.
+///
+/// HTTP/1.1 connections stay open unless a request or its response has
+/// `Connection: close`; HTTP/1.0 ones only with `Connection: keep-alive`.
+/// Pipelined requests are answered one after another. Malformed requests
+/// are rejected with the status `http::Error::status` gives and a panicking
+/// handler results in `500 Internal Server Error`, both closing the
+/// connection. A read timing out before a request starts closes the
+/// connection quietly.
+pub fn handle_connection<S: Read + Write, H: Handler>(stream: S, handler: &H) -> io::Result<()> {
+    let mut reader = RequestReader::new(stream);
+
+    loop {
+        let (response, method, version, keep_alive) = match reader.read_request() {
+            Ok(Some(request)) => {
+                let (method, version) = (request.method, request.version);
+                let keep_alive = wants_keep_alive(&request);
+                match call(handler, request) {
+                    Some(response) => (response, method, version, keep_alive),
+                    None => (Response::error(StatusCode::INTERNAL_SERVER_ERROR), method, version, false),
+                }
+            }
+            Ok(None) => return Ok(()),
+            Err(http::Error::Io(error)) if http::is_timeout(&error) => return Ok(()),
+            Err(http::Error::Io(error)) => return Err(error),
+            Err(error) => {
+                let status = error.status().unwrap_or(StatusCode::BAD_REQUEST);
+                (Response::error(status), Method::Get, Version::Http11, false)
+            }
+        };
+
+        let keep_alive = keep_alive && !response.headers.contains_token("Connection", "close");
+        let response = match (keep_alive, version) {
+            (true, Version::Http11) => response,
+            (true, Version::Http10) => response.with_header("Connection", "keep-alive"),
+            (false, _) if response.headers.contains_token("Connection", "close") => response,
+            (false, _) => response.with_header("Connection", "close"),
+        };
+        response.write_to(reader.get_mut(), method, version)?;
+
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+/// Whether the client of `request` wants the connection to stay open.
+fn wants_keep_alive(request: &Request) -> bool {
+    match request.version {
+        Version::Http11 => !request.headers.contains_token("Connection", "close"),
+        Version::Http10 => request.headers.contains_token("Connection", "keep-alive"),
+    }
+}
+
+/// Answer `request` with `handler`, or return `None` if it panics.
+fn call<H: Handler>(handler: &H, request: Request) -> Option<Response> {
+    panic::catch_unwind(AssertUnwindSafe(|| handler.handle(request))).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A connection reading from `input` and writing into `output`.
+    struct Connection<'a> {
+        input: &'a [u8],
+        output: Vec<u8>,
+    }
+
+    impl<'a> Read for Connection<'a> {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buffer)
+        }
+    }
+
+    impl<'a> Write for Connection<'a> {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.output.write(data)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn exchange<H: Handler>(input: &[u8], handler: &H) -> String {
+        let mut connection = Connection { input, output: Vec::new() };
+        handle_connection(&mut connection, handler).unwrap();
+        String::from_utf8(connection.output).unwrap()
+    }
+
+    fn hello(request: Request) -> Response {
+        Response::text(format!("hello from {}", request.path()))
+    }
+
+    #[test]
+    fn test_handle_connection() {
+        let output = exchange(b"GET /here HTTP/1.1\r\nHost: a\r\n\r\n", &hello);
+
+        assert_eq!(output, "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\n\
+                            Content-Length: 16\r\n\r\nhello from /here");
+    }
+
+    #[test]
+    fn test_handle_connection_pipelined() {
+        let output = exchange(b"GET /1 HTTP/1.1\r\nHost: a\r\n\r\n\
+                                POST /2 HTTP/1.1\r\nHost: a\r\nContent-Length: 4\r\n\r\nbody\r\n\
+                                GET /3 HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n\
+                                GET /4 HTTP/1.1\r\nHost: a\r\n\r\n", &hello);
+
+        let bodies: Vec<&str> = output.split("\r\n\r\n").skip(1).map(|rest| &rest[..rest.len().min(13)]).collect();
+        assert_eq!(bodies, ["hello from /1", "hello from /2", "hello from /3"]);
+        assert_eq!(output.matches("Connection: close").count(), 1);
+    }
+
+    #[test]
+    fn test_handle_connection_keep_alive() {
+        let twice = |request: &[u8]| exchange(&[request, request].concat(), &hello).matches("HTTP/1").count();
+
+        assert_eq!(twice(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n"), 2);
+        assert_eq!(twice(b"GET / HTTP/1.1\r\nHost: a\r\nConnection: Upgrade, Close\r\n\r\n"), 1);
+        assert_eq!(twice(b"GET / HTTP/1.0\r\n\r\n"), 1);
+        assert_eq!(twice(b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n"), 2);
+        assert!(exchange(b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n", &hello).contains("Connection: keep-alive\r\n"));
+
+        let closing = |_: Request| Response::text("bye").with_header("Connection", "close");
+        let output = exchange(b"GET / HTTP/1.1\r\nHost: a\r\n\r\nGET / HTTP/1.1\r\nHost: a\r\n\r\n", &closing);
+        assert_eq!(output.matches("HTTP/1.1 200 OK").count(), 1);
+        assert_eq!(output.matches("Connection: close").count(), 1);
+    }
+
+    #[test]
+    fn test_handle_stream_idle_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let server = std::thread::spawn(move || handle_stream(stream, &hello, Duration::from_millis(100)));
+
+        client.write_all(b"GET /a HTTP/1.1\r\nHost: a\r\n\r\nGET /b HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+        // The server closes the idle connection after answering both
+        // requests, so reading to the end doesn't block.
+        let mut output = String::new();
+        client.read_to_string(&mut output).unwrap();
+
+        assert!(server.join().unwrap().is_ok());
+        assert!(output.contains("hello from /a") && output.ends_with("hello from /b"), "{}", output);
+    }
+
+    #[test]
+    fn test_handle_stream_request_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let server = std::thread::spawn(move || handle_stream(stream, &hello, Duration::from_millis(100)));
+
+        client.write_all(b"GET / HTTP/1.1\r\nHost:").unwrap();
+        let mut output = String::new();
+        client.read_to_string(&mut output).unwrap();
+
+        assert!(server.join().unwrap().is_ok());
+        assert!(output.starts_with("HTTP/1.1 408 Request Timeout\r\n"), "{}", output);
+    }
+
+    #[test]
+    fn test_handle_stream_slow_request() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let server = std::thread::spawn(move || handle_stream(stream, &hello, Duration::from_millis(300)));
+
+        // Every byte arrives well within the timeout, but the whole request
+        // doesn't.
+        let mut writer = client.try_clone().unwrap();
+        let start = Instant::now();
+        std::thread::spawn(move || {
+            for byte in b"GET / HTTP/1.1\r\nHost: a\r\nX-Padding: ".iter().chain([b'a'; 100].iter()) {
+                if writer.write_all(&[*byte]).is_err() {
+                    return;
+                }
+                std::thread::sleep(Duration::from_millis(20));
+            }
+        });
+        let mut output = String::new();
+        client.read_to_string(&mut output).unwrap();
+
+        assert!(server.join().unwrap().is_ok());
+        assert!(output.starts_with("HTTP/1.1 408 Request Timeout\r\n"), "{}", output);
+        assert!(start.elapsed() < Duration::from_secs(1), "{:?}", start.elapsed());
+    }
+
+    #[test]
+    fn test_handle_connection_head() {
+        let output = exchange(b"HEAD / HTTP/1.0\r\n\r\n", &hello);
+
+        assert!(output.starts_with("HTTP/1.0 200 OK\r\n"), "{}", output);
+        assert!(output.ends_with("Content-Length: 12\r\n\r\n"), "{}", output);
+    }
+
+    #[test]
+    fn test_handle_connection_errors() {
+        assert!(exchange(b"GET / HTTP/1.1\r\n\r\n", &hello).starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(exchange(b"", &hello).is_empty());
+
+        let panicking = |_: Request| -> Response { panic!("handler failed") };
+        let output = exchange(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n", &panicking);
+        assert!(output.starts_with("HTTP/1.1 500 Internal Server Error\r\n"), "{}", output);
+    }
+}